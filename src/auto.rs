@@ -0,0 +1,355 @@
+//! Encoding an input into every format and keeping the smallest output.
+use crate::{
+    cli::{AutoCommand, OutputFormat, ProgressMode},
+    exit_on_failure, stage_reporter, CliError,
+};
+use emote_tool::{
+    avif::task::AvifEncoderTask,
+    cancel::CancellationToken,
+    ffmpeg::{input::STDIO_PATH, FrameSink},
+    metrics::serialize_psnr,
+    options::{AvifOptions, OverwritePolicy, WebpOptions},
+    pipeline::{run_ffmpeg, spawn_file_encoder, InputStream},
+    progress::Progress,
+    report::{ErrorReport, Stage, TaskReport},
+    webp::task::WebpEncoderTask,
+    EncoderTask, TaskError,
+};
+use indicatif::MultiProgress;
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::PathBuf,
+};
+
+/// Collects the result of a started encoder once ffmpeg is done
+type PendingCandidate = Box<dyn FnOnce(&Result<u64, ErrorReport>) -> Candidate>;
+
+#[derive(serde::Serialize)]
+pub struct Candidate {
+    pub format: &'static str,
+    pub output: Option<PathBuf>,
+    pub bytes: Option<u64>,
+    #[serde(serialize_with = "serialize_optional_psnr")]
+    pub psnr: Option<f64>,
+    pub ssim: Option<f64>,
+    pub errors: Vec<ErrorReport>,
+    /// 1 = best
+    pub rank: usize,
+    /// `false` if the output was deleted (or never written)
+    pub kept: bool,
+    /// Why this candidate won or lost
+    pub reason: String,
+    #[serde(skip)]
+    disqualified: Option<String>,
+}
+
+#[derive(Default, serde::Serialize)]
+pub struct AutoReport {
+    pub success: bool,
+    /// Format of the best candidate
+    pub best: Option<&'static str>,
+    /// Ranked from best to worst
+    pub candidates: Vec<Candidate>,
+    /// Errors that occurred before any encoder was started
+    pub errors: Vec<ErrorReport>,
+    /// An output that already existed, nothing was converted (see `--no-clobber`)
+    pub skipped: Option<PathBuf>,
+}
+
+pub fn report_auto(command: AutoCommand, cancel: &CancellationToken) -> Result<(), CliError> {
+    // every candidate is written to a file to compare them
+    if command.io.output == STDIO_PATH {
+        return Err(CliError::StdoutOutput("auto"));
+    }
+    let output_format = command.io.output_format;
+    let result = run_auto(command, cancel);
+    let report = match output_format {
+        OutputFormat::Text => {
+            let report = result?;
+            println!("{}", report);
+            report
+        }
+        OutputFormat::Json => {
+            let report = result.unwrap_or_else(|e| AutoReport {
+                errors: vec![ErrorReport::new(Stage::Task, &e)],
+                ..AutoReport::default()
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            report
+        }
+    };
+    exit_on_failure(report.success, cancel);
+    Ok(())
+}
+
+pub fn run_auto(
+    mut command: AutoCommand,
+    cancel: &CancellationToken,
+) -> Result<AutoReport, TaskError> {
+    if let Some(quality) = command.target_quality {
+        apply_target_quality(quality, &mut command.avif_opts, &mut command.webp_opts);
+    }
+    let collect_reference = command.metrics_enabled();
+    // checked before any encoder is started, instead of failing after the first one
+    let outputs = [
+        AvifEncoderTask::make_output_path(&command.io.output),
+        WebpEncoderTask::make_output_path(&command.io.output),
+    ];
+    if let Some(path) = outputs.into_iter().find(|path| path.exists()) {
+        match command.io.overwrite_policy() {
+            OverwritePolicy::Overwrite => (),
+            // the outputs of the candidates that lose are deleted, an existing file can't be one
+            OverwritePolicy::Skip => {
+                return Ok(AutoReport {
+                    success: true,
+                    skipped: Some(path),
+                    ..AutoReport::default()
+                })
+            }
+            OverwritePolicy::Fail => return Err(TaskError::OutputExists(path)),
+        }
+    }
+
+    let input = command.io.open_input()?;
+
+    let progress_manager = MultiProgress::new();
+    let ffmpeg_progress = Progress::new(
+        Stage::Ffmpeg,
+        input.frames,
+        Some(stage_reporter(
+            command.io.progress,
+            &progress_manager,
+            "Ffmpeg",
+        )),
+    );
+    let mut candidates = Candidates {
+        input: &input,
+        output: &command.io.output,
+        collect_reference,
+        progress_mode: command.io.progress,
+        progress_manager: &progress_manager,
+        overwrite: command.io.overwrite_policy(),
+        cancel,
+        sinks: Vec::new(),
+        pending: Vec::new(),
+    };
+    // every format gets the same frames, new formats only have to be added here
+    let started = candidates
+        .start::<AvifEncoderTask>("avif", command.avif_opts.clone())
+        .and_then(|()| candidates.start::<WebpEncoderTask>("webp", command.webp_opts.clone()));
+    let Candidates { sinks, pending, .. } = candidates;
+    if let Err(e) = started {
+        // without their sinks, the started encoders run out of frames, joining them deletes
+        // their temporary files
+        drop(sinks);
+        let error = Err(ErrorReport::new(Stage::Task, &e));
+        for candidate in pending {
+            candidate(&error);
+        }
+        return Err(e);
+    }
+
+    let ff_result = run_ffmpeg(input, sinks, ffmpeg_progress, cancel);
+    let candidates = pending
+        .into_iter()
+        .map(|candidate| candidate(&ff_result))
+        .collect();
+    Ok(rank(candidates, &command))
+}
+
+/// Override the AVIF quantizers and the WebP quality with a shared quality (0-100).
+pub fn apply_target_quality(quality: u8, avif_opts: &mut AvifOptions, webp_opts: &mut WebpOptions) {
+    let quality = quality.min(100);
+    let quantizer = ((100 - quality) as u32 * 63 / 100) as u8;
+    avif_opts.quantizer = quantizer;
+    avif_opts.quantizer_alpha = quantizer;
+    webp_opts.quality = Some(quality as f32);
+}
+
+/// Encoders that were started and are waiting for frames
+struct Candidates<'a> {
+    input: &'a InputStream,
+    output: &'a str,
+    collect_reference: bool,
+    progress_mode: ProgressMode,
+    progress_manager: &'a MultiProgress,
+    overwrite: OverwritePolicy,
+    cancel: &'a CancellationToken,
+    sinks: Vec<FrameSink>,
+    pending: Vec<PendingCandidate>,
+}
+
+impl Candidates<'_> {
+    fn start<T>(&mut self, format: &'static str, options: T::Options) -> Result<(), TaskError>
+    where
+        T: EncoderTask + 'static,
+    {
+        let frames = self.input.frames;
+        let (sink, encoder) = spawn_file_encoder::<T>(
+            self.output,
+            options,
+            self.input,
+            self.collect_reference,
+            Progress::new(
+                Stage::Encoder,
+                frames,
+                Some(stage_reporter(
+                    self.progress_mode,
+                    self.progress_manager,
+                    format,
+                )),
+            ),
+            self.overwrite,
+            self.cancel,
+        )?;
+        let dimensions = self.input.dimensions;
+        self.sinks.push(sink);
+        self.pending
+            .push(Box::new(move |ff_result: &Result<u64, ErrorReport>| {
+                Candidate::new(format, encoder.join(ff_result, dimensions))
+            }));
+        Ok(())
+    }
+}
+
+impl Candidate {
+    fn new<S>(format: &'static str, report: TaskReport<S>) -> Self {
+        Self {
+            format,
+            disqualified: report
+                .errors
+                .first()
+                .map(|e| format!("failed: {}", e.message)),
+            output: report.output,
+            bytes: report.bytes,
+            psnr: report.metrics.as_ref().map(|m| m.psnr()),
+            ssim: report.metrics.as_ref().map(|m| m.mean_ssim()),
+            errors: report.errors,
+            rank: 0,
+            kept: false,
+            reason: String::new(),
+        }
+    }
+}
+
+/// Like [`serialize_psnr`], `null` without metrics
+fn serialize_optional_psnr<S: serde::Serializer>(
+    psnr: &Option<f64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match psnr {
+        Some(psnr) => serialize_psnr(psnr, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Sort the candidates by size (disqualified ones last), then keep the best one.
+fn rank(mut candidates: Vec<Candidate>, command: &AutoCommand) -> AutoReport {
+    for candidate in candidates
+        .iter_mut()
+        .filter(|candidate| candidate.disqualified.is_none())
+    {
+        candidate.disqualified = match (
+            candidate.ssim,
+            candidate.psnr,
+            command.min_ssim,
+            command.min_psnr,
+        ) {
+            (Some(ssim), _, Some(min), _) if ssim < min => Some(format!(
+                "SSIM {:.4} is below the minimum of {:.4}",
+                ssim, min
+            )),
+            (_, Some(psnr), _, Some(min)) if psnr < min => Some(format!(
+                "PSNR {:.2} dB is below the minimum of {:.2} dB",
+                psnr, min
+            )),
+            _ => None,
+        };
+    }
+    candidates.sort_by_key(|candidate| {
+        (
+            candidate.disqualified.is_some(),
+            candidate.bytes.unwrap_or(u64::MAX),
+        )
+    });
+
+    let best = candidates
+        .first()
+        .filter(|candidate| candidate.disqualified.is_none())
+        .map(|candidate| (candidate.format, candidate.bytes.unwrap_or_default()));
+    for (i, candidate) in candidates.iter_mut().enumerate() {
+        let bytes = candidate.bytes.unwrap_or_default();
+        candidate.rank = i + 1;
+        candidate.reason = match (&candidate.disqualified, best) {
+            (Some(reason), _) => reason.clone(),
+            (None, Some(_)) if i == 0 => format!("smallest output ({} bytes)", bytes),
+            (None, Some((best_format, best_bytes))) => format!(
+                "{} bytes larger than {} (+{:.1}%)",
+                bytes - best_bytes,
+                best_format,
+                (bytes - best_bytes) as f64 * 100.0 / best_bytes.max(1) as f64
+            ),
+            (None, None) => String::new(),
+        };
+        candidate.kept =
+            candidate.output.is_some() && ((i == 0 && best.is_some()) || command.keep_all);
+        if let (false, Some(output)) = (candidate.kept, &candidate.output) {
+            if let Err(e) = fs::remove_file(output) {
+                candidate.kept = true;
+                candidate.reason += &format!(" (couldn't delete the output: {})", e);
+            }
+        }
+    }
+
+    AutoReport {
+        success: best.is_some(),
+        best: best.map(|(format, _)| format),
+        candidates,
+        errors: Vec::new(),
+        skipped: None,
+    }
+}
+
+impl Display for AutoReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(output) = &self.skipped {
+            return write!(
+                f,
+                "Skipped, {} already exists",
+                output.as_os_str().to_str().unwrap_or("<invalid UTF8>")
+            );
+        }
+        for candidate in &self.candidates {
+            write!(
+                f,
+                "#{} {:<5} {:>14}",
+                candidate.rank,
+                candidate.format,
+                candidate
+                    .bytes
+                    .map_or_else(|| "-".to_string(), |bytes| format!("{} bytes", bytes))
+            )?;
+            if let (Some(psnr), Some(ssim)) = (candidate.psnr, candidate.ssim) {
+                write!(f, "  PSNR {:>6.2} dB  SSIM {:.4}", psnr, ssim)?;
+            }
+            writeln!(
+                f,
+                "  {:<7} {}",
+                if candidate.kept { "kept" } else { "dropped" },
+                candidate.reason
+            )?;
+        }
+        match self.candidates.first() {
+            Some(Candidate {
+                output: Some(output),
+                ..
+            }) if self.best.is_some() => write!(
+                f,
+                "Written to {}",
+                output.as_os_str().to_str().unwrap_or("<invalid UTF8>")
+            ),
+            _ => write!(f, "No candidate qualified"),
+        }
+    }
+}
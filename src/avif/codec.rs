@@ -1,7 +1,8 @@
 use libavif_sys as sys;
 use libavif_sys::avifCodecChoice;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
 pub enum Codec {
     Auto = sys::AVIF_CODEC_CHOICE_AUTO as isize,
     Aom = sys::AVIF_CODEC_CHOICE_AOM as isize,
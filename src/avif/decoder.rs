@@ -0,0 +1,70 @@
+use crate::avif::image::copy_raw_to_ffmpeg;
+use ffmpeg_next::frame;
+use libavif_sys as sys;
+use std::{marker::PhantomData, ptr::NonNull};
+
+pub struct Decoder<'a> {
+    decoder: NonNull<sys::avifDecoder>,
+    /// `decoder` reads directly from the memory it was created from
+    _data_marker: PhantomData<&'a [u8]>,
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder reading from `data` and parse the container.
+    pub fn from_memory(data: &'a [u8]) -> Result<Self, super::Error> {
+        let decoder = Self {
+            decoder: NonNull::new(unsafe { sys::avifDecoderCreate() })
+                .ok_or(super::Error::Unknown)?,
+            _data_marker: PhantomData::default(),
+        };
+        unsafe {
+            super::Error::from_code(sys::avifDecoderSetIOMemory(
+                decoder.decoder.as_ptr(),
+                data.as_ptr(),
+                data.len(),
+            ))?;
+            super::Error::from_code(sys::avifDecoderParse(decoder.decoder.as_ptr()))?;
+        }
+        Ok(decoder)
+    }
+
+    pub fn image_count(&self) -> usize {
+        unsafe { self.decoder.as_ref().imageCount as usize }
+    }
+
+    /// Decode the next image.
+    ///
+    /// Returns `Ok(false)` if there are no images remaining.
+    pub fn next_image(&mut self) -> Result<bool, super::Error> {
+        match super::Error::from_code(unsafe { sys::avifDecoderNextImage(self.decoder.as_ptr()) }) {
+            Ok(()) => Ok(true),
+            Err(super::Error::NoImagesRemaining) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Presentation timestamp of the current image in milliseconds
+    pub fn image_pts_ms(&self) -> i64 {
+        unsafe { (self.decoder.as_ref().imageTiming.pts * 1000.0) as i64 }
+    }
+
+    /// Duration of the current image in milliseconds
+    pub fn image_duration_ms(&self) -> i64 {
+        unsafe { (self.decoder.as_ref().imageTiming.duration * 1000.0) as i64 }
+    }
+
+    /// Copy the current image into a newly allocated libav frame.
+    ///
+    /// Only 8-bit images are supported.
+    pub fn image_to_ffmpeg(&self) -> Option<frame::Video> {
+        unsafe { copy_raw_to_ffmpeg(NonNull::new(self.decoder.as_ref().image)?.as_ref()) }
+    }
+}
+
+impl Drop for Decoder<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            sys::avifDecoderDestroy(self.decoder.as_ptr());
+        }
+    }
+}
@@ -10,6 +10,19 @@ pub struct Encoder {
     encoder: NonNull<sys::avifEncoder>,
 }
 
+/// Settings and output statistics of an `Encoder`, which can outlive it
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EncoderSettings {
+    pub codec: Codec,
+    pub max_threads: usize,
+    pub quantizer: u8,
+    pub quantizer_alpha: u8,
+    pub speed: u8,
+    pub timescale: u64,
+    pub color_obu_size: usize,
+    pub alpha_obu_size: usize,
+}
+
 impl Encoder {
     pub fn new() -> Option<Self> {
         Some(Self {
@@ -106,6 +119,20 @@ impl Encoder {
         unsafe { self.encoder.as_ref().ioStats }
     }
 
+    pub fn settings(&self) -> EncoderSettings {
+        let stats = self.stats();
+        EncoderSettings {
+            codec: self.codec(),
+            max_threads: self.max_threads(),
+            quantizer: self.quantizer(),
+            quantizer_alpha: self.quantizer_alpha(),
+            speed: self.speed(),
+            timescale: self.timescale(),
+            color_obu_size: stats.colorOBUSize as usize,
+            alpha_obu_size: stats.alphaOBUSize as usize,
+        }
+    }
+
     pub fn add_image_none(
         &mut self,
         image: &AvifImage,
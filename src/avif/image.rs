@@ -1,4 +1,4 @@
-use ffmpeg_next::{format::Pixel, frame};
+use ffmpeg_next::{color, format::Pixel, frame};
 use libavif_sys as sys;
 use std::{marker::PhantomData, ptr::NonNull};
 
@@ -31,6 +31,11 @@ impl<'a> BorrowedAvifImage<'a> {
 
     pub fn from_ffmpeg(frame: &'a mut ffmpeg_next::frame::Video) -> Option<Self> {
         let format = frame.format();
+        // swscale writes limited range unless it's asked for full range
+        let range = match frame.color_range() {
+            color::Range::JPEG => sys::AVIF_RANGE_FULL,
+            _ => sys::AVIF_RANGE_LIMITED,
+        };
         match format {
             Pixel::YUV420P | Pixel::YUV422P | Pixel::YUV444P => unsafe {
                 assert_eq!(frame.planes(), 3);
@@ -42,6 +47,7 @@ impl<'a> BorrowedAvifImage<'a> {
                 }
 
                 image.as_mut().imageOwnsYUVPlanes = sys::AVIF_FALSE as sys::avifBool;
+                image.as_mut().yuvRange = range;
                 Some(Self::from_raw(image))
             },
             Pixel::YUVA444P => unsafe {
@@ -61,6 +67,7 @@ impl<'a> BorrowedAvifImage<'a> {
                 image.as_mut().alphaRowBytes = frame.stride(3) as u32;
 
                 image.as_mut().imageOwnsYUVPlanes = sys::AVIF_FALSE as sys::avifBool;
+                image.as_mut().yuvRange = range;
                 image.as_mut().imageOwnsAlphaPlane = sys::AVIF_FALSE as sys::avifBool;
                 Some(Self::from_raw(image))
             },
@@ -69,6 +76,15 @@ impl<'a> BorrowedAvifImage<'a> {
     }
 }
 
+impl BorrowedAvifImage<'_> {
+    /// Mark the color planes as premultiplied by alpha (they have to be premultiplied already)
+    pub fn set_alpha_premultiplied(&mut self, premultiplied: bool) {
+        unsafe {
+            self.inner.image.as_mut().alphaPremultiplied = premultiplied as sys::avifBool;
+        }
+    }
+}
+
 impl Drop for AvifImage {
     fn drop(&mut self) {
         unsafe {
@@ -101,3 +117,50 @@ fn create_raw_from_yuv_format(
         )
     })
 }
+
+/// Safety: the planes of `image` must be valid for its width, height and row bytes.
+pub(super) unsafe fn copy_raw_to_ffmpeg(image: &sys::avifImage) -> Option<frame::Video> {
+    if image.depth != 8 {
+        return None;
+    }
+    let has_alpha = !image.alphaPlane.is_null();
+    let format = match (image.yuvFormat, has_alpha) {
+        (sys::AVIF_PIXEL_FORMAT_YUV420, false) => Pixel::YUV420P,
+        (sys::AVIF_PIXEL_FORMAT_YUV422, false) => Pixel::YUV422P,
+        (sys::AVIF_PIXEL_FORMAT_YUV444, false) => Pixel::YUV444P,
+        (sys::AVIF_PIXEL_FORMAT_YUV420, true) => Pixel::YUVA420P,
+        (sys::AVIF_PIXEL_FORMAT_YUV422, true) => Pixel::YUVA422P,
+        (sys::AVIF_PIXEL_FORMAT_YUV444, true) => Pixel::YUVA444P,
+        _ => return None,
+    };
+
+    let mut frame = frame::Video::new(format, image.width, image.height);
+    for i in 0..3 {
+        copy_plane(
+            image.yuvPlanes[i],
+            image.yuvRowBytes[i] as usize,
+            &mut frame,
+            i,
+        );
+    }
+    if has_alpha {
+        copy_plane(
+            image.alphaPlane,
+            image.alphaRowBytes as usize,
+            &mut frame,
+            3,
+        );
+    }
+    Some(frame)
+}
+
+unsafe fn copy_plane(src: *const u8, src_stride: usize, frame: &mut frame::Video, plane: usize) {
+    let width = frame.plane_width(plane) as usize;
+    let height = frame.plane_height(plane) as usize;
+    let dst_stride = frame.stride(plane);
+    let dst = frame.data_mut(plane);
+    for y in 0..height {
+        let row = std::slice::from_raw_parts(src.add(y * src_stride), width);
+        dst[y * dst_stride..y * dst_stride + width].copy_from_slice(row);
+    }
+}
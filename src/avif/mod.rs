@@ -1,15 +1,18 @@
 mod codec;
 mod data;
+mod decoder;
 mod encoder;
 mod error;
 mod flags;
 mod format;
 mod image;
+mod stitch;
 pub mod task;
 
 pub use codec::Codec;
 pub use data::AvifRwData;
-pub use encoder::Encoder;
+pub use decoder::Decoder;
+pub use encoder::{Encoder, EncoderSettings};
 pub use error::Error;
 pub use flags::AddImage;
 pub use format::YuvFormat;
@@ -0,0 +1,610 @@
+//! Joining AVIF sequences that were encoded separately into a single sequence.
+//!
+//! This relies on the layout libavif writes: every track has a single chunk in `mdat`,
+//! and the items of the first frame point at the start of these chunks.
+use crate::inspect::{
+    avif::{children, full_box_header},
+    reader::Reader,
+    InspectError,
+};
+
+/// The samples of one track, in the order of the tracks in `moov`
+struct Track<'a> {
+    chunk_offset: u64,
+    sizes: Vec<u32>,
+    /// 1-based sample numbers like in `stss`
+    sync_samples: Vec<u32>,
+    /// `(count, duration)` like in `stts`
+    durations: Vec<(u32, u32)>,
+    media_timescale: u32,
+    chunk: &'a [u8],
+}
+
+struct Segment<'a> {
+    movie_timescale: u32,
+    tracks: Vec<Track<'a>>,
+}
+
+/// All segments' samples of one track and where they're placed in the output
+struct MergedTrack<'a> {
+    sizes: Vec<u32>,
+    sync_samples: Vec<u32>,
+    durations: Vec<(u32, u32)>,
+    chunks: Vec<&'a [u8]>,
+    /// Offset of the chunk in the first segment, which the items refer to
+    first_offset: u64,
+    /// Offset of the chunk relative to the start of the `mdat` payload
+    mdat_offset: u64,
+}
+
+/// Append the samples of all `segments` (AVIF sequences written by libavif) to the first one.
+///
+/// The boxes of the first segment are kept, only the sample tables, durations and offsets
+/// are rewritten. All segments must have the same tracks (e.g. all or none with alpha).
+pub fn stitch(segments: &[&[u8]]) -> Result<Vec<u8>, InspectError> {
+    let first = *segments
+        .first()
+        .ok_or(InspectError::Invalid("empty segment list"))?;
+    let parsed = segments
+        .iter()
+        .map(|data| parse_segment(data))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut tracks = merge_tracks(&parsed)?;
+
+    // alpha is written before color, keep the order of the first segment
+    let mut order = (0..tracks.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| tracks[i].first_offset);
+    let mut mdat_size = 0;
+    for i in order {
+        tracks[i].mdat_offset = mdat_size;
+        mdat_size += tracks[i].sizes.iter().map(|&size| size as u64).sum::<u64>();
+    }
+
+    let media_timescale = parsed[0].tracks[0].media_timescale;
+    let media_duration = tracks[0]
+        .durations
+        .iter()
+        .map(|&(count, duration)| count as u64 * duration as u64)
+        .sum::<u64>();
+    let durations = Durations {
+        movie: media_duration * parsed[0].movie_timescale as u64 / media_timescale.max(1) as u64,
+        media: media_duration,
+    };
+
+    // offsets don't change the size of the boxes, so the headers are measured first
+    let header_size = write_headers(first, &tracks, durations, 0)?.len() as u64;
+    let mdat_start = header_size + 8;
+    let mut out = write_headers(first, &tracks, durations, mdat_start)?;
+    let mdat_box_size =
+        u32::try_from(mdat_size + 8).map_err(|_| InspectError::Invalid("output size"))?;
+    out.extend(mdat_box_size.to_be_bytes());
+    out.extend(b"mdat");
+    let mut chunks = tracks.iter().collect::<Vec<_>>();
+    chunks.sort_by_key(|track| track.mdat_offset);
+    for track in chunks {
+        for chunk in &track.chunks {
+            out.extend_from_slice(chunk);
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Copy, Clone)]
+struct Durations {
+    /// in the timescale of `mvhd`
+    movie: u64,
+    /// in the timescale of `mdhd`
+    media: u64,
+}
+
+fn parse_segment(data: &[u8]) -> Result<Segment, InspectError> {
+    let moov = children(data)?
+        .into_iter()
+        .find(|b| b.kind == "moov")
+        .ok_or(InspectError::Invalid("segment without moov box"))?;
+    let mut segment = Segment {
+        movie_timescale: 0,
+        tracks: Vec::new(),
+    };
+    for b in children(moov.payload)? {
+        match b.kind.as_str() {
+            "mvhd" => {
+                let mut reader = Reader::new(b.payload);
+                let (version, _) = full_box_header(&mut reader)?;
+                reader.skip(if version == 1 { 16 } else { 8 })?;
+                segment.movie_timescale = reader.u32_be()?;
+            }
+            "trak" => segment.tracks.push(parse_trak(data, b.payload)?),
+            _ => (),
+        }
+    }
+    if segment.tracks.is_empty() {
+        return Err(InspectError::Invalid("segment without tracks"));
+    }
+    Ok(segment)
+}
+
+fn parse_trak<'a>(data: &'a [u8], payload: &[u8]) -> Result<Track<'a>, InspectError> {
+    let mut track = Track {
+        chunk_offset: 0,
+        sizes: Vec::new(),
+        sync_samples: Vec::new(),
+        durations: Vec::new(),
+        media_timescale: 0,
+        chunk: &[],
+    };
+    let mdia = children(payload)?
+        .into_iter()
+        .find(|b| b.kind == "mdia")
+        .ok_or(InspectError::Invalid("trak without mdia box"))?;
+    let mut has_sync_table = false;
+    for b in children(mdia.payload)? {
+        match b.kind.as_str() {
+            "mdhd" => {
+                let mut reader = Reader::new(b.payload);
+                let (version, _) = full_box_header(&mut reader)?;
+                reader.skip(if version == 1 { 16 } else { 8 })?;
+                track.media_timescale = reader.u32_be()?;
+            }
+            "minf" => {
+                for stbl in children(b.payload)?.iter().filter(|b| b.kind == "stbl") {
+                    for b in children(stbl.payload)? {
+                        let mut reader = Reader::new(b.payload);
+                        full_box_header(&mut reader)?;
+                        match b.kind.as_str() {
+                            "stco" | "co64" => {
+                                if reader.u32_be()? != 1 {
+                                    return Err(InspectError::Invalid("chunk layout"));
+                                }
+                                track.chunk_offset = match b.kind.as_str() {
+                                    "stco" => reader.u32_be()? as u64,
+                                    _ => reader.u64_be()?,
+                                };
+                            }
+                            "stsc" => {
+                                if reader.u32_be()? != 1 {
+                                    return Err(InspectError::Invalid("chunk layout"));
+                                }
+                            }
+                            "stsz" => {
+                                let sample_size = reader.u32_be()?;
+                                let count = reader.u32_be()?;
+                                track.sizes = match sample_size {
+                                    0 => (0..count)
+                                        .map(|_| reader.u32_be())
+                                        .collect::<Result<_, _>>()?,
+                                    size => vec![size; count as usize],
+                                };
+                            }
+                            "stss" => {
+                                has_sync_table = true;
+                                let count = reader.u32_be()?;
+                                track.sync_samples = (0..count)
+                                    .map(|_| reader.u32_be())
+                                    .collect::<Result<_, _>>()?;
+                            }
+                            "stts" => {
+                                let count = reader.u32_be()?;
+                                track.durations = (0..count)
+                                    .map(|_| Ok((reader.u32_be()?, reader.u32_be()?)))
+                                    .collect::<Result<_, InspectError>>()?;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    // without a sync sample table, every sample is a sync sample
+    if !has_sync_table {
+        track.sync_samples = (1..=track.sizes.len() as u32).collect();
+    }
+    let chunk_size = track.sizes.iter().map(|&size| size as usize).sum::<usize>();
+    track.chunk = usize::try_from(track.chunk_offset)
+        .ok()
+        .and_then(|start| data.get(start..start.checked_add(chunk_size)?))
+        .ok_or(InspectError::Truncated)?;
+    Ok(track)
+}
+
+fn merge_tracks<'a>(segments: &[Segment<'a>]) -> Result<Vec<MergedTrack<'a>>, InspectError> {
+    let track_count = segments[0].tracks.len();
+    if segments.iter().any(|s| s.tracks.len() != track_count) {
+        return Err(InspectError::Invalid("segments with different tracks"));
+    }
+    let mut merged = segments[0]
+        .tracks
+        .iter()
+        .map(|track| MergedTrack {
+            sizes: Vec::new(),
+            sync_samples: Vec::new(),
+            durations: Vec::new(),
+            chunks: Vec::new(),
+            first_offset: track.chunk_offset,
+            mdat_offset: 0,
+        })
+        .collect::<Vec<_>>();
+    for segment in segments {
+        for (merged, track) in merged.iter_mut().zip(&segment.tracks) {
+            let previous_samples = merged.sizes.len() as u32;
+            merged.sizes.extend_from_slice(&track.sizes);
+            merged
+                .sync_samples
+                .extend(track.sync_samples.iter().map(|n| n + previous_samples));
+            for &(count, duration) in &track.durations {
+                match merged.durations.last_mut() {
+                    Some((last_count, last_duration)) if *last_duration == duration => {
+                        *last_count += count
+                    }
+                    _ => merged.durations.push((count, duration)),
+                }
+            }
+            merged.chunks.push(track.chunk);
+        }
+    }
+    Ok(merged)
+}
+
+/// Every box of `first` except `mdat`, with the tables of the merged tracks
+fn write_headers(
+    first: &[u8],
+    tracks: &[MergedTrack],
+    durations: Durations,
+    mdat_start: u64,
+) -> Result<Vec<u8>, InspectError> {
+    let mut out = Vec::with_capacity(first.len());
+    for b in children(first)? {
+        match b.kind.as_str() {
+            "meta" => write_box(
+                &mut out,
+                "meta",
+                &rewrite_meta(b.payload, tracks, mdat_start)?,
+            ),
+            "moov" => write_box(
+                &mut out,
+                "moov",
+                &rewrite_moov(b.payload, tracks, durations, mdat_start)?,
+            ),
+            "mdat" => (),
+            kind => write_box(&mut out, kind, b.payload),
+        }
+    }
+    Ok(out)
+}
+
+/// Point the item extents at the chunks of the tracks in the output
+fn rewrite_meta(
+    payload: &[u8],
+    tracks: &[MergedTrack],
+    mdat_start: u64,
+) -> Result<Vec<u8>, InspectError> {
+    // full box header
+    let mut out = payload.get(..4).ok_or(InspectError::Truncated)?.to_vec();
+    for b in children(&payload[4..])? {
+        if b.kind != "iloc" {
+            write_box(&mut out, &b.kind, b.payload);
+            continue;
+        }
+        let mut iloc = b.payload.to_vec();
+        // libavif writes version 0 with 32 bit offsets and lengths and no base offset
+        if iloc.get(..6) != Some(&[0, 0, 0, 0, 0x44, 0][..]) {
+            return Err(InspectError::Invalid("iloc layout"));
+        }
+        let mut reader = Reader::new(&b.payload[6..]);
+        let item_count = reader.u16_be()?;
+        let mut pos = 8;
+        for _ in 0..item_count {
+            // item_ID, data_reference_index
+            reader.skip(4)?;
+            let extent_count = reader.u16_be()?;
+            pos += 6;
+            for _ in 0..extent_count {
+                let offset = reader.u32_be()? as u64;
+                reader.skip(4)?;
+                let track = tracks
+                    .iter()
+                    .find(|track| track.first_offset == offset)
+                    .ok_or(InspectError::Invalid("item data outside of the tracks"))?;
+                iloc[pos..pos + 4].copy_from_slice(&chunk_offset(track, mdat_start)?.to_be_bytes());
+                pos += 8;
+            }
+        }
+        write_box(&mut out, "iloc", &iloc);
+    }
+    Ok(out)
+}
+
+fn rewrite_moov(
+    payload: &[u8],
+    tracks: &[MergedTrack],
+    durations: Durations,
+    mdat_start: u64,
+) -> Result<Vec<u8>, InspectError> {
+    let mut out = Vec::new();
+    let mut tracks = tracks.iter();
+    for b in children(payload)? {
+        match b.kind.as_str() {
+            // duration after creation/modification time and timescale
+            "mvhd" => write_box(
+                &mut out,
+                "mvhd",
+                &set_duration(b.payload, 4, durations.movie)?,
+            ),
+            "trak" => {
+                let track = tracks
+                    .next()
+                    .ok_or(InspectError::Invalid("segments with different tracks"))?;
+                let mut trak = Vec::new();
+                for b in children(b.payload)? {
+                    match b.kind.as_str() {
+                        // duration after creation/modification time, track_ID and reserved
+                        "tkhd" => write_box(
+                            &mut trak,
+                            "tkhd",
+                            &set_duration(b.payload, 8, durations.movie)?,
+                        ),
+                        "mdia" => write_box(
+                            &mut trak,
+                            "mdia",
+                            &rewrite_mdia(b.payload, track, durations, mdat_start)?,
+                        ),
+                        kind => write_box(&mut trak, kind, b.payload),
+                    }
+                }
+                write_box(&mut out, "trak", &trak);
+            }
+            kind => write_box(&mut out, kind, b.payload),
+        }
+    }
+    Ok(out)
+}
+
+fn rewrite_mdia(
+    payload: &[u8],
+    track: &MergedTrack,
+    durations: Durations,
+    mdat_start: u64,
+) -> Result<Vec<u8>, InspectError> {
+    let mut out = Vec::new();
+    for b in children(payload)? {
+        match b.kind.as_str() {
+            "mdhd" => write_box(
+                &mut out,
+                "mdhd",
+                &set_duration(b.payload, 4, durations.media)?,
+            ),
+            "minf" => {
+                let mut minf = Vec::new();
+                for b in children(b.payload)? {
+                    match b.kind.as_str() {
+                        "stbl" => write_box(
+                            &mut minf,
+                            "stbl",
+                            &rewrite_stbl(b.payload, track, mdat_start)?,
+                        ),
+                        kind => write_box(&mut minf, kind, b.payload),
+                    }
+                }
+                write_box(&mut out, "minf", &minf);
+            }
+            kind => write_box(&mut out, kind, b.payload),
+        }
+    }
+    Ok(out)
+}
+
+fn rewrite_stbl(
+    payload: &[u8],
+    track: &MergedTrack,
+    mdat_start: u64,
+) -> Result<Vec<u8>, InspectError> {
+    let sample_count = track.sizes.len() as u32;
+    let mut out = Vec::new();
+    for b in children(payload)? {
+        match b.kind.as_str() {
+            "stco" | "co64" => write_table(&mut out, "stco", [1, chunk_offset(track, mdat_start)?]),
+            "stsc" => write_table(&mut out, "stsc", [1, 1, sample_count, 1]),
+            "stsz" => write_table(
+                &mut out,
+                "stsz",
+                [0, sample_count]
+                    .into_iter()
+                    .chain(track.sizes.iter().copied()),
+            ),
+            "stss" => write_table(
+                &mut out,
+                "stss",
+                iter_with_count(track.sync_samples.iter().copied(), track.sync_samples.len()),
+            ),
+            "stts" => write_table(
+                &mut out,
+                "stts",
+                iter_with_count(
+                    track
+                        .durations
+                        .iter()
+                        .flat_map(|&(count, duration)| [count, duration]),
+                    track.durations.len(),
+                ),
+            ),
+            kind => write_box(&mut out, kind, b.payload),
+        }
+    }
+    Ok(out)
+}
+
+fn chunk_offset(track: &MergedTrack, mdat_start: u64) -> Result<u32, InspectError> {
+    u32::try_from(mdat_start + track.mdat_offset).map_err(|_| InspectError::Invalid("output size"))
+}
+
+/// Replace the duration of a `mvhd`, `tkhd` or `mdhd` box, which follows the creation and
+/// modification time and `skip` other bytes.
+fn set_duration(payload: &[u8], skip: usize, duration: u64) -> Result<Vec<u8>, InspectError> {
+    let mut payload = payload.to_vec();
+    let (start, end) = match payload.first() {
+        Some(1) => (4 + 16 + skip, 4 + 16 + skip + 8),
+        Some(_) => (4 + 8 + skip, 4 + 8 + skip + 4),
+        None => return Err(InspectError::Truncated),
+    };
+    let field = payload.get_mut(start..end).ok_or(InspectError::Truncated)?;
+    match field.len() {
+        8 => field.copy_from_slice(&duration.to_be_bytes()),
+        _ => field.copy_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes()),
+    }
+    Ok(payload)
+}
+
+fn iter_with_count<I: Iterator<Item = u32>>(entries: I, count: usize) -> impl Iterator<Item = u32> {
+    std::iter::once(count as u32).chain(entries)
+}
+
+/// Write a full box (version 0, no flags) that only contains 32 bit values
+fn write_table<I: IntoIterator<Item = u32>>(out: &mut Vec<u8>, kind: &str, values: I) {
+    let mut payload = vec![0; 4];
+    for value in values {
+        payload.extend(value.to_be_bytes());
+    }
+    write_box(out, kind, &payload);
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &str, payload: &[u8]) {
+    out.extend(((payload.len() + 8) as u32).to_be_bytes());
+    out.extend(kind.as_bytes());
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVIE_TIMESCALE: u32 = 1000;
+    const MEDIA_TIMESCALE: u32 = 100;
+
+    fn bmff(kind: &str, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box(&mut out, kind, payload);
+        out
+    }
+
+    fn full_box(kind: &str, fields: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_table(&mut out, kind, fields.iter().copied());
+        out
+    }
+
+    /// A single track sequence laid out like libavif writes it, every sample lasts 10 units
+    fn segment(samples: &[&[u8]], sync_samples: Option<&[u32]>) -> Vec<u8> {
+        let build = |chunk_offset: u32| {
+            let count = samples.len() as u32;
+            let ftyp = bmff("ftyp", b"avis\0\0\0\0avifmsf1");
+            // one item whose data is the first sample
+            let iloc = [
+                &[0, 0, 0, 0, 0x44, 0, 0, 1, 0, 1, 0, 0, 0, 1][..],
+                &chunk_offset.to_be_bytes(),
+                &(samples[0].len() as u32).to_be_bytes(),
+            ]
+            .concat();
+            let meta = bmff("meta", &[&[0; 4][..], &bmff("iloc", &iloc)].concat());
+            let sizes = samples.iter().map(|sample| sample.len() as u32);
+            let stbl = [
+                full_box("stts", &[1, count, 10]),
+                full_box(
+                    "stsz",
+                    &[0, count].into_iter().chain(sizes).collect::<Vec<_>>(),
+                ),
+                full_box("stsc", &[1, 1, count, 1]),
+                full_box("stco", &[1, chunk_offset]),
+            ];
+            let stbl = match sync_samples {
+                Some(sync) => {
+                    let stss = [&[sync.len() as u32][..], sync].concat();
+                    [stbl.concat(), full_box("stss", &stss)].concat()
+                }
+                None => stbl.concat(),
+            };
+            let minf = bmff("minf", &bmff("stbl", &stbl));
+            let mdhd = full_box("mdhd", &[0, 0, MEDIA_TIMESCALE, 10 * count, 0]);
+            let mdia = bmff("mdia", &[mdhd, minf].concat());
+            let tkhd = full_box("tkhd", &[&[0, 0, 1, 0, 100 * count][..], &[0; 15]].concat());
+            let trak = bmff("trak", &[tkhd, mdia].concat());
+            let mvhd = full_box(
+                "mvhd",
+                &[&[0, 0, MOVIE_TIMESCALE, 100 * count][..], &[0; 21]].concat(),
+            );
+            let moov = bmff("moov", &[mvhd, trak].concat());
+            [ftyp, meta, moov].concat()
+        };
+        // the offset doesn't change the size of the headers
+        let headers = build(0);
+        let mut data = build(headers.len() as u32 + 8);
+        data.extend(bmff("mdat", &samples.concat()));
+        data
+    }
+
+    fn box_payload<'a>(data: &'a [u8], path: &[&str]) -> &'a [u8] {
+        path.iter().fold(data, |data, kind| {
+            // meta is a full box, its children follow the version and flags
+            let data = if *kind == "iloc" { &data[4..] } else { data };
+            children(data)
+                .unwrap()
+                .into_iter()
+                .find(|b| b.kind == *kind)
+                .unwrap()
+                .payload
+        })
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn stitch_segments() {
+        let first = segment(&[b"aaa", b"bb"], Some(&[1]));
+        let second = segment(&[b"cccc"], None);
+        let out = stitch(&[&first[..], &second[..]]).unwrap();
+
+        let segment = parse_segment(&out).unwrap();
+        let track = &segment.tracks[0];
+        assert_eq!(track.chunk, b"aaabbcccc");
+        assert_eq!(track.sizes, [3, 2, 4]);
+        assert_eq!(track.sync_samples, [1, 3]);
+        assert_eq!(track.durations, [(3, 10)]);
+        assert!(out.ends_with(b"mdataaabbcccc"));
+
+        // the item points at the first sample in the new mdat
+        let iloc = box_payload(&out, &["meta", "iloc"]);
+        assert_eq!(u32_at(iloc, 14) as u64, track.chunk_offset);
+        let trak = box_payload(&out, &["moov", "trak"]);
+        let mdhd = box_payload(trak, &["mdia", "mdhd"]);
+        assert_eq!(u32_at(mdhd, 16), 30);
+        let mvhd = box_payload(&out, &["moov", "mvhd"]);
+        assert_eq!(u32_at(mvhd, 16), 300);
+        assert_eq!(u32_at(box_payload(trak, &["tkhd"]), 20), 300);
+    }
+
+    #[test]
+    fn stitch_a_single_segment() {
+        let data = segment(&[b"aaa", b"bb"], Some(&[1]));
+        assert_eq!(stitch(&[&data[..]]).unwrap(), data);
+    }
+
+    #[test]
+    fn invalid_segments() {
+        assert!(matches!(stitch(&[]), Err(InspectError::Invalid(_))));
+        let data = segment(&[b"aaa"], None);
+        let without_moov = bmff("ftyp", b"avis\0\0\0\0");
+        assert!(matches!(
+            stitch(&[&data[..], &without_moov[..]]),
+            Err(InspectError::Invalid(_))
+        ));
+        // the data ends inside the mdat box
+        let truncated = &data[..data.len() - 1];
+        assert!(matches!(
+            stitch(&[&data[..], truncated]),
+            Err(InspectError::Truncated)
+        ));
+    }
+}
@@ -1,21 +1,40 @@
-use super::image::BorrowedAvifImage;
-use crate::{cli::AvifOptions, ffmpeg::types::FrameData, EncoderTask};
-use crossbeam::channel::{Receiver, RecvError};
+use super::{image::BorrowedAvifImage, stitch};
+use crate::{
+    cancel::CancellationToken,
+    ffmpeg::{
+        alpha,
+        formats::is_alpha_format,
+        types::{FrameData, TimingData},
+    },
+    inspect::InspectError,
+    options::AvifOptions,
+    progress::Progress,
+    EncoderTask,
+};
+use crossbeam::{
+    channel,
+    channel::{Receiver, RecvError, Sender},
+};
 use ffmpeg_next::{
     format,
     format::{context, Pixel},
-    Stream,
+    frame, Stream,
 };
-use indicatif::ProgressBar;
 use std::{
     fmt::{Debug, Display, Formatter},
     io,
     io::Write,
     path::PathBuf,
+    thread,
 };
 
 pub struct AvifEncoderTask;
 
+/// Frames queued for all segment encoders together (unless a single frame is larger)
+const MAX_SEGMENT_BYTES: usize = 256 * 1024 * 1024;
+/// Longest segment if the input has no keyframe for longer
+const MAX_SEGMENT_FRAMES: usize = 600;
+
 #[derive(Debug, thiserror::Error)]
 pub enum AvifEncoderError {
     #[error("Encoder error: {0}")]
@@ -28,34 +47,47 @@ pub enum AvifEncoderError {
     CannotCreateEncoder,
     #[error("Encoder thread didn't receive any image ({0})")]
     NoImageReceived(#[from] RecvError),
+    #[error("A libavif image could not be converted to a libav frame")]
+    ImageConversion,
+    #[error("Encoding was cancelled")]
+    Cancelled,
+    #[error("The encoded segments could not be joined: {0}")]
+    Stitch(#[from] InspectError),
+    #[error("A segment encoder thread panicked")]
+    SegmentPanic,
 }
 
 pub struct AvifEncoderConfig {
     args: AvifOptions,
     timescale: u64,
+    /// Number of frames, if the container knows it
+    frames: Option<usize>,
+    dimensions: Option<(u32, u32)>,
 }
 
+#[derive(serde::Serialize)]
 pub struct AvifEncoderStats {
     bytes_written: usize,
-    encoder_data: String,
+    settings: super::EncoderSettings,
 }
 
 impl Display for AvifEncoderStats {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Written {} bytes ({})",
-            self.bytes_written, self.encoder_data
+            "Written {} bytes ({:?})",
+            self.bytes_written, self.settings
         )
     }
 }
 
 impl EncoderTask for AvifEncoderTask {
-    type CliArgs = AvifOptions;
+    type Options = AvifOptions;
     type Config = AvifEncoderConfig;
     type ConfigError = io::Error;
     type RunError = AvifEncoderError;
     type EncoderStats = AvifEncoderStats;
+    type DecodeError = AvifEncoderError;
 
     fn accepted_formats() -> &'static [Pixel] {
         &[Pixel::YUV444P, Pixel::YUV420P, Pixel::YUV422P]
@@ -70,14 +102,17 @@ impl EncoderTask for AvifEncoderTask {
     }
 
     fn configure(
-        args: Self::CliArgs,
+        args: Self::Options,
         stream: &Stream,
         _ctx: &context::Input,
+        dimensions: Option<(u32, u32)>,
     ) -> Result<Self::Config, Self::ConfigError> {
         let timebase = stream.time_base();
         Ok(Self::Config {
             args,
             timescale: (timebase.1 / timebase.0) as u64,
+            frames: (stream.frames() > 0).then_some(stream.frames() as usize),
+            dimensions,
         })
     }
 
@@ -85,25 +120,34 @@ impl EncoderTask for AvifEncoderTask {
         mut output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError> {
-        let mut encoder = make_encoder(&config)?;
-
-        for (mut frame, _) in frame_rx {
-            let format = frame.format();
-            let pkt_duration = unsafe { (*frame.as_ptr()).pkt_duration } as u64;
-            let img = BorrowedAvifImage::from_ffmpeg(&mut frame)
-                .ok_or(Self::RunError::FrameConversion(format))?;
-            encoder.add_image_none(img.as_ref(), pkt_duration)?;
+        if let Some(segment_frames) = config.args.segment_frames {
+            return run_segmented(output, &config, frame_rx, progress, cancel, segment_frames);
+        }
+        let mut encoder = make_encoder(&config, max_threads(&config))?;
+
+        for (mut frame, timing) in frame_rx {
+            if cancel.is_cancelled() {
+                return Err(Self::RunError::Cancelled);
+            }
+            let img = to_avif_image(&mut frame, &config)?;
+            // the timescale is the stream's time base, so is the duration
+            encoder.add_image_none(img.as_ref(), timing.duration as u64)?;
             progress.inc(1);
         }
+        // ffmpeg stops sending frames when cancelled, so this isn't the end of the input
+        if cancel.is_cancelled() {
+            return Err(Self::RunError::Cancelled);
+        }
 
-        progress.finish_with_message("Finishing...");
+        progress.finishing();
         let data = encoder.finish()?;
-        progress.finish_and_clear();
+        progress.finish();
         output.write_all(data.as_slice())?;
         Ok(AvifEncoderStats {
-            encoder_data: format!("{encoder:?}"),
+            settings: encoder.settings(),
             bytes_written: data.len(),
         })
     }
@@ -112,33 +156,378 @@ impl EncoderTask for AvifEncoderTask {
         mut output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError> {
-        let mut encoder = make_encoder(&config)?;
+        let mut encoder = make_encoder(&config, max_threads(&config))?;
 
         let (mut frame, _) = frame_rx.recv()?;
-        let format = frame.format();
-        let img = BorrowedAvifImage::from_ffmpeg(&mut frame)
-            .ok_or(Self::RunError::FrameConversion(format))?;
+        if cancel.is_cancelled() {
+            return Err(Self::RunError::Cancelled);
+        }
+        let img = to_avif_image(&mut frame, &config)?;
 
         let data = encoder.encode_single_image(img.as_ref())?;
-        progress.finish_and_clear();
+        progress.finish();
         output.write_all(data.as_slice())?;
         Ok(AvifEncoderStats {
-            encoder_data: format!("{encoder:?}"),
+            settings: encoder.settings(),
             bytes_written: data.len(),
         })
     }
+
+    fn decode_output(data: &[u8]) -> Result<Vec<FrameData>, Self::DecodeError> {
+        let mut decoder = super::Decoder::from_memory(data)?;
+        let mut frames = Vec::with_capacity(decoder.image_count());
+        while decoder.next_image()? {
+            frames.push((
+                decoder
+                    .image_to_ffmpeg()
+                    .ok_or(Self::DecodeError::ImageConversion)?
+                    .into(),
+                TimingData::from_ms(decoder.image_pts_ms(), decoder.image_duration_ms()),
+            ));
+        }
+        Ok(frames)
+    }
+}
+
+/// Wrap `frame` without copying it, the color is premultiplied first if that's enabled
+fn to_avif_image<'a>(
+    frame: &'a mut frame::Video,
+    config: &AvifEncoderConfig,
+) -> Result<BorrowedAvifImage<'a>, AvifEncoderError> {
+    let format = frame.format();
+    let premultiply = config.args.premultiply_alpha && is_alpha_format(format);
+    if premultiply {
+        alpha::premultiply(frame);
+    }
+    let mut img =
+        BorrowedAvifImage::from_ffmpeg(frame).ok_or(AvifEncoderError::FrameConversion(format))?;
+    img.set_alpha_premultiplied(premultiply);
+    Ok(img)
+}
+
+/// How an animation is split into segments that are encoded in parallel
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct SegmentPlan {
+    /// Segments that are encoded at once
+    workers: usize,
+    /// A segment ends at the first keyframe of the input after this many frames
+    min_frames: usize,
+    /// or after this many frames, which are queued until the segment's encoder gets to them
+    max_frames: usize,
+}
+
+/// Split the animation into as many segments as there are `threads` if the number of `frames`
+/// is known, but none shorter than `min_frames` (every keyframe costs size). Otherwise every
+/// thread encodes one segment of at least `min_frames` at a time.
+///
+/// Segments are cut short so that the queued frames of all workers, of `frame_bytes` each,
+/// stay within [`MAX_SEGMENT_BYTES`].
+fn plan_segments(
+    frames: Option<usize>,
+    threads: usize,
+    min_frames: usize,
+    frame_bytes: usize,
+) -> SegmentPlan {
+    // a segment with a single frame would be a still image
+    let min_frames = min_frames.max(2);
+    let (workers, min_frames) = match frames {
+        Some(frames) => {
+            let segments = (frames / min_frames).clamp(1, threads.max(1));
+            (segments, frames.div_ceil(segments).max(min_frames))
+        }
+        None => (threads.max(1), min_frames),
+    };
+    let max_frames =
+        (MAX_SEGMENT_BYTES / workers / frame_bytes.max(1)).clamp(2, MAX_SEGMENT_FRAMES);
+    SegmentPlan {
+        workers,
+        min_frames: min_frames.min(max_frames),
+        max_frames,
+    }
+}
+
+fn max_threads(config: &AvifEncoderConfig) -> usize {
+    config.args.max_threads.unwrap_or_else(num_cpus::get).max(1)
 }
 
-fn make_encoder(config: &AvifEncoderConfig) -> Result<super::Encoder, AvifEncoderError> {
+fn make_encoder(
+    config: &AvifEncoderConfig,
+    max_threads: usize,
+) -> Result<super::Encoder, AvifEncoderError> {
     let mut encoder = super::Encoder::new().ok_or(AvifEncoderError::CannotCreateEncoder)?;
     encoder
         .set_timescale(config.timescale)
-        .set_max_threads(config.args.max_threads.unwrap_or_else(num_cpus::get))
+        .set_max_threads(max_threads)
         .set_speed(config.args.speed)
         .set_codec(config.args.codec)
         .set_quantizer(config.args.quantizer)
         .set_quantizer_alpha(config.args.quantizer_alpha);
     Ok(encoder)
 }
+
+/// Split the animation at the input's keyframes into segments of at least `segment_frames`
+/// frames, encode them on separate encoders in parallel and join them into one sequence.
+///
+/// The frames are streamed to the encoder of their segment, see [`plan_segments`] for how
+/// long the segments are and how the threads are shared.
+fn run_segmented<W: Write>(
+    mut output: W,
+    config: &AvifEncoderConfig,
+    frame_rx: Receiver<FrameData>,
+    progress: Progress,
+    cancel: &CancellationToken,
+    segment_frames: usize,
+) -> Result<AvifEncoderStats, AvifEncoderError> {
+    let threads = max_threads(config);
+    let frame_bytes = config
+        .dimensions
+        .map_or(0, |(width, height)| width as usize * height as usize * 4);
+    let plan = plan_segments(config.frames, threads, segment_frames, frame_bytes);
+    let encoder_threads = (threads / plan.workers).max(1);
+
+    // a segment is only handed over once a worker is free to encode it
+    let (segment_tx, segment_rx) = channel::bounded::<(usize, Receiver<FrameData>)>(0);
+    let mut segments = thread::scope(|scope| {
+        let handles = (0..plan.workers)
+            .map(|_| {
+                let segment_rx = segment_rx.clone();
+                scope.spawn(move || {
+                    let mut encoded = Vec::new();
+                    for (index, frame_rx) in segment_rx {
+                        let mut encoder = make_encoder(config, encoder_threads)?;
+                        for (mut frame, timing) in frame_rx {
+                            if cancel.is_cancelled() {
+                                return Err(AvifEncoderError::Cancelled);
+                            }
+                            let img = to_avif_image(&mut frame, config)?;
+                            encoder.add_image_none(img.as_ref(), timing.duration as u64)?;
+                        }
+                        encoded.push((index, encoder.finish()?, encoder.settings()));
+                    }
+                    Ok(encoded)
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(segment_rx);
+
+        let mut segmenter = Segmenter {
+            plan,
+            segment_tx,
+            segments: 0,
+            current: None,
+        };
+        // one frame is held back, the last frame can't start a segment
+        let mut held = None;
+        for frame in frame_rx {
+            if cancel.is_cancelled() {
+                break;
+            }
+            progress.inc(1);
+            if let Some(previous) = held.replace(frame) {
+                if !segmenter.send(previous, true) {
+                    break;
+                }
+            }
+        }
+        if let (Some(last), false) = (held, cancel.is_cancelled()) {
+            segmenter.send(last, false);
+        }
+        // the workers stop once the last segment is complete
+        drop(segmenter);
+        progress.finishing();
+
+        let mut segments = Vec::new();
+        for handle in handles {
+            segments.extend(
+                handle
+                    .join()
+                    .map_err(|_| AvifEncoderError::SegmentPanic)??,
+            );
+        }
+        Ok::<_, AvifEncoderError>(segments)
+    })?;
+    // ffmpeg stops sending frames when cancelled, so this isn't the end of the input
+    if cancel.is_cancelled() {
+        return Err(AvifEncoderError::Cancelled);
+    }
+    segments.sort_by_key(|(index, _, _)| *index);
+
+    let (_, _, first_settings) = segments.first().ok_or(RecvError)?;
+    let settings = super::EncoderSettings {
+        max_threads: threads,
+        color_obu_size: segments.iter().map(|(_, _, s)| s.color_obu_size).sum(),
+        alpha_obu_size: segments.iter().map(|(_, _, s)| s.alpha_obu_size).sum(),
+        ..first_settings.clone()
+    };
+    let data = match segments.as_slice() {
+        [(_, data, _)] => data.to_vec(),
+        _ => {
+            let parts = segments
+                .iter()
+                .map(|(_, data, _)| data.as_slice())
+                .collect::<Vec<_>>();
+            stitch::stitch(&parts)?
+        }
+    };
+    progress.finish();
+    output.write_all(&data)?;
+    Ok(AvifEncoderStats {
+        bytes_written: data.len(),
+        settings,
+    })
+}
+
+/// Sends the frames of an animation to the segment encoders, split by a [`SegmentPlan`]
+struct Segmenter {
+    plan: SegmentPlan,
+    segment_tx: Sender<(usize, Receiver<FrameData>)>,
+    segments: usize,
+    /// The segment that receives the frames and its number of frames so far
+    current: Option<(Sender<FrameData>, usize)>,
+}
+
+impl Segmenter {
+    /// Send `frame` to the current segment, or start a new one with it at a keyframe of a long
+    /// enough segment, if `more_follow` (so the new segment gets at least two frames).
+    ///
+    /// Returns `false` if the encoders stopped.
+    fn send(&mut self, frame: FrameData, more_follow: bool) -> bool {
+        if self.starts_segment(&frame, more_follow) {
+            // the previous segment is complete, its worker can take the next one
+            self.current = None;
+            let (frame_tx, frame_rx) = channel::bounded(self.plan.max_frames);
+            if self.segment_tx.send((self.segments, frame_rx)).is_err() {
+                return false;
+            }
+            self.segments += 1;
+            self.current = Some((frame_tx, 0));
+        }
+        match &mut self.current {
+            Some((frame_tx, frames)) => {
+                *frames += 1;
+                frame_tx.send(frame).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    fn starts_segment(&self, (_, timing): &FrameData, more_follow: bool) -> bool {
+        match &self.current {
+            Some((_, frames)) => {
+                more_follow
+                    && *frames >= self.plan.min_frames
+                    && (timing.keyframe || *frames >= self.plan.max_frames)
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_BYTES: usize = 112 * 112 * 4;
+
+    fn plan(workers: usize, min_frames: usize, max_frames: usize) -> SegmentPlan {
+        SegmentPlan {
+            workers,
+            min_frames,
+            max_frames,
+        }
+    }
+
+    #[test]
+    fn plan_known_frame_count() {
+        let max = MAX_SEGMENT_FRAMES;
+        assert_eq!(
+            plan_segments(Some(100), 4, 10, FRAME_BYTES),
+            plan(4, 25, max)
+        );
+        // not enough frames for every thread
+        assert_eq!(
+            plan_segments(Some(30), 8, 10, FRAME_BYTES),
+            plan(3, 10, max)
+        );
+        assert_eq!(plan_segments(Some(5), 8, 10, FRAME_BYTES), plan(1, 10, max));
+        // the last segment may be shorter
+        assert_eq!(
+            plan_segments(Some(101), 4, 10, FRAME_BYTES),
+            plan(4, 26, max)
+        );
+    }
+
+    #[test]
+    fn plan_unknown_frame_count() {
+        let max = MAX_SEGMENT_FRAMES;
+        assert_eq!(plan_segments(None, 4, 10, FRAME_BYTES), plan(4, 10, max));
+        assert_eq!(plan_segments(None, 0, 10, FRAME_BYTES), plan(1, 10, max));
+    }
+
+    #[test]
+    fn segments_have_at_least_two_frames() {
+        let max = MAX_SEGMENT_FRAMES;
+        assert_eq!(plan_segments(Some(10), 8, 1, FRAME_BYTES), plan(5, 2, max));
+        assert_eq!(plan_segments(None, 8, 0, FRAME_BYTES), plan(8, 2, max));
+        assert_eq!(plan_segments(None, 8, 10, usize::MAX), plan(8, 2, 2));
+    }
+
+    #[test]
+    fn queued_frames_are_limited() {
+        // 1080p frames with alpha, about 8 MiB each
+        let frame_bytes = 1920 * 1080 * 4;
+        let max = MAX_SEGMENT_BYTES / 4 / frame_bytes;
+        assert_eq!(
+            plan_segments(Some(1000), 4, 10, frame_bytes),
+            plan(4, max, max)
+        );
+        assert_eq!(plan_segments(None, 4, 1, frame_bytes), plan(4, 2, max));
+    }
+
+    /// The lengths of the segments that frames with these `keyframes` are split into
+    fn split(plan: SegmentPlan, keyframes: &[bool]) -> Vec<usize> {
+        let (segment_tx, segment_rx) = channel::unbounded();
+        // segments are complete before the next one starts, like the workers take them
+        let counter = thread::spawn(move || {
+            segment_rx
+                .iter()
+                .map(|(_, frame_rx): (usize, Receiver<FrameData>)| frame_rx.iter().count())
+                .collect::<Vec<_>>()
+        });
+        let mut segmenter = Segmenter {
+            plan,
+            segment_tx,
+            segments: 0,
+            current: None,
+        };
+        for (i, &keyframe) in keyframes.iter().enumerate() {
+            let timing = TimingData {
+                keyframe,
+                ..TimingData::from_ms(i as i64 * 10, 10)
+            };
+            let frame = (frame::Video::empty().into(), timing);
+            assert!(segmenter.send(frame, i + 1 < keyframes.len()));
+        }
+        drop(segmenter);
+        counter.join().unwrap()
+    }
+
+    #[test]
+    fn split_at_keyframes() {
+        let keyframes = [
+            true, false, false, true, false, true, false, false, true, false,
+        ];
+        assert_eq!(split(plan(2, 2, 10), &keyframes), [3, 2, 3, 2]);
+        assert_eq!(split(plan(2, 4, 10), &keyframes), [5, 5]);
+    }
+
+    #[test]
+    fn long_segments_are_cut() {
+        assert_eq!(split(plan(2, 2, 4), &[false; 10]), [4, 4, 2]);
+        // the last frame can't start a segment of its own
+        assert_eq!(split(plan(2, 2, 4), &[false; 9]), [4, 5]);
+    }
+}
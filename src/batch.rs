@@ -0,0 +1,279 @@
+//! Converting multiple files, directories or glob patterns with a fixed number of concurrent jobs.
+use crate::{
+    cli::{BatchOptions, IoOptions, MetricsOptions, OutputFormat, ProgressMode},
+    exit_on_failure, run_task, task_reporter, CliError,
+};
+use emote_tool::{
+    cancel::CancellationToken,
+    options::SequenceOptions,
+    report::{ErrorReport, Stage, TaskReport},
+    EncoderTask, TaskError,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BatchError {
+    #[error("Invalid glob pattern: {0}")]
+    InvalidPattern(#[from] glob::PatternError),
+    #[error("Couldn't read {0}: {1}")]
+    ReadInput(PathBuf, io::Error),
+    #[error("No input files found")]
+    NoInputs,
+}
+
+pub struct BatchJob {
+    pub input: PathBuf,
+    /// Output path relative to the output directory (the extension is replaced by the task)
+    pub relative_output: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+pub struct BatchResult<S> {
+    pub input: PathBuf,
+    #[serde(flatten)]
+    pub report: TaskReport<S>,
+}
+
+/// Resolve files, directories and glob patterns to a list of jobs.
+pub fn collect_inputs(inputs: &[String]) -> Result<Vec<BatchJob>, BatchError> {
+    let mut jobs = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            collect_dir(path, path, &mut jobs)?;
+        } else if path.is_file() {
+            jobs.push(BatchJob {
+                input: path.to_path_buf(),
+                relative_output: path.file_name().map(PathBuf::from).unwrap_or_default(),
+            });
+        } else {
+            let base = glob_base(input);
+            for entry in glob::glob(input)? {
+                let entry = entry
+                    .map_err(|e| BatchError::ReadInput(e.path().to_path_buf(), e.into_error()))?;
+                if entry.is_file() {
+                    jobs.push(job_relative_to(&base, entry));
+                }
+            }
+        }
+    }
+    if jobs.is_empty() {
+        Err(BatchError::NoInputs)
+    } else {
+        Ok(jobs)
+    }
+}
+
+fn collect_dir(base: &Path, dir: &Path, jobs: &mut Vec<BatchJob>) -> Result<(), BatchError> {
+    let read_error = |e: io::Error| BatchError::ReadInput(dir.to_path_buf(), e);
+    let mut entries = fs::read_dir(dir)
+        .map_err(read_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(read_error)?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_dir(base, &path, jobs)?;
+        } else if path.is_file() {
+            jobs.push(job_relative_to(base, path));
+        }
+    }
+    Ok(())
+}
+
+fn job_relative_to(base: &Path, input: PathBuf) -> BatchJob {
+    let relative_output = match input.strip_prefix(base) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => input.file_name().map(PathBuf::from).unwrap_or_default(),
+    };
+    BatchJob {
+        input,
+        relative_output,
+    }
+}
+
+/// Leading components of a glob pattern that don't contain any wildcards
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+/// Convert all inputs and print a summary of successes and failures.
+pub fn report_batch<T>(
+    options: &BatchOptions,
+    task_options: T::Options,
+    cancel: &CancellationToken,
+) -> Result<(), CliError>
+where
+    T: EncoderTask,
+    T::Options: Clone + Send,
+{
+    let results = run_batch::<T>(options, task_options, cancel)?;
+    match options.output_format {
+        OutputFormat::Text => print_summary(&results),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&results)?),
+    }
+    exit_on_failure(results.iter().all(|result| result.report.success), cancel);
+    Ok(())
+}
+
+pub fn run_batch<T>(
+    options: &BatchOptions,
+    task_options: T::Options,
+    cancel: &CancellationToken,
+) -> Result<Vec<BatchResult<T::EncoderStats>>, BatchError>
+where
+    T: EncoderTask,
+    T::Options: Clone + Send,
+{
+    let jobs = collect_inputs(&options.inputs)?;
+    let inputs = jobs.iter().map(|job| job.input.clone()).collect::<Vec<_>>();
+
+    let progress_manager = MultiProgress::new();
+    let batch_progress = match options.progress {
+        ProgressMode::Bar => progress_manager.add(ProgressBar::new(jobs.len() as u64).with_style(
+            ProgressStyle::default_bar().template("[Batch {spinner}] File {pos}/{len} ({percent}%) {bar:20} {elapsed}/{eta} {msg}").unwrap())),
+        _ => ProgressBar::hidden(),
+    };
+
+    let (job_tx, job_rx) = crossbeam::channel::unbounded();
+    for job in jobs.into_iter().enumerate() {
+        // the receiver is still alive
+        job_tx.send(job).unwrap();
+    }
+    drop(job_tx);
+
+    let mut reports = inputs.iter().map(|_| None).collect::<Vec<_>>();
+    thread::scope(|scope| {
+        let workers = (0..options.jobs.max(1))
+            .map(|_| {
+                let job_rx = job_rx.clone();
+                let task_options = task_options.clone();
+                let progress_manager = &progress_manager;
+                let batch_progress = &batch_progress;
+                scope.spawn(move || {
+                    job_rx
+                        .iter()
+                        .map(|(index, job)| {
+                            let report = convert::<T>(
+                                &job,
+                                options,
+                                task_options.clone(),
+                                progress_manager,
+                                cancel,
+                            );
+                            batch_progress.inc(1);
+                            (index, report)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            // a panicking worker leaves its reports empty, they're reported as panics below
+            for (index, report) in worker.join().unwrap_or_default() {
+                reports[index] = Some(report);
+            }
+        }
+    });
+    batch_progress.finish_and_clear();
+
+    Ok(inputs
+        .into_iter()
+        .zip(reports)
+        .map(|(input, report)| BatchResult {
+            input,
+            report: report.unwrap_or_else(|| TaskReport::failed(ErrorReport::panic(Stage::Task))),
+        })
+        .collect())
+}
+
+/// Convert a single job into `options.output_dir`, errors are part of the report.
+pub fn convert<T>(
+    job: &BatchJob,
+    options: &BatchOptions,
+    task_options: T::Options,
+    progress_manager: &MultiProgress,
+    cancel: &CancellationToken,
+) -> TaskReport<T::EncoderStats>
+where
+    T: EncoderTask,
+{
+    // the remaining jobs are skipped, but still reported
+    if cancel.is_cancelled() {
+        return TaskReport::failed(ErrorReport::new(Stage::Task, &TaskError::Cancelled));
+    }
+    let output = options.output_dir.join(&job.relative_output);
+    if let Some(parent) = output.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            let error = TaskError::IoOutputFileError(e);
+            return TaskReport::failed(ErrorReport::new(Stage::Task, &error));
+        }
+    }
+    let io_options = IoOptions {
+        input: job.input.to_string_lossy().into_owned(),
+        output: output.to_string_lossy().into_owned(),
+        stream: None,
+        no_autorotate: false,
+        alpha_bleed: false,
+        sequence: SequenceOptions::default(),
+        limits: options.limits,
+        output_format: options.output_format,
+        progress: options.progress,
+        overwrite: options.overwrite,
+        no_clobber: options.no_clobber,
+    };
+    let reporter = task_reporter(options.progress, progress_manager, Some(&io_options.input));
+    run_task::<T>(
+        &io_options,
+        &MetricsOptions::default(),
+        task_options,
+        reporter,
+        cancel,
+    )
+    .unwrap_or_else(|e| TaskReport::failed(ErrorReport::new(Stage::Task, &e)))
+}
+
+fn print_summary<S>(results: &[BatchResult<S>]) {
+    let inputs = results
+        .iter()
+        .map(|result| result.input.to_string_lossy())
+        .collect::<Vec<_>>();
+    let width = inputs.iter().map(|input| input.len()).max().unwrap_or(0);
+
+    println!("{:<7} {:<width$} Result", "Status", "Input", width = width);
+    for (result, input) in results.iter().zip(&inputs) {
+        let report = &result.report;
+        let (status, message) = match (&report.output, report.errors.first()) {
+            (Some(output), None) if report.skipped => (
+                "skipped",
+                format!("{} already exists", output.to_string_lossy()),
+            ),
+            (Some(output), None) => (
+                "ok",
+                format!(
+                    "{} ({} bytes)",
+                    output.to_string_lossy(),
+                    report.bytes.unwrap_or_default()
+                ),
+            ),
+            (_, Some(error)) => ("failed", format!("[{:?}] {}", error.stage, error.message)),
+            (None, None) => ("failed", "No output".to_string()),
+        };
+        println!("{:<7} {:<width$} {}", status, input, message, width = width);
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| !result.report.success)
+        .count();
+    println!("{} succeeded, {} failed", results.len() - failed, failed);
+}
@@ -0,0 +1,22 @@
+//! Stopping a running conversion from another thread (e.g. a Ctrl+C handler).
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Shared flag that's checked by the decoder and the encoders between frames.
+///
+/// Once cancelled, ffmpeg stops decoding, the encoders return without writing anything
+/// and the partial output is deleted.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -1,12 +1,30 @@
-use crate::{avif, webp};
 use clap::{Args, Parser, Subcommand};
-use hex::FromHex;
-use std::{borrow::Cow, os::raw::c_int, str::FromStr};
+use emote_tool::{
+    ffmpeg::limits::Limits,
+    options::{
+        AvifOptions, OverwritePolicy, Platform, SequenceOptions, Size, SpriteSheetOptions,
+        WebpOptions,
+    },
+    pipeline::{InputOptions, InputStream},
+    TaskError,
+};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 pub struct Cli {
+    /// Apply the encoder options of `[profile.<name>]` in the config file,
+    /// flags on the command line take precedence
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
+    /// Config file with the profiles, by default `emote-tool.toml` in the current directory
+    /// or `emote-tool/config.toml` in the user's config directory
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Set for a `--sandbox` worker by its parent, the pipe the report is written to
+    #[clap(long, hide = true)]
+    pub result_fd: Option<i32>,
     #[clap(subcommand)]
     pub command: CliCommand,
 }
@@ -15,13 +33,153 @@ pub struct Cli {
 pub enum CliCommand {
     Avif(AvifCommand),
     Webp(WebpCommand),
+    /// Print information about an input without encoding it
+    Probe(ProbeCommand),
+    /// Print how an existing AVIF or WebP file was encoded
+    Inspect(InspectCommand),
+    /// Convert multiple files, directories or glob patterns
+    Batch(BatchCommand),
+    /// Decode once and encode to multiple formats in parallel
+    Multi(MultiCommand),
+    /// Encode to every format and keep the smallest output
+    Auto(AutoCommand),
+    /// Convert uploaded files over HTTP (`POST /convert?format=webp&quality=80`)
+    Serve(ServeCommand),
+    /// Convert files that are added to a directory until Ctrl+C is pressed
+    Watch(WatchCommand),
+    /// Pack all frames into a grid (sprite sheet) with a JSON atlas of the frames
+    Sprite(SpriteCommand),
+    /// Print the effective encoder options (defaults, the profile and flags) as a profile
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+pub enum ProgressMode {
+    /// Progress bars on the terminal
+    Bar,
+    /// One JSON object per update on stderr
+    Json,
+    None,
 }
 
 #[derive(Args)]
 pub struct IoOptions {
+    /// Input file or URL, `-` reads from stdin.
+    /// Image sequences can be passed as a pattern (`frame_%04d.png`) or a quoted glob (`"frames/*.png"`)
     pub input: String,
+    /// Output name (the extension is added), `-` writes to stdout
     #[clap(default_value = "out")]
     pub output: String,
+    /// Index of the video stream to convert (see `probe`), by default the best video stream
+    /// that isn't an attached picture (cover art)
+    #[clap(long)]
+    pub stream: Option<usize>,
+    /// Keep the frames as they're stored instead of rotating them according to the metadata
+    #[clap(long)]
+    pub no_autorotate: bool,
+    /// Fill the color of fully transparent pixels from the visible ones, which avoids dark
+    /// halos around transparent edges but converts every frame at full resolution first
+    #[clap(long)]
+    pub alpha_bleed: bool,
+    /// Scale the frames down to fit into this size (e.g. `112x112`), keeping the aspect ratio
+    #[clap(long)]
+    pub resize: Option<Size>,
+    /// Scale the frames down to the largest size the site shows emotes at (unless --resize is set)
+    #[clap(long, arg_enum)]
+    pub platform: Option<Platform>,
+    #[clap(flatten)]
+    pub sequence: SequenceOptions,
+    #[clap(flatten)]
+    pub limits: Limits,
+    /// Print the result (stats, metrics and errors) as text or as a single JSON document
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+    /// How progress is shown while converting
+    #[clap(long, arg_enum, default_value_t = ProgressMode::Bar)]
+    pub progress: ProgressMode,
+    /// Replace existing output files (the default)
+    #[clap(long, conflicts_with = "no-clobber")]
+    pub overwrite: bool,
+    /// Skip inputs whose output file already exists
+    #[clap(long)]
+    pub no_clobber: bool,
+}
+
+impl IoOptions {
+    pub fn overwrite_policy(&self) -> OverwritePolicy {
+        overwrite_policy(self.overwrite, self.no_clobber)
+    }
+
+    /// `--resize`, or the size of `--platform`
+    pub fn max_size(&self) -> Option<Size> {
+        self.resize
+            .or_else(|| self.platform.map(Platform::max_size))
+    }
+
+    pub fn input_options(&self) -> InputOptions {
+        InputOptions {
+            stream: self.stream,
+            autorotate: !self.no_autorotate,
+            alpha_bleed: self.alpha_bleed,
+            limits: self.limits,
+            max_size: self.max_size(),
+            sequence: self.sequence.is_set().then(|| self.sequence.clone()),
+            restricted: false,
+        }
+    }
+
+    pub fn open_input(&self) -> Result<InputStream, TaskError> {
+        InputStream::open_with(Path::new(&self.input), &self.input_options())
+    }
+}
+
+#[derive(Args, Default)]
+pub struct MetricsOptions {
+    /// Decode the output and compare it against the source frames (PSNR/SSIM)
+    #[clap(long)]
+    pub metrics: bool,
+    /// Write per-frame metrics to a CSV file (implies --metrics)
+    #[clap(long)]
+    pub metrics_csv: Option<PathBuf>,
+}
+
+impl MetricsOptions {
+    pub fn enabled(&self) -> bool {
+        self.metrics || self.metrics_csv.is_some()
+    }
+}
+
+/// Isolation of a single conversion, see `sandbox`
+#[derive(Args, Default)]
+pub struct SandboxOptions {
+    /// Convert in a separate worker process, so a crashing or hanging decoder or encoder
+    /// only fails the conversion
+    #[clap(long)]
+    pub sandbox: bool,
+    /// Kill the worker after this many seconds (implies --sandbox)
+    #[clap(long)]
+    pub timeout: Option<f64>,
+    /// Maximum address space of the worker in bytes (implies --sandbox)
+    #[clap(long)]
+    pub max_memory: Option<u64>,
+    /// Maximum CPU time of the worker in seconds (implies --sandbox)
+    #[clap(long)]
+    pub max_cpu_time: Option<u64>,
+}
+
+impl SandboxOptions {
+    pub fn enabled(&self) -> bool {
+        self.sandbox
+            || self.timeout.is_some()
+            || self.max_memory.is_some()
+            || self.max_cpu_time.is_some()
+    }
 }
 
 #[derive(Args)]
@@ -29,6 +187,10 @@ pub struct AvifCommand {
     #[clap(flatten)]
     pub io: IoOptions,
     #[clap(flatten)]
+    pub metrics: MetricsOptions,
+    #[clap(flatten)]
+    pub sandbox: SandboxOptions,
+    #[clap(flatten)]
     pub opts: AvifOptions,
 }
 
@@ -37,127 +199,253 @@ pub struct WebpCommand {
     #[clap(flatten)]
     pub io: IoOptions,
     #[clap(flatten)]
+    pub metrics: MetricsOptions,
+    #[clap(flatten)]
+    pub sandbox: SandboxOptions,
+    #[clap(flatten)]
     pub opts: WebpOptions,
 }
 
 #[derive(Args)]
-pub struct AvifOptions {
-    #[clap(short, long, arg_enum, default_value_t = avif::Codec::Auto)]
-    pub codec: avif::Codec,
-    #[clap(long, default_value = "0")]
-    pub quantizer: u8,
-    #[clap(long, default_value = "0")]
-    pub quantizer_alpha: u8,
-    #[clap(long, default_value = "10")]
-    pub speed: u8,
+pub struct ProbeCommand {
+    pub input: String,
+    /// Index of the video stream to probe, all streams are listed regardless
     #[clap(long)]
-    pub max_threads: Option<usize>,
+    pub stream: Option<usize>,
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
 }
 
 #[derive(Args)]
-pub struct WebpOptions {
-    // general encoder options
-    #[clap(long, arg_enum)]
-    pub preset: Option<webp::Preset>,
+pub struct InspectCommand {
+    pub input: PathBuf,
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
 
+#[derive(Args)]
+pub struct MultiCommand {
+    #[clap(flatten)]
+    pub io: IoOptions,
+    /// Encode to AVIF (if neither --avif nor --webp is set, both are encoded)
     #[clap(long)]
-    pub lossless: Option<bool>,
-    #[clap(long)]
-    pub quality: Option<f32>,
-    #[clap(long)]
-    pub method: Option<c_int>,
-    #[clap(long, arg_enum)]
-    pub image_hint: Option<webp::ImageHint>,
-    #[clap(long)]
-    pub target_size: Option<c_int>,
-    #[clap(long)]
-    pub target_psnr: Option<f32>,
-    #[clap(long)]
-    pub segments: Option<c_int>,
-    #[clap(long)]
-    pub sns_strength: Option<c_int>,
-    #[clap(long)]
-    pub filter_strength: Option<c_int>,
-    #[clap(long)]
-    pub filter_sharpness: Option<c_int>,
-    #[clap(long)]
-    pub strong_filter: Option<bool>,
-    #[clap(long)]
-    pub autofilter: Option<bool>,
-    #[clap(long)]
-    pub alpha_compression: Option<bool>,
-    #[clap(long, arg_enum)]
-    pub alpha_filtering: Option<webp::AlphaFiltering>,
-    #[clap(long)]
-    pub alpha_quality: Option<c_int>,
-    #[clap(long)]
-    pub pass: Option<c_int>,
-    #[clap(long)]
-    pub show_compressed: Option<bool>,
-    #[clap(long, arg_enum)]
-    pub preprocessing: Option<webp::PreprocessingFilter>,
-    #[clap(long)]
-    pub partitions: Option<c_int>,
-    #[clap(long)]
-    pub partition_limit: Option<c_int>,
-    #[clap(long)]
-    pub emulate_jpeg_size: Option<bool>,
+    pub avif: bool,
+    /// Encode to WebP (if neither --avif nor --webp is set, both are encoded)
     #[clap(long)]
-    pub thread_level: Option<bool>,
+    pub webp: bool,
+    #[clap(flatten)]
+    pub avif_opts: AvifOptions,
+    #[clap(flatten)]
+    pub webp_opts: WebpOptions,
+}
+
+#[derive(Args)]
+pub struct AutoCommand {
+    #[clap(flatten)]
+    pub io: IoOptions,
+    /// Shared quality target (0-100) that overrides the AVIF quantizers and the WebP quality
     #[clap(long)]
-    pub low_memory: Option<bool>,
+    pub target_quality: Option<u8>,
+    /// Compute PSNR/SSIM for every candidate
     #[clap(long)]
-    pub near_lossless: Option<c_int>,
+    pub metrics: bool,
+    /// Disqualify candidates with a lower mean SSIM (implies --metrics)
     #[clap(long)]
-    pub exact: Option<bool>,
+    pub min_ssim: Option<f64>,
+    /// Disqualify candidates with a lower PSNR in dB (implies --metrics)
     #[clap(long)]
-    pub use_delta_palette: Option<bool>,
+    pub min_psnr: Option<f64>,
+    /// Keep the outputs of all candidates instead of only the best one
     #[clap(long)]
-    pub use_sharp_yuv: Option<bool>,
+    pub keep_all: bool,
+    #[clap(flatten)]
+    pub avif_opts: AvifOptions,
+    #[clap(flatten)]
+    pub webp_opts: WebpOptions,
+}
 
-    //anim encoder options
-    #[clap(long)]
-    pub minimize_size: Option<bool>,
-    #[clap(long)]
-    pub keyframe_distance: Option<webp::KeyframeDistance>,
+/// The options are only parsed, the effective values are read from the matches
+#[derive(Args)]
+#[allow(dead_code)]
+pub struct ConfigCommand {
+    #[clap(flatten)]
+    pub avif_opts: AvifOptions,
+    #[clap(flatten)]
+    pub webp_opts: WebpOptions,
+}
+
+#[derive(Args)]
+pub struct ServeCommand {
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+    /// Number of conversions that run concurrently
+    #[clap(short, long, default_value_t = 1)]
+    pub jobs: usize,
+    /// Number of uploads that wait for a free job, further uploads are rejected with 503
+    #[clap(long, default_value_t = 16)]
+    pub queue: usize,
+    /// Maximum size of an upload in bytes
+    #[clap(long, default_value_t = 64 * 1024 * 1024)]
+    pub max_upload: u64,
+    /// Seconds a conversion may take before it's cancelled
+    #[clap(long, default_value_t = 120.0, parse(try_from_str = parse_seconds))]
+    pub timeout: f64,
+    #[clap(flatten)]
+    pub limits: Limits,
+    /// Defaults for AVIF conversions, `quality` in the request overrides the quantizers
+    #[clap(flatten)]
+    pub avif_opts: AvifOptions,
+    /// Defaults for WebP conversions, `quality` in the request overrides the quality
+    #[clap(flatten)]
+    pub webp_opts: WebpOptions,
+}
+
+impl AutoCommand {
+    pub fn metrics_enabled(&self) -> bool {
+        self.metrics || self.min_ssim.is_some() || self.min_psnr.is_some()
+    }
+}
+
+#[derive(Args)]
+pub struct BatchCommand {
+    #[clap(subcommand)]
+    pub format: BatchFormat,
+}
+
+#[derive(Subcommand)]
+pub enum BatchFormat {
+    Avif(BatchAvifCommand),
+    Webp(BatchWebpCommand),
+}
+
+#[derive(Args)]
+pub struct BatchOptions {
+    /// Input files, directories (searched recursively) or glob patterns
+    #[clap(required = true)]
+    pub inputs: Vec<String>,
+    /// Directory for the outputs, the structure of input directories is mirrored
+    #[clap(short, long, default_value = "out")]
+    pub output_dir: PathBuf,
+    /// Number of files converted concurrently
+    #[clap(short, long, default_value_t = 1)]
+    pub jobs: usize,
+    #[clap(flatten)]
+    pub limits: Limits,
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+    /// How progress is shown while converting
+    #[clap(long, arg_enum, default_value_t = ProgressMode::Bar)]
+    pub progress: ProgressMode,
+    /// Replace existing output files (the default)
+    #[clap(long, conflicts_with = "no-clobber")]
+    pub overwrite: bool,
+    /// Skip inputs whose output file already exists
     #[clap(long)]
-    pub allow_mixed: Option<bool>,
+    pub no_clobber: bool,
+}
+
+#[derive(Args)]
+pub struct WatchCommand {
+    #[clap(subcommand)]
+    pub format: WatchFormat,
+}
+
+#[derive(Subcommand)]
+pub enum WatchFormat {
+    Avif(WatchAvifCommand),
+    Webp(WatchWebpCommand),
+}
+
+#[derive(Args)]
+pub struct WatchOptions {
+    /// Directory to watch, including its subdirectories
+    pub dir: PathBuf,
+    /// Directory for the outputs, the structure of the watched directory is mirrored
+    #[clap(short, long, default_value = "out")]
+    pub output_dir: PathBuf,
+    /// Seconds between two scans of the directory
+    #[clap(long, default_value_t = 2.0, parse(try_from_str = parse_seconds))]
+    pub interval: f64,
+    /// File the result of every conversion is appended to as a JSON line
+    /// (default: `watch.log` in the output directory)
     #[clap(long)]
-    pub background_color: Option<BackgroundColor>,
+    pub log: Option<PathBuf>,
+    /// Replace outputs that already exist, outputs of changed files are always replaced
     #[clap(long)]
-    pub loop_count: Option<i32>,
+    pub overwrite: bool,
+    #[clap(flatten)]
+    pub limits: Limits,
+}
+
+#[derive(Args)]
+pub struct WatchAvifCommand {
+    #[clap(flatten)]
+    pub watch: WatchOptions,
+    #[clap(flatten)]
+    pub opts: AvifOptions,
 }
 
-pub struct BackgroundColor(pub webp::Argb);
+#[derive(Args)]
+pub struct WatchWebpCommand {
+    #[clap(flatten)]
+    pub watch: WatchOptions,
+    #[clap(flatten)]
+    pub opts: WebpOptions,
+}
 
-impl FromStr for BackgroundColor {
-    type Err = Cow<'static, str>;
+#[derive(Args)]
+pub struct SpriteCommand {
+    #[clap(subcommand)]
+    pub format: SpriteFormat,
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(hex) = s.strip_prefix('#') {
-            match hex.len() {
-                6 => {
-                    let rgb = <[u8; 3]>::from_hex(hex).map_err(|e| e.to_string())?;
-                    Ok(Self([255, rgb[0], rgb[1], rgb[2]]))
-                }
-                8 => {
-                    let argb = <[u8; 4]>::from_hex(hex).map_err(|e| e.to_string())?;
-                    Ok(Self(argb))
-                }
-                _ => Err("Expected #abcdef, or #abcdef01".into()),
-            }
-        } else {
-            Err("Expected #abcdef, or #abcdef01".into())
-        }
+#[derive(Subcommand)]
+pub enum SpriteFormat {
+    Png(SpriteSheetCommand),
+    Webp(SpriteSheetCommand),
+}
+
+#[derive(Args)]
+pub struct SpriteSheetCommand {
+    #[clap(flatten)]
+    pub io: IoOptions,
+    /// Where the atlas is written (default: the output with a `.json` extension)
+    #[clap(long)]
+    pub atlas: Option<PathBuf>,
+    #[clap(flatten)]
+    pub opts: SpriteSheetOptions,
+}
+
+/// Without a flag, existing outputs are replaced like they always were
+fn overwrite_policy(overwrite: bool, no_clobber: bool) -> OverwritePolicy {
+    match (overwrite, no_clobber) {
+        (false, true) => OverwritePolicy::Skip,
+        _ => OverwritePolicy::Overwrite,
     }
 }
-#[macro_export]
-macro_rules! apply_options {
-    ($self:ident, $opts:ident; $($opt:ident,)*) => {
-            $(if let Some(v) = &$opts.$opt {
-                concat_idents::concat_idents!(fn_name = set_, $opt {
-                    $self.fn_name(*v);
-                });
-            })*
-    };
+
+#[derive(Args)]
+pub struct BatchAvifCommand {
+    #[clap(flatten)]
+    pub batch: BatchOptions,
+    #[clap(flatten)]
+    pub opts: AvifOptions,
+}
+
+#[derive(Args)]
+pub struct BatchWebpCommand {
+    #[clap(flatten)]
+    pub batch: BatchOptions,
+    #[clap(flatten)]
+    pub opts: WebpOptions,
+}
+
+/// A finite, non-negative number of seconds
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(seconds),
+        Ok(_) => Err("must be a finite number of seconds that isn't negative".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
 }
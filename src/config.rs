@@ -0,0 +1,326 @@
+//! Named profiles of encoder options in a TOML config file.
+//!
+//! ```toml
+//! [profile.7tv-animated]
+//! quality = 80
+//! method = 6
+//! speed = 6
+//! ```
+//!
+//! Keys are the long flags of [`AvifOptions`] and [`WebpOptions`]. The values of the selected
+//! profile are passed as if they were on the command line, unless the flag is there already.
+use crate::cli::Cli;
+use clap::{ArgMatches, Args, Command, CommandFactory, FromArgMatches};
+use emote_tool::options::{AvifOptions, WebpOptions};
+use std::{
+    env,
+    ffi::OsString,
+    fs, io, iter,
+    path::{Path, PathBuf},
+};
+
+/// Looked up in the current directory if there's no `--config`
+const LOCAL_CONFIG: &str = "emote-tool.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Couldn't read the config file {}: {1}", .0.display())]
+    Read(PathBuf, io::Error),
+    #[error("Couldn't parse the config file {}: {1}", .0.display())]
+    Parse(PathBuf, toml::de::Error),
+    #[error("No config file found, pass one with --config")]
+    NoConfigFile,
+    #[error("The config file has no [profile.{0}]")]
+    UnknownProfile(String),
+    #[error("[profile.{0}] sets {1:?}, which isn't an AVIF or WebP option")]
+    UnknownOption(String, String),
+    #[error("[profile.{0}] has an invalid value for {1:?}")]
+    InvalidValue(String, String),
+}
+
+/// The parsed command line with the profile applied
+pub struct ParsedCli {
+    pub cli: Cli,
+    matches: ArgMatches,
+    /// Options that were set by the profile and not on the command line
+    from_profile: Vec<String>,
+}
+
+/// Parse the command line, with the options of `--profile` where there's no flag.
+///
+/// Like [`clap::Parser::parse`], this exits on invalid arguments.
+pub fn parse_cli() -> Result<ParsedCli, ConfigError> {
+    let args = env::args_os().collect::<Vec<_>>();
+    let matches = Cli::command().get_matches_from(&args);
+    let (args, from_profile) = match matches.value_of("profile") {
+        Some(name) => {
+            let profile = load_profile(matches.value_of_os("config").map(Path::new), name)?;
+            apply_profile(args, &matches, name, &profile)?
+        }
+        None => (args, Vec::new()),
+    };
+    let matches = Cli::command().get_matches_from(&args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    Ok(ParsedCli {
+        cli,
+        matches,
+        from_profile,
+    })
+}
+
+/// Print the encoder options of the command line as a profile, with where every value came from.
+pub fn dump(parsed: &ParsedCli) {
+    let command = Cli::command();
+    let (command, matches) = innermost(&command, &parsed.matches);
+    let options = option_names();
+    println!(
+        "[profile.{}]",
+        parsed.matches.value_of("profile").unwrap_or("default")
+    );
+    for arg in command.get_arguments() {
+        let long = match arg.get_long() {
+            Some(long) if options.iter().any(|option| option == long) => long,
+            _ => continue,
+        };
+        let value = if arg.is_takes_value_set() {
+            match matches.value_of(arg.get_id()) {
+                Some(value) => toml_value(value),
+                // an unset optional value, the encoder's default is used
+                None => continue,
+            }
+        } else {
+            toml::Value::Boolean(matches.is_present(arg.get_id()))
+        };
+        let source = if parsed.from_profile.iter().any(|option| option == long) {
+            "profile"
+        } else if matches.occurrences_of(arg.get_id()) > 0 {
+            "command line"
+        } else {
+            "default"
+        };
+        println!("{} = {} # {}", long, value, source);
+    }
+}
+
+/// The table of `[profile.<name>]` from the config file
+fn load_profile(config: Option<&Path>, name: &str) -> Result<toml::value::Table, ConfigError> {
+    let path = match config {
+        Some(path) => path.to_path_buf(),
+        None => default_config().ok_or(ConfigError::NoConfigFile)?,
+    };
+    let content = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+    let config = content
+        .parse::<toml::Value>()
+        .map_err(|e| ConfigError::Parse(path, e))?;
+    config
+        .get("profile")
+        .and_then(|profiles| profiles.get(name))
+        .and_then(toml::Value::as_table)
+        .cloned()
+        .ok_or_else(|| ConfigError::UnknownProfile(name.to_string()))
+}
+
+/// `emote-tool.toml` in the current directory, or `emote-tool/config.toml` in the user's config directory
+fn default_config() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from));
+    iter::once(PathBuf::from(LOCAL_CONFIG))
+        .chain(config_dir.map(|dir| dir.join("emote-tool").join("config.toml")))
+        .find(|path| path.is_file())
+}
+
+/// Add the options of the profile that aren't on the command line to `args`.
+///
+/// Returns the new arguments and the options that were added.
+fn apply_profile(
+    mut args: Vec<OsString>,
+    matches: &ArgMatches,
+    name: &str,
+    profile: &toml::value::Table,
+) -> Result<(Vec<OsString>, Vec<String>), ConfigError> {
+    let command = Cli::command();
+    let (command, matches) = innermost(&command, matches);
+    let options = option_names();
+
+    let mut profile_args = Vec::new();
+    let mut from_profile = Vec::new();
+    for (key, value) in profile {
+        let long = key.replace('_', "-");
+        if !options.contains(&long) {
+            return Err(ConfigError::UnknownOption(name.to_string(), key.clone()));
+        }
+        let invalid = || ConfigError::InvalidValue(name.to_string(), key.clone());
+        // profiles can set options of both formats, the other format's are ignored
+        let arg = match command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+        {
+            Some(arg) if matches.occurrences_of(arg.get_id()) == 0 => arg,
+            _ => continue,
+        };
+        if arg.is_takes_value_set() {
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => return Err(invalid()),
+            };
+            profile_args.push(OsString::from(format!("--{}={}", long, value)));
+        } else if value.as_bool().ok_or_else(invalid)? {
+            profile_args.push(OsString::from(format!("--{}", long)));
+        }
+        from_profile.push(long);
+    }
+
+    // before `--`, everything after it is positional
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    args.splice(end..end, profile_args);
+    Ok((args, from_profile))
+}
+
+/// The subcommand that was selected (e.g. `batch avif`) and its matches
+fn innermost<'a, 'help>(
+    mut command: &'a Command<'help>,
+    mut matches: &'a ArgMatches,
+) -> (&'a Command<'help>, &'a ArgMatches) {
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        match command.find_subcommand(name) {
+            Some(subcommand) => {
+                command = subcommand;
+                matches = sub_matches;
+            }
+            None => break,
+        }
+    }
+    (command, matches)
+}
+
+/// Long flags of all encoder options, the keys a profile can set
+fn option_names() -> Vec<String> {
+    let options = WebpOptions::augment_args(AvifOptions::augment_args(Command::new("options")));
+    options
+        .get_arguments()
+        .filter_map(|arg| arg.get_long())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Numbers and booleans aren't quoted
+fn toml_value(value: &str) -> toml::Value {
+    if let Ok(value) = value.parse::<i64>() {
+        toml::Value::Integer(value)
+    } else if let Ok(value) = value.parse::<f64>() {
+        toml::Value::Float(value)
+    } else if let Ok(value) = value.parse::<bool>() {
+        toml::Value::Boolean(value)
+    } else {
+        toml::Value::String(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The arguments and added options after applying `profile` to the command line `args`
+    fn apply(args: &[&str], profile: &str) -> Result<(Vec<String>, Vec<String>), ConfigError> {
+        let args = args
+            .iter()
+            .map(|&arg| OsString::from(arg))
+            .collect::<Vec<_>>();
+        let matches = Cli::command().try_get_matches_from(&args).unwrap();
+        let profile = profile.parse::<toml::Value>().unwrap();
+        let (args, from_profile) =
+            apply_profile(args, &matches, "test", profile.as_table().unwrap())?;
+        let args = args
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect();
+        Ok((args, from_profile))
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let (args, from_profile) = apply(
+            &["emote-tool", "webp", "in.gif", "--method", "4"],
+            "method = 6\nquality = 80",
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "emote-tool",
+                "webp",
+                "in.gif",
+                "--method",
+                "4",
+                "--quality=80"
+            ]
+        );
+        assert_eq!(from_profile, ["quality"]);
+    }
+
+    #[test]
+    fn options_of_the_other_format_are_ignored() {
+        let (args, from_profile) =
+            apply(&["emote-tool", "avif", "in.gif"], "quality = 80\nspeed = 6").unwrap();
+        assert_eq!(args, ["emote-tool", "avif", "in.gif", "--speed=6"]);
+        assert_eq!(from_profile, ["speed"]);
+    }
+
+    #[test]
+    fn flags_and_underscores() {
+        let (args, _) = apply(
+            &["emote-tool", "avif", "in.gif"],
+            "premultiply_alpha = true",
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            ["emote-tool", "avif", "in.gif", "--premultiply-alpha"]
+        );
+        let (args, _) = apply(
+            &["emote-tool", "avif", "in.gif"],
+            "premultiply-alpha = false",
+        )
+        .unwrap();
+        assert_eq!(args, ["emote-tool", "avif", "in.gif"]);
+    }
+
+    #[test]
+    fn options_go_before_positional_separator() {
+        let (args, _) = apply(&["emote-tool", "webp", "--", "-in.gif"], "lossless = true").unwrap();
+        assert_eq!(
+            args,
+            ["emote-tool", "webp", "--lossless=true", "--", "-in.gif"]
+        );
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let args = ["emote-tool", "webp", "in.gif"];
+        assert!(matches!(
+            apply(&args, "colour = 1"),
+            Err(ConfigError::UnknownOption(_, key)) if key == "colour"
+        ));
+        // options other than the encoder's can't be set
+        assert!(matches!(
+            apply(&args, "overwrite = true"),
+            Err(ConfigError::UnknownOption(..))
+        ));
+        assert!(matches!(
+            apply(&args, "quality = [80]"),
+            Err(ConfigError::InvalidValue(_, key)) if key == "quality"
+        ));
+        assert!(matches!(
+            apply(&["emote-tool", "avif", "in.gif"], "premultiply-alpha = 1"),
+            Err(ConfigError::InvalidValue(..))
+        ));
+    }
+}
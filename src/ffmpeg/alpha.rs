@@ -0,0 +1,179 @@
+//! Alpha-aware preprocessing of frames with straight (not premultiplied) alpha.
+use ffmpeg_next::{color, format::Pixel, frame};
+
+/// Fill the color of fully transparent pixels from their visible neighbours.
+///
+/// The color under transparent pixels is often garbage (usually black), which bleeds into
+/// visible edges once the chroma planes are subsampled or the image is compressed.
+/// The color spreads outwards one pixel per pass, so every transparent pixel gets the average
+/// of the closest visible ones. Only [`Pixel::YUVA444P`] is supported, other formats are ignored.
+pub fn bleed(frame: &mut frame::Video) {
+    if frame.format() != Pixel::YUVA444P {
+        return;
+    }
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let alpha_stride = frame.stride(3);
+    let mut known = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &frame.data(3)[y * alpha_stride..][..width];
+        known.extend(row.iter().map(|&alpha| alpha > 0));
+    }
+    if known.iter().all(|&known| known) || !known.iter().any(|&known| known) {
+        return;
+    }
+
+    let mut frontier = (0..width * height)
+        .filter(|&i| !known[i] && neighbours(i, width, height).any(|n| known[n]))
+        .collect::<Vec<_>>();
+    let mut filled = Vec::with_capacity(frontier.len());
+    while !frontier.is_empty() {
+        // compute the whole pass first, so the pixels of a pass don't depend on each other
+        filled.clear();
+        for &i in &frontier {
+            let mut sums = [0u32; 3];
+            let mut count = 0;
+            for n in neighbours(i, width, height).filter(|&n| known[n]) {
+                for (plane, sum) in sums.iter_mut().enumerate() {
+                    *sum += sample(frame, plane, n, width) as u32;
+                }
+                count += 1;
+            }
+            filled.push((i, sums.map(|sum| ((sum + count / 2) / count) as u8)));
+        }
+        for &(i, color) in &filled {
+            for (plane, value) in color.into_iter().enumerate() {
+                let stride = frame.stride(plane);
+                frame.data_mut(plane)[i / width * stride + i % width] = value;
+            }
+            known[i] = true;
+        }
+        frontier = filled
+            .iter()
+            .flat_map(|&(i, _)| neighbours(i, width, height))
+            .filter(|&n| !known[n])
+            .collect();
+        frontier.sort_unstable();
+        frontier.dedup();
+    }
+}
+
+/// Multiply the color of a [`Pixel::YUVA444P`] frame by its alpha.
+///
+/// The luma plane is scaled towards black, which is 16 unless the frame is full range
+/// (limited is the default of YUV), the chroma planes are scaled around 128.
+/// Other formats are ignored.
+pub fn premultiply(frame: &mut frame::Video) {
+    if frame.format() != Pixel::YUVA444P {
+        return;
+    }
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let alpha_stride = frame.stride(3);
+    let alpha = (0..height)
+        .flat_map(|y| frame.data(3)[y * alpha_stride..][..width].to_vec())
+        .collect::<Vec<_>>();
+    let black = match frame.color_range() {
+        color::Range::JPEG => 0,
+        _ => 16,
+    };
+    for plane in 0..3 {
+        let center = if plane == 0 { black } else { 128 };
+        let stride = frame.stride(plane);
+        let data = frame.data_mut(plane);
+        for y in 0..height {
+            for x in 0..width {
+                let value = &mut data[y * stride + x];
+                let scaled = (*value as i32 - center) * alpha[y * width + x] as i32;
+                *value = (center + (scaled + scaled.signum() * 127) / 255) as u8;
+            }
+        }
+    }
+}
+
+fn sample(frame: &frame::Video, plane: usize, i: usize, width: usize) -> u8 {
+    frame.data(plane)[i / width * frame.stride(plane) + i % width]
+}
+
+/// Indices of the (up to eight) pixels around `i`
+fn neighbours(i: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % width) as isize, (i / width) as isize);
+    (-1..=1)
+        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(move |&(nx, ny)| {
+            (nx, ny) != (x, y)
+                && (0..width as isize).contains(&nx)
+                && (0..height as isize).contains(&ny)
+        })
+        .map(move |(nx, ny)| ny as usize * width + nx as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single row of pixels, `planes` are Y, U, V and alpha
+    fn row(planes: [&[u8]; 4]) -> frame::Video {
+        let mut frame = frame::Video::new(Pixel::YUVA444P, planes[0].len() as u32, 1);
+        for (plane, values) in planes.into_iter().enumerate() {
+            frame.data_mut(plane)[..values.len()].copy_from_slice(values);
+        }
+        frame
+    }
+
+    fn plane(frame: &frame::Video, plane: usize) -> Vec<u8> {
+        frame.data(plane)[..frame.width() as usize].to_vec()
+    }
+
+    #[test]
+    fn bleed_into_transparent_pixels() {
+        let mut frame = row([
+            &[100, 0, 0, 0],
+            &[50, 0, 0, 0],
+            &[200, 0, 0, 0],
+            &[255, 0, 0, 0],
+        ]);
+        bleed(&mut frame);
+        assert_eq!(plane(&frame, 0), [100; 4]);
+        assert_eq!(plane(&frame, 1), [50; 4]);
+        assert_eq!(plane(&frame, 2), [200; 4]);
+        assert_eq!(plane(&frame, 3), [255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bleed_averages_the_closest_pixels() {
+        let mut frame = row([&[100, 0, 201], &[0; 3], &[0; 3], &[255, 0, 1]]);
+        bleed(&mut frame);
+        assert_eq!(plane(&frame, 0), [100, 151, 201]);
+    }
+
+    #[test]
+    fn bleed_without_visible_or_transparent_pixels() {
+        for alpha in [0, 255] {
+            let mut frame = row([&[1, 2], &[3, 4], &[5, 6], &[alpha; 2]]);
+            bleed(&mut frame);
+            assert_eq!(plane(&frame, 0), [1, 2]);
+        }
+        let mut frame = frame::Video::new(Pixel::YUV444P, 2, 1);
+        frame.data_mut(0)[..2].copy_from_slice(&[1, 2]);
+        bleed(&mut frame);
+        assert_eq!(plane(&frame, 0), [1, 2]);
+    }
+
+    #[test]
+    fn premultiply_full_range() {
+        let mut frame = row([&[200, 200, 200], &[228, 28, 228], &[128; 3], &[255, 128, 0]]);
+        frame.set_color_range(color::Range::JPEG);
+        premultiply(&mut frame);
+        assert_eq!(plane(&frame, 0), [200, 100, 0]);
+        assert_eq!(plane(&frame, 1), [228, 78, 128]);
+        assert_eq!(plane(&frame, 2), [128; 3]);
+        assert_eq!(plane(&frame, 3), [255, 128, 0]);
+    }
+
+    #[test]
+    fn premultiply_limited_range() {
+        let mut frame = row([&[216, 216, 216], &[228; 3], &[128; 3], &[255, 128, 0]]);
+        premultiply(&mut frame);
+        assert_eq!(plane(&frame, 0), [216, 116, 16]);
+        assert_eq!(plane(&frame, 1), [228, 178, 128]);
+    }
+}
@@ -11,6 +11,14 @@ pub fn open_decoder(stream: &ffmpeg_next::Stream) -> Result<decoder::Video, ffmp
     }
 }
 
+/// The decoder `open_decoder` uses for `id` and whether it's an overwritten one.
+pub fn select_decoder(id: codec::Id) -> Option<(codec::codec::Codec, bool)> {
+    match overwrite_decoder(id) {
+        Some(codec) => Some((codec, true)),
+        None => decoder::find(id).map(|codec| (codec, false)),
+    }
+}
+
 fn overwrite_decoder(id: codec::Id) -> Option<codec::codec::Codec> {
     match id {
         codec::Id::VP9 => decoder::find_by_name("libvpx-vp9"),
@@ -27,7 +27,7 @@ impl AcceptedFormats {
     }
 }
 
-fn is_alpha_format(format: Pixel) -> bool {
+pub fn is_alpha_format(format: Pixel) -> bool {
     matches!(
         format,
         Pixel::ARGB
@@ -1,7 +1,9 @@
 use ffmpeg_next::{
-    ffi::AV_TIME_BASE,
+    ffi::{av_guess_sample_aspect_ratio, AV_TIME_BASE},
     format::{context, stream},
+    Rational,
 };
+use std::ptr::{self, NonNull};
 
 pub fn extract_frames(stream: &stream::Stream, ctx: &context::Input) -> i64 {
     if stream.frames() > 0 {
@@ -24,3 +26,79 @@ pub fn extract_duration_ms(stream: &stream::Stream, ctx: &context::Input) -> i64
         (ctx.duration() * 1000) / (AV_TIME_BASE as i64)
     }
 }
+
+/// Width and height from the stream's codec parameters
+pub fn extract_dimensions(stream: &stream::Stream) -> Option<(u32, u32)> {
+    unsafe {
+        let par = NonNull::new((*stream.as_ptr()).codecpar)?;
+        Some((par.as_ref().width as u32, par.as_ref().height as u32))
+    }
+}
+
+/// Sample aspect ratio (width of a pixel divided by its height) of the stream,
+/// the container's value is preferred over the codec's. `0/1` if it's unknown.
+pub fn extract_sample_aspect_ratio(stream: &stream::Stream, ctx: &context::Input) -> Rational {
+    unsafe {
+        // ffmpeg only reads both contexts
+        av_guess_sample_aspect_ratio(
+            ctx.as_ptr() as *mut _,
+            stream.as_ptr() as *mut _,
+            ptr::null_mut(),
+        )
+        .into()
+    }
+}
+
+/// Dimensions of a `width`x`height` frame with square pixels.
+///
+/// Like ffmpeg, the width is scaled and the height is kept. Unknown ratios are treated as square.
+pub fn display_dimensions(
+    (width, height): (u32, u32),
+    sample_aspect_ratio: Rational,
+) -> (u32, u32) {
+    let (num, den) = (
+        sample_aspect_ratio.numerator(),
+        sample_aspect_ratio.denominator(),
+    );
+    if num <= 0 || den <= 0 || num == den {
+        return (width, height);
+    }
+    let display_width = (width as u64 * num as u64 + den as u64 / 2) / den as u64;
+    (display_width.max(1) as u32, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_and_unknown_pixels() {
+        for ratio in [(1, 1), (0, 1), (1, 0), (-4, 3), (16, 16)] {
+            let ratio = Rational::new(ratio.0, ratio.1);
+            assert_eq!(display_dimensions((720, 480), ratio), (720, 480));
+        }
+    }
+
+    #[test]
+    fn anamorphic_pixels() {
+        assert_eq!(
+            display_dimensions((1440, 1080), Rational::new(4, 3)),
+            (1920, 1080)
+        );
+        assert_eq!(
+            display_dimensions((720, 480), Rational::new(8, 9)),
+            (640, 480)
+        );
+        // 853.33 and 1.5 are rounded to the closest width
+        assert_eq!(
+            display_dimensions((720, 480), Rational::new(32, 27)),
+            (853, 480)
+        );
+        assert_eq!(display_dimensions((3, 2), Rational::new(1, 2)), (2, 2));
+    }
+
+    #[test]
+    fn never_zero_width() {
+        assert_eq!(display_dimensions((1, 1), Rational::new(1, 3)), (1, 1));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{ffmpeg::FfmpegError, options::SequenceOptions};
+use ffmpeg_next::{
+    ffi::{
+        av_free, av_freep, av_malloc, avformat_alloc_context, avformat_close_input,
+        avformat_find_stream_info, avformat_open_input, avio_alloc_context, avio_context_free,
+        AVIOContext, AVERROR, AVERROR_EOF, AVFMT_FLAG_CUSTOM_IO,
+    },
+    format,
+    format::context,
+    util::error::{EIO, ENOMEM},
+    Dictionary,
+};
+use std::{
+    io,
+    io::Read,
+    ops::{Deref, DerefMut},
+    os::raw::{c_int, c_void},
+    path::Path,
+    ptr, slice,
+};
+
+/// Path that selects stdin as the input (and stdout as the output)
+pub const STDIO_PATH: &str = "-";
+
+/// Frame rate of image sequences if neither a frame rate nor delays are set
+const DEFAULT_SEQUENCE_FPS: &str = "25";
+
+/// Demuxers that [`InputContext::open_restricted`] allows, none of them opens other files or URLs
+/// (unlike e.g. HLS, concat or image sequences)
+const RESTRICTED_FORMATS: &str =
+    "gif,apng,webp_pipe,png_pipe,jpeg_pipe,bmp_pipe,mov,matroska,avi,ivf,mpegts";
+
+/// Size of the buffer ffmpeg reads into (ffmpeg may replace it with a larger one while probing)
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// An opened input, either a file/URL or any reader (e.g. stdin).
+///
+/// Derefs to the ffmpeg context, which is closed before the custom IO is freed.
+pub struct InputContext {
+    ctx: context::Input,
+    // fields are dropped in order, the context still uses the custom IO when it's closed
+    reader_io: Option<ReaderIo>,
+    /// Delays in milliseconds that replace the timestamps of the frames
+    frame_delays: Option<Vec<u32>>,
+}
+
+/// A custom AVIO context reading from a boxed reader
+struct ReaderIo {
+    avio: *mut AVIOContext,
+    reader: *mut Box<dyn Read + Send>,
+}
+
+// The reader is `Send` and the AVIO context is only used by the `InputContext` that owns it
+unsafe impl Send for ReaderIo {}
+
+impl InputContext {
+    /// Open a file or URL, [`STDIO_PATH`] (`-`) reads from stdin.
+    ///
+    /// Image sequence patterns are opened with the default frame rate, see [`Self::open_sequence`].
+    pub fn open<P: AsRef<Path>>(input: &P) -> Result<Self, FfmpegError> {
+        let path = input.as_ref();
+        if path == Path::new(STDIO_PATH) {
+            return Self::from_reader(io::stdin());
+        }
+        if let Some(pattern) = path.to_str().filter(|pattern| is_sequence_pattern(pattern)) {
+            return Self::open_sequence(pattern, &SequenceOptions::default());
+        }
+        Ok(Self {
+            ctx: format::input(input)?,
+            reader_io: None,
+            frame_delays: None,
+        })
+    }
+
+    /// Open a local file from an untrusted source (e.g. an upload).
+    ///
+    /// Only the `file` protocol and the demuxers in [`RESTRICTED_FORMATS`] are allowed, so a
+    /// playlist can't make ffmpeg read other files or URLs. Patterns aren't expanded either.
+    pub fn open_restricted<P: AsRef<Path>>(input: &P) -> Result<Self, FfmpegError> {
+        let mut dictionary = Dictionary::new();
+        dictionary.set("protocol_whitelist", "file");
+        dictionary.set("format_whitelist", RESTRICTED_FORMATS);
+        Ok(Self {
+            ctx: format::input_with_dictionary(input, dictionary)?,
+            reader_io: None,
+            frame_delays: None,
+        })
+    }
+
+    /// Open numbered images (`frame_%04d.png`) or a glob (`frames/*.png`, sorted by name)
+    /// as a single video stream.
+    pub fn open_sequence(pattern: &str, options: &SequenceOptions) -> Result<Self, FfmpegError> {
+        let mut dictionary = Dictionary::new();
+        if is_glob(pattern) {
+            dictionary.set("pattern_type", "glob");
+        }
+        // with delays, the timestamps are synthesized in milliseconds, so that's the time base
+        let framerate = match (options.delays.is_empty(), options.fps) {
+            (false, _) => "1000".to_string(),
+            (true, Some(fps)) => fps.to_string(),
+            (true, None) => DEFAULT_SEQUENCE_FPS.to_string(),
+        };
+        dictionary.set("framerate", &framerate);
+
+        Ok(Self {
+            ctx: format::input_with_dictionary(&pattern, dictionary)?,
+            reader_io: None,
+            frame_delays: (!options.delays.is_empty()).then(|| options.delays.clone()),
+        })
+    }
+
+    /// Read the input from `reader`, the format is probed from the first bytes.
+    ///
+    /// The reader can't seek, so formats that need seeking (e.g. MP4 with the index at the end) fail.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Result<Self, FfmpegError> {
+        let reader_io = ReaderIo::new(Box::new(reader))?;
+        unsafe {
+            let mut ps = avformat_alloc_context();
+            if ps.is_null() {
+                return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+            }
+            (*ps).pb = reader_io.avio;
+            (*ps).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            // the context is freed by ffmpeg if opening fails
+            match avformat_open_input(&mut ps, ptr::null(), ptr::null_mut(), ptr::null_mut()) {
+                0 => (),
+                e => return Err(ffmpeg_next::Error::from(e).into()),
+            }
+            match avformat_find_stream_info(ps, ptr::null_mut()) {
+                r if r >= 0 => Ok(Self {
+                    ctx: context::Input::wrap(ps),
+                    reader_io: Some(reader_io),
+                    frame_delays: None,
+                }),
+                e => {
+                    avformat_close_input(&mut ps);
+                    Err(ffmpeg_next::Error::from(e).into())
+                }
+            }
+        }
+    }
+
+    /// `true` if the input is read through a custom reader (e.g. stdin)
+    pub fn is_custom_io(&self) -> bool {
+        self.reader_io.is_some()
+    }
+
+    /// Delays (in milliseconds) of an image sequence that replace the timestamps of the frames
+    pub fn frame_delays(&self) -> Option<&[u32]> {
+        self.frame_delays.as_deref()
+    }
+}
+
+/// `true` if `input` is a printf-style pattern (`frame_%04d.png`)
+/// or a glob (`frames/*.png`) that isn't the name of an existing file.
+pub fn is_sequence_pattern(input: &str) -> bool {
+    let is_printf_pattern = input.split('%').skip(1).any(|rest| {
+        rest.trim_start_matches(|c: char| c.is_ascii_digit())
+            .starts_with('d')
+    });
+    (is_printf_pattern || is_glob(input)) && !Path::new(input).exists()
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+impl Deref for InputContext {
+    type Target = context::Input;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ctx
+    }
+}
+
+impl DerefMut for InputContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ctx
+    }
+}
+
+impl ReaderIo {
+    fn new(reader: Box<dyn Read + Send>) -> Result<Self, FfmpegError> {
+        unsafe {
+            let buffer = av_malloc(READ_BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+            }
+            let reader = Box::into_raw(Box::new(reader));
+            let avio = avio_alloc_context(
+                buffer,
+                READ_BUFFER_SIZE as c_int,
+                0,
+                reader as *mut c_void,
+                Some(read_packet),
+                None,
+                None,
+            );
+            if avio.is_null() {
+                av_free(buffer as *mut c_void);
+                drop(Box::from_raw(reader));
+                return Err(ffmpeg_next::Error::Other { errno: ENOMEM }.into());
+            }
+            Ok(Self { avio, reader })
+        }
+    }
+}
+
+impl Drop for ReaderIo {
+    fn drop(&mut self) {
+        unsafe {
+            // ffmpeg may have replaced the buffer, so it's freed through the context
+            av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+            avio_context_free(&mut self.avio);
+            drop(Box::from_raw(self.reader));
+        }
+    }
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let reader = &mut *(opaque as *mut Box<dyn Read + Send>);
+    let buf = slice::from_raw_parts_mut(buf, buf_size as usize);
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return AVERROR_EOF,
+            Ok(read) => return read as c_int,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return AVERROR(EIO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printf_patterns() {
+        assert!(is_sequence_pattern("frame_%04d.png"));
+        assert!(is_sequence_pattern("%d.png"));
+        assert!(is_sequence_pattern("100%_%3d.png"));
+        assert!(!is_sequence_pattern("100%.png"));
+        assert!(!is_sequence_pattern("frame_%s.png"));
+        assert!(!is_sequence_pattern("emote.gif"));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(is_sequence_pattern("frames/*.png"));
+        assert!(is_sequence_pattern("frame_?.png"));
+        assert!(is_sequence_pattern("frame_[0-9].png"));
+    }
+
+    #[test]
+    fn existing_file_that_looks_like_a_glob() {
+        let path = std::env::temp_dir().join(format!("emote [{}].gif", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let input = path.to_str().unwrap();
+        let result = is_sequence_pattern(input);
+        std::fs::remove_file(&path).ok();
+        assert!(!result);
+    }
+
+    #[test]
+    fn restricted_input_rejects_playlists() {
+        let path = std::env::temp_dir().join(format!("emote-concat-{}", std::process::id()));
+        std::fs::write(&path, b"ffconcat version 1.0\nfile '/etc/hostname'\n").unwrap();
+        let result = InputContext::open_restricted(&path);
+        std::fs::remove_file(&path).ok();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn existing_file_that_looks_like_a_printf_pattern() {
+        let path = std::env::temp_dir().join(format!("emote_%1d_{}.gif", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let input = path.to_str().unwrap();
+        let result = is_sequence_pattern(input);
+        std::fs::remove_file(&path).ok();
+        assert!(!result);
+    }
+}
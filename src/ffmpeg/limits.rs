@@ -0,0 +1,241 @@
+//! Limits for untrusted inputs, checked before and while decoding.
+use crate::ffmpeg::{
+    frames::{extract_dimensions, extract_duration_ms},
+    input::InputContext,
+    types::TimingData,
+    FfmpegError,
+};
+use ffmpeg_next::frame;
+
+/// Upper bounds for the input, unset limits aren't checked.
+///
+/// With the `cli` feature, these are also used as command line arguments.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct Limits {
+    /// Maximum width of the frames in pixels
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_width: Option<u32>,
+    /// Maximum height of the frames in pixels
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_height: Option<u32>,
+    /// Maximum number of pixels per frame (width * height)
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_pixels: Option<u64>,
+    /// Maximum number of frames
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_frames: Option<u64>,
+    /// Maximum duration in seconds
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_duration: Option<f64>,
+    /// Maximum size of all decoded frames together in bytes
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_decoded_bytes: Option<u64>,
+}
+
+impl Limits {
+    /// Check the metadata of the stream, before anything is decoded.
+    ///
+    /// Frame counts and durations are only checked if the container knows them,
+    /// [`DecodeBudget`] checks the actual frames.
+    pub fn check_stream(&self, ctx: &InputContext, stream_idx: usize) -> Result<(), FfmpegError> {
+        let stream = ctx.stream(stream_idx).unwrap();
+        if let Some((width, height)) = extract_dimensions(&stream) {
+            self.check_dimensions(width, height)?;
+        }
+        if stream.frames() > 0 {
+            self.check_frames(stream.frames() as u64)?;
+        }
+        let duration_ms = extract_duration_ms(&stream, ctx);
+        if duration_ms > 0 {
+            self.check_duration_ms(duration_ms)?;
+        }
+        Ok(())
+    }
+
+    fn check_dimensions(&self, width: u32, height: u32) -> Result<(), FfmpegError> {
+        match (self.max_width, self.max_height, self.max_pixels) {
+            (Some(max), _, _) if width > max => Err(FfmpegError::WidthLimit(width, max)),
+            (_, Some(max), _) if height > max => Err(FfmpegError::HeightLimit(height, max)),
+            (_, _, Some(max)) if width as u64 * height as u64 > max => {
+                Err(FfmpegError::PixelLimit(width as u64 * height as u64, max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_frames(&self, frames: u64) -> Result<(), FfmpegError> {
+        match self.max_frames {
+            Some(max) if frames > max => Err(FfmpegError::FrameLimit(max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_duration_ms(&self, duration_ms: i64) -> Result<(), FfmpegError> {
+        match self.max_duration {
+            Some(max) if duration_ms as f64 > max * 1000.0 => Err(FfmpegError::DurationLimit(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks every decoded frame against the [`Limits`]
+pub struct DecodeBudget {
+    limits: Limits,
+    frames: u64,
+    decoded_bytes: u64,
+    first_timestamp_ms: Option<i64>,
+}
+
+impl DecodeBudget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            frames: 0,
+            decoded_bytes: 0,
+            first_timestamp_ms: None,
+        }
+    }
+
+    /// Account for a decoded `frame`, before it's processed any further.
+    pub fn add(&mut self, frame: &frame::Video, timing: &TimingData) -> Result<(), FfmpegError> {
+        self.limits
+            .check_dimensions(frame.width(), frame.height())?;
+        self.frames += 1;
+        self.limits.check_frames(self.frames)?;
+        let timestamp_ms = timing.ts_in_ms();
+        let first_ms = *self.first_timestamp_ms.get_or_insert(timestamp_ms);
+        self.limits
+            .check_duration_ms(timestamp_ms + timing.duration_in_ms() - first_ms)?;
+
+        // the size of the planes as the decoder returned them, including the padding
+        self.decoded_bytes += (0..frame.planes())
+            .map(|plane| frame.stride(plane) as u64 * frame.plane_height(plane) as u64)
+            .sum::<u64>();
+        match self.limits.max_decoded_bytes {
+            Some(max) if self.decoded_bytes > max => Err(FfmpegError::DecodedBytesLimit(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next::format::Pixel;
+
+    fn frame(width: u32, height: u32) -> frame::Video {
+        frame::Video::new(Pixel::GRAY8, width, height)
+    }
+
+    #[test]
+    fn unset_limits() {
+        let limits = Limits::default();
+        assert!(limits.check_dimensions(u32::MAX, u32::MAX).is_ok());
+        assert!(limits.check_frames(u64::MAX).is_ok());
+        assert!(limits.check_duration_ms(i64::MAX).is_ok());
+    }
+
+    #[test]
+    fn dimensions() {
+        let limits = Limits {
+            max_width: Some(100),
+            max_height: Some(50),
+            max_pixels: Some(2000),
+            ..Limits::default()
+        };
+        assert!(limits.check_dimensions(40, 50).is_ok());
+        assert!(matches!(
+            limits.check_dimensions(101, 1),
+            Err(FfmpegError::WidthLimit(101, 100))
+        ));
+        assert!(matches!(
+            limits.check_dimensions(1, 51),
+            Err(FfmpegError::HeightLimit(51, 50))
+        ));
+        assert!(matches!(
+            limits.check_dimensions(41, 50),
+            Err(FfmpegError::PixelLimit(2050, 2000))
+        ));
+    }
+
+    #[test]
+    fn frames_and_duration() {
+        let limits = Limits {
+            max_frames: Some(10),
+            max_duration: Some(1.5),
+            ..Limits::default()
+        };
+        assert!(limits.check_frames(10).is_ok());
+        assert!(matches!(
+            limits.check_frames(11),
+            Err(FfmpegError::FrameLimit(10))
+        ));
+        assert!(limits.check_duration_ms(1500).is_ok());
+        assert!(matches!(
+            limits.check_duration_ms(1501),
+            Err(FfmpegError::DurationLimit(_))
+        ));
+    }
+
+    #[test]
+    fn budget_counts_frames() {
+        let mut budget = DecodeBudget::new(Limits {
+            max_frames: Some(2),
+            ..Limits::default()
+        });
+        let frame = frame(4, 4);
+        budget.add(&frame, &TimingData::from_ms(0, 10)).unwrap();
+        budget.add(&frame, &TimingData::from_ms(10, 10)).unwrap();
+        assert!(matches!(
+            budget.add(&frame, &TimingData::from_ms(20, 10)),
+            Err(FfmpegError::FrameLimit(2))
+        ));
+    }
+
+    #[test]
+    fn budget_duration_starts_at_first_frame() {
+        let mut budget = DecodeBudget::new(Limits {
+            max_duration: Some(1.0),
+            ..Limits::default()
+        });
+        let frame = frame(4, 4);
+        budget.add(&frame, &TimingData::from_ms(5000, 500)).unwrap();
+        budget.add(&frame, &TimingData::from_ms(5500, 500)).unwrap();
+        assert!(matches!(
+            budget.add(&frame, &TimingData::from_ms(6000, 1)),
+            Err(FfmpegError::DurationLimit(_))
+        ));
+    }
+
+    #[test]
+    fn budget_counts_decoded_bytes() {
+        let frame = frame(4, 4);
+        let frame_bytes = frame.stride(0) as u64 * 4;
+        let mut budget = DecodeBudget::new(Limits {
+            max_decoded_bytes: Some(2 * frame_bytes),
+            ..Limits::default()
+        });
+        budget.add(&frame, &TimingData::from_ms(0, 10)).unwrap();
+        budget.add(&frame, &TimingData::from_ms(10, 10)).unwrap();
+        assert!(matches!(
+            budget.add(&frame, &TimingData::from_ms(20, 10)),
+            Err(FfmpegError::DecodedBytesLimit(_))
+        ));
+    }
+
+    #[test]
+    fn budget_checks_dimensions() {
+        let mut budget = DecodeBudget::new(Limits {
+            max_width: Some(4),
+            ..Limits::default()
+        });
+        budget
+            .add(&frame(4, 8), &TimingData::from_ms(0, 10))
+            .unwrap();
+        assert!(matches!(
+            budget.add(&frame(5, 8), &TimingData::from_ms(10, 10)),
+            Err(FfmpegError::WidthLimit(5, 4))
+        ));
+    }
+}
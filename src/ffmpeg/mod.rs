@@ -1,16 +1,34 @@
+pub mod alpha;
 mod decoders;
 pub mod formats;
 pub mod frames;
+pub mod input;
+pub mod limits;
+pub mod pool;
+pub mod probe;
+pub mod transform;
 pub mod types;
 
-use crate::ffmpeg::{
-    decoders::open_decoder,
-    formats::AcceptedFormats,
-    types::{FrameData, TimingData},
+use crate::{
+    cancel::CancellationToken,
+    ffmpeg::{
+        decoders::open_decoder,
+        formats::{is_alpha_format, AcceptedFormats},
+        input::InputContext,
+        limits::{DecodeBudget, Limits},
+        pool::FramePool,
+        transform::Orientation,
+        types::{FrameData, TimingData},
+    },
+    progress::Progress,
 };
 use crossbeam::channel::Sender;
-use ffmpeg_next::{format, format::Pixel, frame, media::Type, software::scaling};
-use indicatif::ProgressBar;
+use ffmpeg_next::{
+    format::{stream::Disposition, Pixel},
+    frame,
+    media::Type,
+    software::scaling,
+};
 use std::{iter, path::Path};
 
 #[derive(Debug, thiserror::Error)]
@@ -23,53 +41,211 @@ pub enum FfmpegError {
     SendFrame(bool),
     #[error("Stream had no timing information")]
     NoTimingInformation,
+    #[error("Decoding was cancelled")]
+    Cancelled,
+    #[error("The input has no stream #{0}")]
+    NoSuchStream(usize),
+    #[error("Stream #{0} is not a video stream")]
+    NotAVideoStream(usize),
+    #[error("The frames are {0} pixels wide, the limit is {1}")]
+    WidthLimit(u32, u32),
+    #[error("The frames are {0} pixels high, the limit is {1}")]
+    HeightLimit(u32, u32),
+    #[error("The frames have {0} pixels, the limit is {1}")]
+    PixelLimit(u64, u64),
+    #[error("The input has more than {0} frames")]
+    FrameLimit(u64),
+    #[error("The input is longer than {0} seconds")]
+    DurationLimit(f64),
+    #[error("The decoded frames exceed the limit of {0} bytes")]
+    DecodedBytesLimit(u64),
 }
 
+/// Open `input` (`-` reads from stdin) and select the video stream `stream`,
+/// or the best one if it's `None` (see [`select_stream`]).
+///
+/// The stream's metadata is checked against `limits` before anything is decoded.
 pub fn read_initial_stream<P: AsRef<Path>>(
     input: &P,
-) -> Result<(format::context::Input, usize), FfmpegError> {
-    let ctx = format::input(input)?;
-    let istream = ctx
+    stream: Option<usize>,
+    limits: &Limits,
+) -> Result<(InputContext, usize), FfmpegError> {
+    let ctx = InputContext::open(input)?;
+    let stream_idx = select_stream(&ctx, stream)?;
+    limits.check_stream(&ctx, stream_idx)?;
+    Ok((ctx, stream_idx))
+}
+
+/// Index of the video stream to convert.
+///
+/// With `stream`, that stream is checked to be a video stream. Otherwise it's the best
+/// video stream, but attached pictures (cover art, thumbnails) are skipped unless
+/// the input has nothing else.
+pub fn select_stream(ctx: &InputContext, stream: Option<usize>) -> Result<usize, FfmpegError> {
+    if let Some(index) = stream {
+        let istream = ctx.stream(index).ok_or(FfmpegError::NoSuchStream(index))?;
+        return match istream.parameters().medium() {
+            Type::Video => Ok(index),
+            _ => Err(FfmpegError::NotAVideoStream(index)),
+        };
+    }
+    let best = ctx
         .streams()
         .best(Type::Video)
         .ok_or(ffmpeg_next::Error::StreamNotFound)?;
+    if !is_attached_picture(&best) {
+        return Ok(best.index());
+    }
+    let animation = ctx.streams().find(|istream| {
+        istream.parameters().medium() == Type::Video && !is_attached_picture(istream)
+    });
+    Ok(animation.unwrap_or(best).index())
+}
 
-    let stream_idx = istream.index();
-    Ok((ctx, stream_idx))
+/// `true` if the stream is a single picture attached to the container (e.g. cover art)
+pub fn is_attached_picture(stream: &ffmpeg_next::Stream) -> bool {
+    stream.disposition().contains(Disposition::ATTACHED_PIC)
+}
+
+/// Receives decoded frames converted to one of the `accepted_formats`
+pub struct FrameSink {
+    pub accepted_formats: AcceptedFormats,
+    pub frame_tx: Sender<FrameData>,
+    /// The frames are allocated from this pool, the receiver returns them by dropping them
+    pub pool: FramePool,
+}
+
+struct SinkOutput {
+    frame_tx: Sender<FrameData>,
+    pool: FramePool,
+    /// Format of the frames sent to this sink
+    format: Pixel,
+    scaler: Option<scaling::Context>,
+    /// The scaled frame before it's rotated, reused for every frame
+    unrotated: frame::Video,
+}
+
+/// How decoded frames are prepared before they're converted for the sinks
+#[derive(Debug, Copy, Clone)]
+pub struct FrameProcessing {
+    /// Resample the frames to this size if it's set (e.g. square pixels for anamorphic videos)
+    pub display_size: Option<(u32, u32)>,
+    /// Applied after the frames are converted and resampled
+    pub orientation: Orientation,
+    /// Fill the color of transparent pixels from their neighbours, see [`alpha::bleed`]
+    pub alpha_bleed: bool,
+}
+
+impl Default for FrameProcessing {
+    fn default() -> Self {
+        Self {
+            display_size: None,
+            orientation: Orientation::default(),
+            alpha_bleed: false,
+        }
+    }
 }
 
+/// Decode the stream once and send every frame to all `sinks`.
+///
+/// If an encoder stops receiving, the remaining sinks still get the frames.
+/// Decoding stops with an error as soon as a frame exceeds the `limits`.
+/// Once `cancel` is cancelled, no more frames are sent and the senders are dropped.
 pub fn emit_frames(
-    mut input_ctx: format::context::Input,
+    mut input_ctx: InputContext,
     istream_idx: usize,
-    accepted_formats: AcceptedFormats,
-    frame_tx: Sender<FrameData>,
-    progress: ProgressBar,
-) -> Result<(), FfmpegError> {
+    processing: FrameProcessing,
+    limits: Limits,
+    sinks: Vec<FrameSink>,
+    progress: Progress,
+    cancel: &CancellationToken,
+) -> Result<u64, FfmpegError> {
     let istream = input_ctx.stream(istream_idx).unwrap();
     let stream_time_base = istream.time_base();
 
     progress.set_length(istream.frames() as u64);
 
     let mut decoder = open_decoder(&istream)?;
+    let decoded_size = (decoder.width(), decoder.height());
+    let target_size = processing.display_size.unwrap_or(decoded_size);
+    let orientation = processing.orientation;
 
-    let mut scaler = if accepted_formats.passes(decoder.format()) {
-        None
-    } else {
+    // transparent pixels are bled in full resolution, before the chroma planes are subsampled
+    let mut preprocessor = if processing.alpha_bleed && is_alpha_format(decoder.format()) {
         Some(scaling::context::Context::get(
             decoder.format(),
-            decoder.width(),
-            decoder.height(),
-            accepted_formats
-                .select(decoder.format())
-                .ok_or_else(|| FfmpegError::NoPixelFormat(decoder.format()))?,
-            decoder.width(),
-            decoder.height(),
+            decoded_size.0,
+            decoded_size.1,
+            Pixel::YUVA444P,
+            target_size.0,
+            target_size.1,
             scaling::Flags::BILINEAR,
         )?)
+    } else {
+        None
     };
+    let (source_format, source_size) = match &preprocessor {
+        Some(_) => (Pixel::YUVA444P, target_size),
+        None => (decoder.format(), decoded_size),
+    };
+
+    let mut outputs = sinks
+        .into_iter()
+        .map(|sink| {
+            let keeps_format =
+                sink.accepted_formats.passes(source_format) && orientation.supports(source_format);
+            let format = if keeps_format {
+                source_format
+            } else {
+                sink.accepted_formats
+                    .select(source_format)
+                    .ok_or(FfmpegError::NoPixelFormat(source_format))?
+            };
+            let scaler = if keeps_format && source_size == target_size {
+                None
+            } else {
+                Some(scaling::context::Context::get(
+                    source_format,
+                    source_size.0,
+                    source_size.1,
+                    format,
+                    target_size.0,
+                    target_size.1,
+                    scaling::Flags::BILINEAR,
+                )?)
+            };
+            Ok(SinkOutput {
+                frame_tx: sink.frame_tx,
+                pool: sink.pool,
+                format,
+                scaler,
+                unrotated: frame::Video::empty(),
+            })
+        })
+        .collect::<Result<Vec<_>, FfmpegError>>()?;
+    if outputs.is_empty() {
+        return Ok(0);
+    }
 
+    // image sequences with delays don't have meaningful timestamps
+    let frame_delays = input_ctx.frame_delays().map(<[u32]>::to_vec);
+    let mut sequence_ms = 0;
+
+    let mut frames_sent = 0;
+    let mut budget = DecodeBudget::new(limits);
     let mut decoded = frame::Video::empty();
+    let mut preprocessed = frame::Video::empty();
+    // rotated frames are written to a frame of the sink's pool
+    let rotate = |frame: &frame::Video, pool: &FramePool| {
+        let (width, height) = orientation.apply_dimensions((frame.width(), frame.height()));
+        let mut rotated = pool.get(frame.format(), width, height);
+        orientation.apply(frame, &mut rotated);
+        pool.wrap(rotated)
+    };
     for pack in input_ctx.packets().map(Some).chain(iter::once(None)) {
+        if cancel.is_cancelled() {
+            return Err(FfmpegError::Cancelled);
+        }
         match pack {
             Some((stream, packet)) if stream.index() == istream_idx => {
                 decoder.send_packet(&packet)?;
@@ -79,23 +255,76 @@ pub fn emit_frames(
         };
 
         while decoder.receive_frame(&mut decoded).is_ok() {
-            let timing = TimingData::try_new(&decoded, stream_time_base)
-                .ok_or(FfmpegError::NoTimingInformation)?;
-            let frame = match &mut scaler {
-                Some(scaler) => {
-                    let mut frame = frame::Video::empty();
-                    scaler.run(&decoded, &mut frame)?;
-                    frame
+            let timing = match &frame_delays {
+                Some(delays) => {
+                    let delay = frame_delay(delays, frames_sent as usize);
+                    sequence_ms += delay;
+                    TimingData::from_ms(sequence_ms - delay, delay)
                 }
-                None => std::mem::replace(&mut decoded, frame::Video::empty()),
+                None => TimingData::try_new(&decoded, stream_time_base)
+                    .ok_or(FfmpegError::NoTimingInformation)?,
             };
-            frame_tx
-                .send((frame, timing))
-                .map_err(|_| FfmpegError::SendFrame(frame_tx.is_full()))?;
+            budget.add(&decoded, &timing)?;
+            if let Some(preprocessor) = &mut preprocessor {
+                preprocessor.run(&decoded, &mut preprocessed)?;
+                alpha::bleed(&mut preprocessed);
+            }
+            let last = outputs.len() - 1;
+            let mut closed = Vec::new();
+            for (i, output) in outputs.iter_mut().enumerate() {
+                let source = match preprocessor {
+                    Some(_) => &preprocessed,
+                    None => &decoded,
+                };
+                let frame = match &mut output.scaler {
+                    Some(scaler) if orientation.is_identity() => {
+                        let mut frame =
+                            output.pool.get(output.format, target_size.0, target_size.1);
+                        scaler.run(source, &mut frame)?;
+                        output.pool.wrap(frame)
+                    }
+                    Some(scaler) => {
+                        scaler.run(source, &mut output.unrotated)?;
+                        rotate(&output.unrotated, &output.pool)
+                    }
+                    None if !orientation.is_identity() => rotate(source, &output.pool),
+                    // the decoder's buffers are handed over, they can't be recycled
+                    None if i == last && preprocessor.is_none() => {
+                        std::mem::replace(&mut decoded, frame::Video::empty()).into()
+                    }
+                    None => {
+                        let mut frame =
+                            output
+                                .pool
+                                .get(output.format, source.width(), source.height());
+                        frame.clone_from(source);
+                        output.pool.wrap(frame)
+                    }
+                };
+                if output.frame_tx.send((frame, timing)).is_err() {
+                    closed.push(i);
+                }
+            }
+            if closed.len() == outputs.len() {
+                return Err(FfmpegError::SendFrame(outputs[0].frame_tx.is_full()));
+            }
+            for i in closed.into_iter().rev() {
+                outputs.remove(i);
+            }
+            frames_sent += 1;
             progress.inc(1);
         }
     }
 
-    progress.finish_and_clear();
-    Ok(())
+    progress.finish();
+    Ok(frames_sent)
+}
+
+/// Delay of the frame at `index` in milliseconds, frames after the end of `delays` use the last one
+fn frame_delay(delays: &[u32], index: usize) -> i64 {
+    delays
+        .get(index)
+        .or_else(|| delays.last())
+        .copied()
+        .unwrap_or_default() as i64
 }
@@ -0,0 +1,109 @@
+//! Recycling frame buffers between the ffmpeg thread and the encoders.
+use crossbeam::channel::{self, Receiver, Sender};
+use ffmpeg_next::{format::Pixel, frame};
+use std::ops::{Deref, DerefMut};
+
+/// Frames that were allocated for one sink and returned once the encoder is done with them.
+///
+/// Cloning the pool shares the recycled frames.
+#[derive(Clone)]
+pub struct FramePool {
+    recycled_tx: Sender<frame::Video>,
+    recycled_rx: Receiver<frame::Video>,
+}
+
+/// A frame that's returned to its pool when it's dropped (unless it doesn't belong to one).
+///
+/// Derefs to the frame.
+pub struct PooledFrame {
+    // only `None` while dropping
+    frame: Option<frame::Video>,
+    pool: Option<Sender<frame::Video>>,
+}
+
+impl FramePool {
+    /// A pool that keeps up to `capacity` frames, any further returned frames are freed.
+    pub fn new(capacity: usize) -> Self {
+        let (recycled_tx, recycled_rx) = channel::bounded(capacity);
+        Self {
+            recycled_tx,
+            recycled_rx,
+        }
+    }
+
+    /// A recycled frame with this format and size, or a newly allocated one.
+    ///
+    /// The content of recycled frames is whatever was written to them before.
+    pub fn get(&self, format: Pixel, width: u32, height: u32) -> frame::Video {
+        // frames with a different size (e.g. rotated ones) go back to the end of the queue
+        for _ in 0..self.recycled_rx.len() {
+            match self.recycled_rx.try_recv() {
+                Ok(frame)
+                    if frame.format() == format
+                        && frame.width() == width
+                        && frame.height() == height =>
+                {
+                    return frame;
+                }
+                Ok(frame) => {
+                    self.recycled_tx.try_send(frame).ok();
+                }
+                Err(_) => break,
+            }
+        }
+        frame::Video::new(format, width, height)
+    }
+
+    /// Return `frame` to this pool once it's dropped.
+    ///
+    /// The frame must own its buffers, frames that are still referenced by a decoder
+    /// can't be reused.
+    pub fn wrap(&self, frame: frame::Video) -> PooledFrame {
+        PooledFrame {
+            frame: Some(frame),
+            pool: Some(self.recycled_tx.clone()),
+        }
+    }
+}
+
+impl PooledFrame {
+    /// Take the frame out, it won't be returned to the pool.
+    pub fn into_inner(mut self) -> frame::Video {
+        self.pool = None;
+        self.frame.take().unwrap()
+    }
+}
+
+/// A frame that doesn't belong to a pool
+impl From<frame::Video> for PooledFrame {
+    fn from(frame: frame::Video) -> Self {
+        Self {
+            frame: Some(frame),
+            pool: None,
+        }
+    }
+}
+
+impl Deref for PooledFrame {
+    type Target = frame::Video;
+
+    fn deref(&self) -> &Self::Target {
+        // only taken in `into_inner` and `drop`
+        self.frame.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledFrame {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.frame.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledFrame {
+    fn drop(&mut self) {
+        if let (Some(frame), Some(pool)) = (self.frame.take(), &self.pool) {
+            // the frame is freed if the pool is full or gone
+            pool.try_send(frame).ok();
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::ffmpeg::{
+    decoders::{open_decoder, select_decoder},
+    formats::is_alpha_format,
+    frames::{
+        display_dimensions, extract_dimensions, extract_duration_ms, extract_frames,
+        extract_sample_aspect_ratio,
+    },
+    input::InputContext,
+    is_attached_picture,
+    limits::Limits,
+    read_initial_stream,
+    transform::Orientation,
+    FfmpegError,
+};
+use ffmpeg_next::{
+    format::{stream::Disposition, Pixel},
+    frame,
+    media::Type,
+    Rational,
+};
+use std::{
+    fmt::{Display, Formatter},
+    iter,
+    path::Path,
+};
+
+#[derive(Debug, serde::Serialize)]
+pub struct ProbeInfo {
+    pub container: String,
+    pub stream_index: usize,
+    pub codec: String,
+    pub decoder: Option<String>,
+    /// `true` if the decoder isn't ffmpeg's default for the codec (e.g. libvpx for VP9)
+    pub decoder_overwritten: bool,
+    pub width: u32,
+    pub height: u32,
+    /// Width of a pixel divided by its height, `0/1` if unknown
+    pub sample_aspect_ratio: Fraction,
+    /// Dimensions with square pixels, frames are resampled to these if they differ
+    pub display_width: u32,
+    pub display_height: u32,
+    /// Rotation from the display matrix, the frames are rotated unless that's disabled
+    pub orientation: Orientation,
+    pub pixel_format: String,
+    pub has_alpha: bool,
+    pub frames: i64,
+    pub frame_rate: Fraction,
+    pub duration_ms: i64,
+    pub time_base: Fraction,
+    /// All streams of the input, not only the selected one
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StreamInfo {
+    pub index: usize,
+    /// `video`, `audio`, `subtitle`, ...
+    pub kind: String,
+    pub codec: String,
+    /// Only set for video streams
+    pub dimensions: Option<(u32, u32)>,
+    pub frames: i64,
+    /// A single picture attached to the container (cover art, thumbnail)
+    pub attached_picture: bool,
+    pub default: bool,
+}
+
+#[derive(Debug, Copy, Clone, serde::Serialize)]
+pub struct Fraction {
+    pub num: i32,
+    pub den: i32,
+}
+
+/// Read the stream information of `input` and decode the first frame
+/// to get the pixel format the decoder actually produces.
+///
+/// `stream` selects the video stream like for a conversion, the best one if it's `None`.
+pub fn probe<P: AsRef<Path>>(input: &P, stream: Option<usize>) -> Result<ProbeInfo, FfmpegError> {
+    let (mut ctx, stream_idx) = read_initial_stream(input, stream, &Limits::default())?;
+    let streams = list_streams(&ctx);
+    // stream_idx is always valid
+    let stream = ctx.stream(stream_idx).unwrap();
+    let codec_id = stream.parameters().id();
+    let (decoder, decoder_overwritten) = match select_decoder(codec_id) {
+        Some((codec, overwritten)) => (Some(codec.name().to_string()), overwritten),
+        None => (None, false),
+    };
+    let mut decoder_ctx = open_decoder(&stream)?;
+    let frames = extract_frames(&stream, &ctx);
+    let frame_rate = stream.avg_frame_rate().into();
+    let duration_ms = extract_duration_ms(&stream, &ctx);
+    let time_base = stream.time_base().into();
+    let orientation = Orientation::from_stream(&stream);
+    let sample_aspect_ratio = extract_sample_aspect_ratio(&stream, &ctx);
+
+    let mut decoded = frame::Video::empty();
+    for pack in ctx.packets().map(Some).chain(iter::once(None)) {
+        match pack {
+            Some((stream, packet)) if stream.index() == stream_idx => {
+                decoder_ctx.send_packet(&packet)?;
+            }
+            Some(_) => continue,
+            None => decoder_ctx.send_eof()?,
+        };
+        if decoder_ctx.receive_frame(&mut decoded).is_ok() {
+            break;
+        }
+    }
+    let (display_width, display_height) = display_dimensions(
+        (decoder_ctx.width(), decoder_ctx.height()),
+        sample_aspect_ratio,
+    );
+    let pixel_format = match decoded.format() {
+        Pixel::None => decoder_ctx.format(),
+        format => format,
+    };
+
+    Ok(ProbeInfo {
+        container: ctx.format().name().to_string(),
+        stream_index: stream_idx,
+        codec: codec_id.name().to_string(),
+        decoder,
+        decoder_overwritten,
+        width: decoder_ctx.width(),
+        height: decoder_ctx.height(),
+        sample_aspect_ratio: sample_aspect_ratio.into(),
+        display_width,
+        display_height,
+        orientation,
+        pixel_format: pixel_format
+            .descriptor()
+            .map_or_else(|| format!("{:?}", pixel_format), |d| d.name().to_string()),
+        has_alpha: is_alpha_format(pixel_format),
+        frames,
+        frame_rate,
+        duration_ms,
+        time_base,
+        streams,
+    })
+}
+
+/// Describe every stream of `ctx`
+pub fn list_streams(ctx: &InputContext) -> Vec<StreamInfo> {
+    ctx.streams()
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let kind = parameters.medium();
+            StreamInfo {
+                index: stream.index(),
+                kind: format!("{:?}", kind).to_lowercase(),
+                codec: parameters.id().name().to_string(),
+                dimensions: match kind {
+                    Type::Video => extract_dimensions(&stream),
+                    _ => None,
+                },
+                frames: extract_frames(&stream, ctx),
+                attached_picture: is_attached_picture(&stream),
+                default: stream.disposition().contains(Disposition::DEFAULT),
+            }
+        })
+        .collect()
+}
+
+impl From<Rational> for Fraction {
+    fn from(r: Rational) -> Self {
+        Self {
+            num: r.numerator(),
+            den: r.denominator(),
+        }
+    }
+}
+
+impl Display for Fraction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+impl Display for ProbeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Container:    {}", self.container)?;
+        writeln!(f, "Stream:       #{}", self.stream_index)?;
+        writeln!(f, "Codec:        {}", self.codec)?;
+        match &self.decoder {
+            Some(decoder) if self.decoder_overwritten => {
+                writeln!(f, "Decoder:      {} (overwritten)", decoder)?
+            }
+            Some(decoder) => writeln!(f, "Decoder:      {}", decoder)?,
+            None => writeln!(f, "Decoder:      <none>")?,
+        };
+        writeln!(f, "Dimensions:   {}x{}", self.width, self.height)?;
+        if (self.display_width, self.display_height) != (self.width, self.height) {
+            writeln!(
+                f,
+                "Display size: {}x{} (SAR {})",
+                self.display_width, self.display_height, self.sample_aspect_ratio
+            )?;
+        }
+        writeln!(f, "Rotation:     {}", self.orientation)?;
+        writeln!(f, "Pixel format: {}", self.pixel_format)?;
+        writeln!(
+            f,
+            "Alpha:        {}",
+            if self.has_alpha { "yes" } else { "no" }
+        )?;
+        writeln!(f, "Frames:       {}", self.frames)?;
+        writeln!(
+            f,
+            "Frame rate:   {} ({:.3} fps)",
+            self.frame_rate,
+            self.frame_rate.num as f64 / self.frame_rate.den.max(1) as f64
+        )?;
+        writeln!(f, "Duration:     {} ms", self.duration_ms)?;
+        writeln!(f, "Time base:    {}", self.time_base)?;
+        write!(f, "Streams:")?;
+        for stream in &self.streams {
+            let marker = if stream.index == self.stream_index {
+                '*'
+            } else {
+                ' '
+            };
+            write!(f, "\n  {} {}", marker, stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for StreamInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {} {}", self.index, self.kind, self.codec)?;
+        if let Some((width, height)) = self.dimensions {
+            write!(f, " {}x{}", width, height)?;
+        }
+        if self.kind == "video" && self.frames >= 0 {
+            write!(f, ", {} frames", self.frames)?;
+        }
+        if self.attached_picture {
+            write!(f, " (attached picture)")?;
+        }
+        if self.default {
+            write!(f, " (default)")?;
+        }
+        Ok(())
+    }
+}
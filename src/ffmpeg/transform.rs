@@ -0,0 +1,223 @@
+//! Rotating and flipping frames according to the display matrix of a stream.
+use ffmpeg_next::{
+    codec::packet::side_data, ffi::av_display_rotation_get, format::Pixel, frame, Stream,
+};
+use std::fmt::{Display, Formatter};
+
+/// How frames have to be transformed to be displayed upright.
+///
+/// The frame is flipped horizontally (if `hflip` is set) and then rotated clockwise.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Serialize)]
+pub struct Orientation {
+    /// Clockwise rotation in degrees, one of 0, 90, 180 or 270
+    pub rotation: u32,
+    pub hflip: bool,
+}
+
+impl Orientation {
+    /// Read the orientation from the display matrix of `stream` (e.g. phone recordings).
+    pub fn from_stream(stream: &Stream) -> Self {
+        stream
+            .side_data()
+            .find(|data| data.kind() == side_data::Type::DisplayMatrix)
+            .and_then(|data| Self::from_display_matrix(data.data()))
+            .unwrap_or_default()
+    }
+
+    /// `matrix` is the raw side data, nine native-endian 32 bit integers
+    fn from_display_matrix(matrix: &[u8]) -> Option<Self> {
+        let matrix = matrix
+            .chunks_exact(4)
+            .map(|chunk| i32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect::<Vec<_>>();
+        if matrix.len() < 9 {
+            return None;
+        }
+        // ffmpeg returns the counterclockwise rotation, which is NaN for a degenerate matrix
+        let rotation = -unsafe { av_display_rotation_get(matrix.as_ptr()) };
+        if rotation.is_nan() {
+            return None;
+        }
+        let determinant = matrix[0] as i64 * matrix[4] as i64 - matrix[1] as i64 * matrix[3] as i64;
+        Some(Self {
+            // round to the closest quarter turn, other angles can't be displayed anyway
+            rotation: ((rotation / 90.0).round() as i64).rem_euclid(4) as u32 * 90,
+            hflip: determinant < 0,
+        })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.rotation == 0 && !self.hflip
+    }
+
+    /// `true` if width and height are swapped (rotated by 90 or 270 degrees)
+    pub fn swaps_dimensions(&self) -> bool {
+        self.rotation % 180 == 90
+    }
+
+    /// Dimensions of a `width`x`height` frame after the transformation
+    pub fn apply_dimensions(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        if self.swaps_dimensions() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// `true` if [`apply`](Self::apply) can transform frames in `format`.
+    ///
+    /// Only 8 bit planar formats are supported, the chroma planes have to be subsampled
+    /// equally in both directions if width and height are swapped.
+    pub fn supports(&self, format: Pixel) -> bool {
+        match format {
+            Pixel::YUV420P | Pixel::YUVA420P | Pixel::YUV444P | Pixel::YUVA444P => true,
+            Pixel::YUV422P | Pixel::YUVA422P => !self.swaps_dimensions(),
+            _ => self.is_identity(),
+        }
+    }
+
+    /// Transform `frame` into `transformed`, its format has to be [supported](Self::supports).
+    ///
+    /// `transformed` has to have the same format and the [transformed dimensions](Self::apply_dimensions).
+    pub fn apply(&self, frame: &frame::Video, transformed: &mut frame::Video) {
+        for plane in 0..frame.planes() {
+            self.apply_plane(frame, transformed, plane);
+        }
+    }
+
+    fn apply_plane(&self, src: &frame::Video, dst: &mut frame::Video, plane: usize) {
+        let (src_width, src_height) = (
+            src.plane_width(plane) as usize,
+            src.plane_height(plane) as usize,
+        );
+        let (dst_width, dst_height) = (
+            dst.plane_width(plane) as usize,
+            dst.plane_height(plane) as usize,
+        );
+        let (src_stride, dst_stride) = (src.stride(plane), dst.stride(plane));
+        let src_data = src.data(plane);
+        let dst_data = dst.data_mut(plane);
+        for y in 0..dst_height {
+            for x in 0..dst_width {
+                // position in the flipped source frame
+                let (sx, sy) = match self.rotation {
+                    90 => (y, src_height - 1 - x),
+                    180 => (src_width - 1 - x, src_height - 1 - y),
+                    270 => (src_width - 1 - y, x),
+                    _ => (x, y),
+                };
+                let sx = if self.hflip { src_width - 1 - sx } else { sx };
+                dst_data[y * dst_stride + x] = src_data[sy * src_stride + sx];
+            }
+        }
+    }
+}
+
+impl Display for Orientation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}°", self.rotation)?;
+        if self.hflip {
+            write!(f, " (flipped)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next::ffi::{av_display_matrix_flip, av_display_rotation_set};
+
+    /// A 3x2 frame with the luma values 0 to 5, row by row
+    fn frame() -> frame::Video {
+        let mut frame = frame::Video::new(Pixel::YUV444P, 3, 2);
+        let stride = frame.stride(0);
+        for (i, value) in (0..6).enumerate() {
+            frame.data_mut(0)[i / 3 * stride + i % 3] = value;
+        }
+        frame
+    }
+
+    fn transform(orientation: Orientation) -> Vec<Vec<u8>> {
+        let frame = frame();
+        let (width, height) = orientation.apply_dimensions((3, 2));
+        let mut transformed = frame::Video::new(Pixel::YUV444P, width, height);
+        orientation.apply(&frame, &mut transformed);
+        let stride = transformed.stride(0);
+        (0..height as usize)
+            .map(|y| transformed.data(0)[y * stride..][..width as usize].to_vec())
+            .collect()
+    }
+
+    fn display_matrix(counterclockwise: f64, hflip: bool) -> Vec<u8> {
+        let mut matrix = [0i32; 9];
+        unsafe {
+            av_display_rotation_set(matrix.as_mut_ptr(), counterclockwise);
+            if hflip {
+                av_display_matrix_flip(matrix.as_mut_ptr(), 1, 0);
+            }
+        }
+        matrix
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn rotate_clockwise() {
+        let orientation = |rotation| Orientation {
+            rotation,
+            hflip: false,
+        };
+        assert_eq!(transform(orientation(0)), [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(transform(orientation(90)), [[3, 0], [4, 1], [5, 2]]);
+        assert_eq!(transform(orientation(180)), [[5, 4, 3], [2, 1, 0]]);
+        assert_eq!(transform(orientation(270)), [[2, 5], [1, 4], [0, 3]]);
+    }
+
+    #[test]
+    fn flip_then_rotate() {
+        let flipped = |rotation| Orientation {
+            rotation,
+            hflip: true,
+        };
+        assert_eq!(transform(flipped(0)), [[2, 1, 0], [5, 4, 3]]);
+        assert_eq!(transform(flipped(90)), [[5, 2], [4, 1], [3, 0]]);
+    }
+
+    #[test]
+    fn dimensions_and_formats() {
+        let rotated = Orientation {
+            rotation: 90,
+            hflip: false,
+        };
+        assert_eq!(rotated.apply_dimensions((3, 2)), (2, 3));
+        assert!(rotated.supports(Pixel::YUV420P));
+        assert!(!rotated.supports(Pixel::YUV422P));
+        assert!(!rotated.supports(Pixel::RGBA));
+        assert!(Orientation::default().supports(Pixel::RGBA));
+    }
+
+    #[test]
+    fn read_display_matrix() {
+        let read = |matrix: Vec<u8>| Orientation::from_display_matrix(&matrix);
+        assert_eq!(
+            read(display_matrix(0.0, false)),
+            Some(Orientation::default())
+        );
+        // ffmpeg's angles are counterclockwise
+        assert_eq!(
+            read(display_matrix(90.0, false)),
+            Some(Orientation {
+                rotation: 270,
+                hflip: false
+            })
+        );
+        assert_eq!(
+            read(display_matrix(-90.0, true)).map(|o| o.hflip),
+            Some(true)
+        );
+        assert_eq!(read(vec![0; 9 * 4]), None);
+        assert_eq!(read(vec![0; 8]), None);
+    }
+}
@@ -1,23 +1,45 @@
+use crate::ffmpeg::pool::PooledFrame;
 use ffmpeg_next::{frame, Rational};
 
-pub type FrameData = (frame::Video, TimingData);
+/// A frame and when it's shown, the frame is recycled once it's dropped (if it's pooled)
+pub type FrameData = (PooledFrame, TimingData);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct TimingData {
     /// in time_base
     pub timestamp: i64,
+    /// in time_base, 0 if unknown
+    pub duration: i64,
     pub time_base: Rational,
+    /// The frame doesn't depend on previous frames of the source (a good place to split it)
+    pub keyframe: bool,
 }
 
 impl TimingData {
     pub fn try_new(frame: &frame::Video, stream_time_base: Rational) -> Option<Self> {
         Some(Self {
             timestamp: frame.timestamp()?,
+            duration: unsafe { (*frame.as_ptr()).pkt_duration },
             time_base: stream_time_base,
+            keyframe: frame.is_key(),
         })
     }
 
+    /// Synthesized timing for frames without (usable) timestamps, e.g. images of a sequence
+    pub fn from_ms(timestamp_ms: i64, duration_ms: i64) -> Self {
+        Self {
+            timestamp: timestamp_ms,
+            duration: duration_ms,
+            time_base: Rational::new(1, 1000),
+            keyframe: true,
+        }
+    }
+
     pub fn ts_in_ms(&self) -> i64 {
         (1000 * self.timestamp * self.time_base.0 as i64) / (self.time_base.1 as i64)
     }
+
+    pub fn duration_in_ms(&self) -> i64 {
+        (1000 * self.duration * self.time_base.0 as i64) / (self.time_base.1 as i64)
+    }
 }
@@ -0,0 +1,585 @@
+use crate::inspect::{reader::Reader, InspectError};
+use std::fmt::{Display, Formatter};
+
+const ALPHA_URNS: &[&str] = &[
+    "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
+    "urn:mpeg:hevc:2015:auxid:1",
+];
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct AvifReport {
+    pub major_brand: String,
+    pub minor_version: u32,
+    pub compatible_brands: Vec<String>,
+    pub primary_item: Option<u32>,
+    pub has_alpha: bool,
+    pub items: Vec<AvifItem>,
+    pub tracks: Vec<AvifTrack>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct AvifItem {
+    pub id: u32,
+    pub item_type: String,
+    pub name: String,
+    /// Fourccs of the associated properties
+    pub property_types: Vec<String>,
+    #[serde(flatten)]
+    pub properties: ImageProperties,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct AvifTrack {
+    pub track_id: u32,
+    pub handler: String,
+    pub codec: String,
+    pub timescale: u32,
+    pub duration: u64,
+    pub duration_ms: u64,
+    pub sample_count: u32,
+    pub sample_durations: Vec<SampleDurations>,
+    #[serde(flatten)]
+    pub properties: ImageProperties,
+}
+
+/// `count` consecutive samples with a duration of `duration` (in the track's timescale)
+#[derive(Debug, serde::Serialize)]
+pub struct SampleDurations {
+    pub count: u32,
+    pub duration: u32,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ImageProperties {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub depth: Option<u8>,
+    /// e.g. `4:2:0`
+    pub chroma_subsampling: Option<String>,
+    pub cicp: Option<Cicp>,
+    pub icc_size: Option<usize>,
+    /// The item/track is an auxiliary alpha image
+    pub alpha: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Cicp {
+    pub colour_primaries: u16,
+    pub transfer_characteristics: u16,
+    pub matrix_coefficients: u16,
+    pub full_range: bool,
+}
+
+pub(crate) struct BmffBox<'a> {
+    pub kind: String,
+    pub payload: &'a [u8],
+}
+
+pub fn parse(data: &[u8]) -> Result<AvifReport, InspectError> {
+    let mut report = AvifReport::default();
+    for b in children(data)? {
+        match b.kind.as_str() {
+            "ftyp" => parse_ftyp(&mut report, b.payload)?,
+            "meta" => parse_meta(&mut report, b.payload)?,
+            "moov" => {
+                for trak in children(b.payload)?.iter().filter(|b| b.kind == "trak") {
+                    report.tracks.push(parse_trak(trak.payload)?);
+                }
+            }
+            _ => (),
+        }
+    }
+    if report.major_brand.is_empty() {
+        return Err(InspectError::Invalid("file without ftyp box"));
+    }
+    report.has_alpha = report.items.iter().any(|i| i.properties.alpha)
+        || report.tracks.iter().any(|t| t.properties.alpha);
+    Ok(report)
+}
+
+pub(crate) fn children(data: &[u8]) -> Result<Vec<BmffBox>, InspectError> {
+    let mut reader = Reader::new(data);
+    let mut boxes = Vec::new();
+    while !reader.is_empty() {
+        let size = reader.u32_be()? as u64;
+        let kind = reader.fourcc()?;
+        let payload_size = match size {
+            // box extends to the end of the file
+            0 => reader.remaining() as u64,
+            1 => reader
+                .u64_be()?
+                .checked_sub(16)
+                .ok_or(InspectError::Invalid("box size"))?,
+            size => size
+                .checked_sub(8)
+                .ok_or(InspectError::Invalid("box size"))?,
+        };
+        boxes.push(BmffBox {
+            kind,
+            payload: reader.bytes(payload_size as usize)?,
+        });
+    }
+    Ok(boxes)
+}
+
+/// Reads the version and flags of a full box
+pub(crate) fn full_box_header(reader: &mut Reader) -> Result<(u8, u32), InspectError> {
+    let version = reader.u8()?;
+    let flags = reader.bytes(3)?;
+    Ok((
+        version,
+        u32::from_be_bytes([0, flags[0], flags[1], flags[2]]),
+    ))
+}
+
+fn parse_ftyp(report: &mut AvifReport, payload: &[u8]) -> Result<(), InspectError> {
+    let mut reader = Reader::new(payload);
+    report.major_brand = reader.fourcc()?;
+    report.minor_version = reader.u32_be()?;
+    while reader.remaining() >= 4 {
+        report.compatible_brands.push(reader.fourcc()?);
+    }
+    Ok(())
+}
+
+fn parse_meta(report: &mut AvifReport, payload: &[u8]) -> Result<(), InspectError> {
+    let mut reader = Reader::new(payload);
+    full_box_header(&mut reader)?;
+    let boxes = children(reader.rest())?;
+
+    let mut properties = Vec::new();
+    let mut associations = Vec::new();
+    for b in &boxes {
+        match b.kind.as_str() {
+            "pitm" => {
+                let mut reader = Reader::new(b.payload);
+                report.primary_item = Some(match full_box_header(&mut reader)?.0 {
+                    0 => reader.u16_be()? as u32,
+                    _ => reader.u32_be()?,
+                });
+            }
+            "iinf" => {
+                let mut reader = Reader::new(b.payload);
+                match full_box_header(&mut reader)?.0 {
+                    0 => reader.skip(2)?,
+                    _ => reader.skip(4)?,
+                };
+                for infe in children(reader.rest())?.iter().filter(|b| b.kind == "infe") {
+                    report.items.push(parse_infe(infe.payload)?);
+                }
+            }
+            "iprp" => {
+                for b in children(b.payload)? {
+                    match b.kind.as_str() {
+                        "ipco" => properties = children(b.payload)?,
+                        "ipma" => associations = parse_ipma(b.payload)?,
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for (item_id, indices) in associations {
+        let item = match report.items.iter_mut().find(|i| i.id == item_id) {
+            Some(item) => item,
+            None => continue,
+        };
+        // property indices start at 1, 0 means "no property"
+        for property in indices
+            .into_iter()
+            .filter_map(|i| properties.get((i as usize).checked_sub(1)?))
+        {
+            item.property_types.push(property.kind.clone());
+            parse_property(&mut item.properties, property)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_infe(payload: &[u8]) -> Result<AvifItem, InspectError> {
+    let mut reader = Reader::new(payload);
+    let (version, _) = full_box_header(&mut reader)?;
+    if version < 2 {
+        return Err(InspectError::Invalid("infe version"));
+    }
+    let id = match version {
+        2 => reader.u16_be()? as u32,
+        _ => reader.u32_be()?,
+    };
+    // item_protection_index
+    reader.skip(2)?;
+    Ok(AvifItem {
+        id,
+        item_type: reader.fourcc()?,
+        name: reader.cstr().unwrap_or_default(),
+        ..AvifItem::default()
+    })
+}
+
+/// Returns the property indices associated with each item
+fn parse_ipma(payload: &[u8]) -> Result<Vec<(u32, Vec<u16>)>, InspectError> {
+    let mut reader = Reader::new(payload);
+    let (version, flags) = full_box_header(&mut reader)?;
+    let entries = reader.u32_be()?;
+    let mut associations = Vec::with_capacity((entries as usize).min(reader.remaining()));
+    for _ in 0..entries {
+        let item_id = match version {
+            0 => reader.u16_be()? as u32,
+            _ => reader.u32_be()?,
+        };
+        let count = reader.u8()?;
+        let indices = (0..count)
+            .map(|_| -> Result<u16, InspectError> {
+                // the highest bit is the "essential" flag
+                if flags & 1 != 0 {
+                    Ok(reader.u16_be()? & 0x7fff)
+                } else {
+                    Ok((reader.u8()? & 0x7f) as u16)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        associations.push((item_id, indices));
+    }
+    Ok(associations)
+}
+
+fn parse_property(properties: &mut ImageProperties, b: &BmffBox) -> Result<(), InspectError> {
+    let mut reader = Reader::new(b.payload);
+    match b.kind.as_str() {
+        "ispe" => {
+            full_box_header(&mut reader)?;
+            properties.width = Some(reader.u32_be()?);
+            properties.height = Some(reader.u32_be()?);
+        }
+        "pixi" => {
+            full_box_header(&mut reader)?;
+            if reader.u8()? > 0 {
+                properties.depth = Some(reader.u8()?);
+            }
+        }
+        "av1C" => {
+            // marker/version, profile/level
+            reader.skip(2)?;
+            let flags = reader.u8()?;
+            let high_bitdepth = flags & 0x40 != 0;
+            let twelve_bit = flags & 0x20 != 0;
+            let monochrome = flags & 0x10 != 0;
+            let subsampling = (flags & 0x08 != 0, flags & 0x04 != 0);
+            properties
+                .depth
+                .get_or_insert(match (high_bitdepth, twelve_bit) {
+                    (true, true) => 12,
+                    (true, false) => 10,
+                    _ => 8,
+                });
+            properties.chroma_subsampling = Some(
+                match (monochrome, subsampling) {
+                    (true, _) => "4:0:0",
+                    (false, (true, true)) => "4:2:0",
+                    (false, (true, false)) => "4:2:2",
+                    (false, _) => "4:4:4",
+                }
+                .to_string(),
+            );
+        }
+        "colr" => match reader.fourcc()?.as_str() {
+            "nclx" => {
+                properties.cicp = Some(Cicp {
+                    colour_primaries: reader.u16_be()?,
+                    transfer_characteristics: reader.u16_be()?,
+                    matrix_coefficients: reader.u16_be()?,
+                    full_range: reader.u8()? & 0x80 != 0,
+                });
+            }
+            "rICC" | "prof" => properties.icc_size = Some(reader.remaining()),
+            _ => (),
+        },
+        "auxC" => {
+            full_box_header(&mut reader)?;
+            properties.alpha = ALPHA_URNS.contains(&reader.cstr()?.as_str());
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn parse_trak(payload: &[u8]) -> Result<AvifTrack, InspectError> {
+    let mut track = AvifTrack::default();
+    for b in children(payload)? {
+        match b.kind.as_str() {
+            "tkhd" => {
+                let mut reader = Reader::new(b.payload);
+                let (version, _) = full_box_header(&mut reader)?;
+                // creation and modification time
+                reader.skip(if version == 1 { 16 } else { 8 })?;
+                track.track_id = reader.u32_be()?;
+                // reserved, duration, reserved, layer, alternate group, volume, reserved, matrix
+                reader.skip(4 + if version == 1 { 8 } else { 4 } + 8 + 2 + 2 + 2 + 2 + 36)?;
+                // 16.16 fixed point
+                track.properties.width = Some(reader.u32_be()? >> 16);
+                track.properties.height = Some(reader.u32_be()? >> 16);
+            }
+            "tref" => {
+                if children(b.payload)?.iter().any(|b| b.kind == "auxl") {
+                    track.properties.alpha = true;
+                }
+            }
+            "mdia" => parse_mdia(&mut track, b.payload)?,
+            _ => (),
+        }
+    }
+    if track.timescale > 0 {
+        track.duration_ms = track.duration * 1000 / track.timescale as u64;
+    }
+    Ok(track)
+}
+
+fn parse_mdia(track: &mut AvifTrack, payload: &[u8]) -> Result<(), InspectError> {
+    for b in children(payload)? {
+        let mut reader = Reader::new(b.payload);
+        match b.kind.as_str() {
+            "mdhd" => {
+                let (version, _) = full_box_header(&mut reader)?;
+                if version == 1 {
+                    reader.skip(16)?;
+                    track.timescale = reader.u32_be()?;
+                    track.duration = reader.u64_be()?;
+                } else {
+                    reader.skip(8)?;
+                    track.timescale = reader.u32_be()?;
+                    track.duration = reader.u32_be()? as u64;
+                }
+            }
+            "hdlr" => {
+                full_box_header(&mut reader)?;
+                // pre_defined
+                reader.skip(4)?;
+                track.handler = reader.fourcc()?;
+            }
+            "minf" => {
+                for stbl in children(b.payload)?.iter().filter(|b| b.kind == "stbl") {
+                    parse_stbl(track, stbl.payload)?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn parse_stbl(track: &mut AvifTrack, payload: &[u8]) -> Result<(), InspectError> {
+    for b in children(payload)? {
+        let mut reader = Reader::new(b.payload);
+        match b.kind.as_str() {
+            "stsd" => {
+                full_box_header(&mut reader)?;
+                // entry_count
+                reader.skip(4)?;
+                if let Some(entry) = children(reader.rest())?.first() {
+                    track.codec = entry.kind.clone();
+                    // the VisualSampleEntry fields precede the child boxes
+                    let config = entry.payload.get(78..).unwrap_or_default();
+                    for property in children(config)? {
+                        parse_property(&mut track.properties, &property)?;
+                    }
+                }
+            }
+            "stts" => {
+                full_box_header(&mut reader)?;
+                let entries = reader.u32_be()?;
+                for _ in 0..entries {
+                    track.sample_durations.push(SampleDurations {
+                        count: reader.u32_be()?,
+                        duration: reader.u32_be()?,
+                    });
+                }
+            }
+            "stsz" => {
+                full_box_header(&mut reader)?;
+                // sample_size
+                reader.skip(4)?;
+                track.sample_count = reader.u32_be()?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+impl Display for ImageProperties {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            write!(f, "{}x{}", width, height)?;
+        }
+        if let Some(depth) = self.depth {
+            write!(f, " {}-bit", depth)?;
+        }
+        if let Some(chroma) = &self.chroma_subsampling {
+            write!(f, " {}", chroma)?;
+        }
+        if let Some(cicp) = &self.cicp {
+            write!(
+                f,
+                " CICP {}/{}/{} ({} range)",
+                cicp.colour_primaries,
+                cicp.transfer_characteristics,
+                cicp.matrix_coefficients,
+                if cicp.full_range { "full" } else { "limited" }
+            )?;
+        }
+        if let Some(icc) = self.icc_size {
+            write!(f, " ICC ({} bytes)", icc)?;
+        }
+        if self.alpha {
+            write!(f, " [alpha]")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for AvifReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Format:  AVIF")?;
+        writeln!(
+            f,
+            "Brands:  {} (minor {}), compatible: {}",
+            self.major_brand,
+            self.minor_version,
+            self.compatible_brands.join(", ")
+        )?;
+        writeln!(f, "Alpha:   {}", if self.has_alpha { "yes" } else { "no" })?;
+        write!(f, "Items:   {}", self.items.len())?;
+        for item in &self.items {
+            write!(
+                f,
+                "\n  #{:<3} {}{} {} [{}]",
+                item.id,
+                item.item_type,
+                if self.primary_item == Some(item.id) {
+                    " (primary)"
+                } else {
+                    ""
+                },
+                item.properties,
+                item.property_types.join(" ")
+            )?;
+        }
+        write!(f, "\nTracks:  {}", self.tracks.len())?;
+        for track in &self.tracks {
+            write!(
+                f,
+                "\n  #{:<3} {} {} {} samples, {} ms (timescale {}) {}",
+                track.track_id,
+                track.handler,
+                track.codec,
+                track.sample_count,
+                track.duration_ms,
+                track.timescale,
+                track.properties
+            )?;
+            for durations in &track.sample_durations {
+                write!(
+                    f,
+                    "\n        {} x {} ({} ms)",
+                    durations.count,
+                    durations.duration,
+                    durations.duration as u64 * 1000 / track.timescale.max(1) as u64
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bmff(kind: &str, payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind.as_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn full_box(kind: &str, version: u8, payload: &[u8]) -> Vec<u8> {
+        bmff(kind, &[&[version, 0, 0, 0][..], payload].concat())
+    }
+
+    /// A still image with one 64x32 4:2:0 item
+    fn still_image() -> Vec<u8> {
+        let ftyp = bmff("ftyp", b"avif\0\0\0\0mif1miaf");
+        let pitm = full_box("pitm", 0, &[0, 1]);
+        let infe = full_box("infe", 2, b"\0\x01\0\0av01Color\0");
+        let iinf = full_box("iinf", 0, &[&[0, 1][..], &infe].concat());
+        let ispe = full_box("ispe", 0, &[0, 0, 0, 64, 0, 0, 0, 32]);
+        let av1c = bmff("av1C", &[0x81, 0x00, 0x0c, 0x00]);
+        let ipco = bmff("ipco", &[ispe, av1c].concat());
+        // one item with two properties, the first one is marked as essential
+        let ipma = full_box("ipma", 0, &[0, 0, 0, 1, 0, 1, 2, 0x81, 2]);
+        let iprp = bmff("iprp", &[ipco, ipma].concat());
+        let meta = full_box("meta", 0, &[pitm, iinf, iprp].concat());
+        [ftyp, meta].concat()
+    }
+
+    #[test]
+    fn parse_still_image() {
+        let report = parse(&still_image()).unwrap();
+        assert_eq!(report.major_brand, "avif");
+        assert_eq!(report.compatible_brands, ["mif1", "miaf"]);
+        assert_eq!(report.primary_item, Some(1));
+        assert!(!report.has_alpha);
+        let item = &report.items[0];
+        assert_eq!((item.id, item.item_type.as_str()), (1, "av01"));
+        assert_eq!(item.name, "Color");
+        assert_eq!(item.property_types, ["ispe", "av1C"]);
+        assert_eq!(item.properties.width, Some(64));
+        assert_eq!(item.properties.height, Some(32));
+        assert_eq!(item.properties.depth, Some(8));
+        assert_eq!(item.properties.chroma_subsampling.as_deref(), Some("4:2:0"));
+    }
+
+    #[test]
+    fn truncated_files() {
+        let data = still_image();
+        // cutting off the end of any box is an error, never a panic
+        for len in 0..data.len() {
+            let result = parse(&data[..len]);
+            if len > 24 {
+                assert!(matches!(result, Err(InspectError::Truncated)), "{}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_box_size() {
+        let mut data = still_image();
+        data[..4].copy_from_slice(&4u32.to_be_bytes());
+        assert!(matches!(parse(&data), Err(InspectError::Invalid(_))));
+    }
+
+    #[test]
+    fn box_until_the_end() {
+        let mut data = still_image();
+        // the meta box starts after the 24 bytes of the ftyp box
+        data[24..28].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(parse(&data).unwrap().items.len(), 1);
+    }
+
+    #[test]
+    fn missing_ftyp() {
+        let data = bmff("free", &[0; 4]);
+        assert!(matches!(parse(&data), Err(InspectError::Invalid(_))));
+    }
+
+    #[test]
+    fn too_many_associations() {
+        let ipma = full_box("ipma", 0, &[0xff, 0xff, 0xff, 0xff, 0, 1, 0]);
+        assert!(matches!(
+            parse_ipma(&ipma[8..]),
+            Err(InspectError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+//! Parsers for AVIF and WebP files that report how a file was encoded.
+//!
+//! These only read the container structure and don't decode any image data.
+pub(crate) mod avif;
+pub(crate) mod reader;
+mod webp;
+
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, thiserror::Error)]
+pub enum InspectError {
+    #[error("Unknown file format, expected AVIF or WebP")]
+    UnknownFormat,
+    #[error("Unexpected end of data")]
+    Truncated,
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum InspectReport {
+    Avif(avif::AvifReport),
+    Webp(webp::WebpReport),
+}
+
+pub fn inspect(data: &[u8]) -> Result<InspectReport, InspectError> {
+    match data.get(..12) {
+        Some([b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P']) => {
+            webp::parse(data).map(InspectReport::Webp)
+        }
+        Some([_, _, _, _, b'f', b't', b'y', b'p', ..]) => {
+            avif::parse(data).map(InspectReport::Avif)
+        }
+        _ => Err(InspectError::UnknownFormat),
+    }
+}
+
+impl Display for InspectReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InspectReport::Avif(report) => report.fmt(f),
+            InspectReport::Webp(report) => report.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::inspect::InspectError;
+
+/// Bounds-checked cursor over a byte slice
+#[derive(Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], InspectError> {
+        if self.remaining() < len {
+            return Err(InspectError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), InspectError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    pub fn u8(&mut self) -> Result<u8, InspectError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16_be(&mut self) -> Result<u16, InspectError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32_be(&mut self) -> Result<u32, InspectError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64_be(&mut self) -> Result<u64, InspectError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    pub fn u16_le(&mut self) -> Result<u16, InspectError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u24_le(&mut self) -> Result<u32, InspectError> {
+        let [a, b, c] = self.array()?;
+        Ok(u32::from_le_bytes([a, b, c, 0]))
+    }
+
+    pub fn u32_le(&mut self) -> Result<u32, InspectError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn fourcc(&mut self) -> Result<String, InspectError> {
+        Ok(String::from_utf8_lossy(self.bytes(4)?).into_owned())
+    }
+
+    /// Null-terminated string (the terminator is consumed)
+    pub fn cstr(&mut self) -> Result<String, InspectError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(InspectError::Truncated)?;
+        let string = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += len + 1;
+        Ok(string)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], InspectError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+}
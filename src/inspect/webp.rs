@@ -0,0 +1,326 @@
+use crate::inspect::{reader::Reader, InspectError};
+use std::fmt::{Display, Formatter};
+
+const VP8X_FLAG_ICC: u8 = 0x20;
+const VP8X_FLAG_ALPHA: u8 = 0x10;
+const VP8X_FLAG_EXIF: u8 = 0x08;
+const VP8X_FLAG_XMP: u8 = 0x04;
+const VP8X_FLAG_ANIMATION: u8 = 0x02;
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct WebpReport {
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    pub has_alpha: bool,
+    pub has_animation: bool,
+    pub has_icc: bool,
+    pub has_exif: bool,
+    pub has_xmp: bool,
+    /// 0 = infinite
+    pub loop_count: Option<u16>,
+    /// `#aarrggbb`
+    pub background_color: Option<String>,
+    pub frames: Vec<WebpFrame>,
+    pub chunks: Vec<WebpChunk>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WebpChunk {
+    pub fourcc: String,
+    pub size: u32,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct WebpFrame {
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub width: u32,
+    pub height: u32,
+    pub duration_ms: u32,
+    pub blend: bool,
+    pub dispose_to_background: bool,
+    /// `VP8` (lossy) or `VP8L` (lossless)
+    pub codec: String,
+    pub has_alpha: bool,
+    /// Compression of the `ALPH` chunk (0 = none, 1 = lossless)
+    pub alpha_compression: Option<u8>,
+    /// Size of the frame's bitstream (including the alpha chunk)
+    pub size: u32,
+}
+
+pub fn parse(data: &[u8]) -> Result<WebpReport, InspectError> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(4)? != b"RIFF" {
+        return Err(InspectError::Invalid("RIFF header"));
+    }
+    let riff_size = reader.u32_le()? as usize;
+    if reader.bytes(4)? != b"WEBP" {
+        return Err(InspectError::Invalid("WEBP signature"));
+    }
+    let mut reader =
+        Reader::new(reader.bytes(riff_size.saturating_sub(4).min(reader.remaining()))?);
+
+    let mut report = WebpReport::default();
+    while !reader.is_empty() {
+        let (fourcc, payload) = read_chunk(&mut reader)?;
+        report.chunks.push(WebpChunk {
+            fourcc: fourcc.clone(),
+            size: payload.len() as u32,
+        });
+        let mut payload = Reader::new(payload);
+        match fourcc.as_str() {
+            "VP8X" => {
+                let flags = payload.u8()?;
+                payload.skip(3)?;
+                report.canvas_width = payload.u24_le()? + 1;
+                report.canvas_height = payload.u24_le()? + 1;
+                report.has_icc = flags & VP8X_FLAG_ICC != 0;
+                report.has_alpha = flags & VP8X_FLAG_ALPHA != 0;
+                report.has_exif = flags & VP8X_FLAG_EXIF != 0;
+                report.has_xmp = flags & VP8X_FLAG_XMP != 0;
+                report.has_animation = flags & VP8X_FLAG_ANIMATION != 0;
+            }
+            "ANIM" => {
+                let bgra = payload.bytes(4)?;
+                report.background_color = Some(format!(
+                    "#{}",
+                    hex::encode([bgra[3], bgra[2], bgra[1], bgra[0]])
+                ));
+                report.loop_count = Some(payload.u16_le()?);
+            }
+            "ANMF" => {
+                let x_offset = payload.u24_le()? * 2;
+                let y_offset = payload.u24_le()? * 2;
+                let width = payload.u24_le()? + 1;
+                let height = payload.u24_le()? + 1;
+                let duration_ms = payload.u24_le()?;
+                let flags = payload.u8()?;
+                let mut frame = parse_frame_data(&mut payload)?;
+                frame.x_offset = x_offset;
+                frame.y_offset = y_offset;
+                frame.width = width;
+                frame.height = height;
+                frame.duration_ms = duration_ms;
+                frame.blend = flags & 0x02 == 0;
+                frame.dispose_to_background = flags & 0x01 != 0;
+                report.frames.push(frame);
+            }
+            _ => (),
+        }
+    }
+
+    // simple (non-extended) files and still images only contain the bitstream chunks
+    if report.frames.is_empty() {
+        let frame = parse_frame_data(&mut Reader::new(data.get(12..).unwrap_or_default()))?;
+        if report.canvas_width == 0 {
+            report.canvas_width = frame.width;
+            report.canvas_height = frame.height;
+            report.has_alpha = frame.has_alpha;
+        }
+        report.frames.push(frame);
+    }
+
+    Ok(report)
+}
+
+fn read_chunk<'a>(reader: &mut Reader<'a>) -> Result<(String, &'a [u8]), InspectError> {
+    let fourcc = reader.fourcc()?;
+    let size = reader.u32_le()? as usize;
+    let payload = reader.bytes(size)?;
+    // chunks are padded to an even size
+    if size % 2 == 1 && !reader.is_empty() {
+        reader.skip(1)?;
+    }
+    Ok((fourcc, payload))
+}
+
+/// Parse the `ALPH`/`VP8`/`VP8L` chunks of a frame, other chunks are skipped.
+fn parse_frame_data(reader: &mut Reader) -> Result<WebpFrame, InspectError> {
+    let mut frame = WebpFrame::default();
+    while !reader.is_empty() {
+        let (fourcc, payload) = read_chunk(reader)?;
+        match fourcc.as_str() {
+            "ALPH" => {
+                frame.has_alpha = true;
+                frame.alpha_compression = payload.first().map(|header| header & 0x03);
+                frame.size += payload.len() as u32;
+            }
+            "VP8 " => {
+                let bitstream_size = payload.len() as u32;
+                let mut payload = Reader::new(payload);
+                // frame tag
+                payload.skip(3)?;
+                if payload.bytes(3)? != [0x9d, 0x01, 0x2a] {
+                    return Err(InspectError::Invalid("VP8 start code"));
+                }
+                frame.width = (payload.u16_le()? & 0x3fff) as u32;
+                frame.height = (payload.u16_le()? & 0x3fff) as u32;
+                frame.codec = "VP8".to_string();
+                frame.size += bitstream_size;
+            }
+            "VP8L" => {
+                let bitstream_size = payload.len() as u32;
+                let mut payload = Reader::new(payload);
+                if payload.u8()? != 0x2f {
+                    return Err(InspectError::Invalid("VP8L signature"));
+                }
+                let bits = payload.u32_le()?;
+                frame.width = (bits & 0x3fff) + 1;
+                frame.height = ((bits >> 14) & 0x3fff) + 1;
+                frame.has_alpha = (bits >> 28) & 1 != 0;
+                frame.codec = "VP8L".to_string();
+                frame.size += bitstream_size;
+            }
+            _ => (),
+        }
+    }
+    if frame.codec.is_empty() {
+        return Err(InspectError::Invalid("frame without VP8/VP8L bitstream"));
+    }
+    Ok(frame)
+}
+
+impl Display for WebpReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Format:     WebP")?;
+        writeln!(
+            f,
+            "Canvas:     {}x{}",
+            self.canvas_width, self.canvas_height
+        )?;
+        writeln!(
+            f,
+            "Features:   alpha={} animation={} icc={} exif={} xmp={}",
+            self.has_alpha, self.has_animation, self.has_icc, self.has_exif, self.has_xmp
+        )?;
+        if let Some(loop_count) = self.loop_count {
+            writeln!(f, "Loop count: {} (0 = infinite)", loop_count)?;
+        }
+        if let Some(color) = &self.background_color {
+            writeln!(f, "Background: {}", color)?;
+        }
+        let chunks = self
+            .chunks
+            .iter()
+            .map(|c| format!("{}({})", c.fourcc.trim_end(), c.size))
+            .collect::<Vec<_>>();
+        writeln!(f, "Chunks:     {}", chunks.join(" "))?;
+        write!(f, "Frames:     {}", self.frames.len())?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(
+                f,
+                "\n  #{:<4} {:>4}x{:<4} at ({}, {}) {:>5} ms {:<4} alpha={} blend={} dispose={} {} bytes",
+                i,
+                frame.width,
+                frame.height,
+                frame.x_offset,
+                frame.y_offset,
+                frame.duration_ms,
+                frame.codec,
+                frame.has_alpha,
+                frame.blend,
+                if frame.dispose_to_background { "background" } else { "none" },
+                frame.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(fourcc: &str, payload: &[u8]) -> Vec<u8> {
+        let mut data = fourcc.as_bytes().to_vec();
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend_from_slice(&chunks);
+        data
+    }
+
+    /// A 2x3 lossless bitstream with alpha
+    fn vp8l() -> Vec<u8> {
+        let bits: u32 = 1 | 2 << 14 | 1 << 28;
+        chunk("VP8L", &[&[0x2f][..], &bits.to_le_bytes(), &[0]].concat())
+    }
+
+    fn animation() -> Vec<u8> {
+        let vp8x = chunk("VP8X", &[0x12, 0, 0, 0, 9, 0, 0, 4, 0, 0]);
+        // blue background, loops forever
+        let anim = chunk("ANIM", &[0xff, 0, 0, 0xff, 0, 0]);
+        let header = [
+            &[1, 0, 0, 2, 0, 0, 1, 0, 0, 2, 0, 0][..],
+            &[100, 0, 0, 0x03],
+        ]
+        .concat();
+        let anmf = chunk("ANMF", &[header, vp8l()].concat());
+        riff(&[vp8x, anim, anmf])
+    }
+
+    #[test]
+    fn parse_simple_lossless() {
+        let report = parse(&riff(&[vp8l()])).unwrap();
+        assert_eq!((report.canvas_width, report.canvas_height), (2, 3));
+        assert!(report.has_alpha);
+        let frame = &report.frames[0];
+        assert_eq!(frame.codec, "VP8L");
+        assert_eq!(frame.size, 6);
+    }
+
+    #[test]
+    fn parse_animation() {
+        let report = parse(&animation()).unwrap();
+        assert_eq!((report.canvas_width, report.canvas_height), (10, 5));
+        assert!(report.has_alpha && report.has_animation);
+        assert_eq!(report.loop_count, Some(0));
+        assert_eq!(report.background_color.as_deref(), Some("#ff0000ff"));
+        let frame = &report.frames[0];
+        assert_eq!((frame.x_offset, frame.y_offset), (2, 4));
+        assert_eq!((frame.width, frame.height), (2, 3));
+        assert_eq!(frame.duration_ms, 100);
+        assert!(!frame.blend && frame.dispose_to_background);
+    }
+
+    #[test]
+    fn truncated_files() {
+        let data = animation();
+        for len in 0..data.len() {
+            assert!(parse(&data[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn odd_chunk_size() {
+        let exif = chunk("EXIF", &[1, 2, 3]);
+        let report = parse(&riff(&[exif, vp8l()])).unwrap();
+        assert_eq!(report.chunks[0].size, 3);
+        assert_eq!(report.chunks[1].fourcc, "VP8L");
+    }
+
+    #[test]
+    fn invalid_bitstreams() {
+        let vp8 = chunk("VP8 ", &[0; 10]);
+        assert!(matches!(
+            parse(&riff(&[vp8])),
+            Err(InspectError::Invalid("VP8 start code"))
+        ));
+        let vp8l = chunk("VP8L", &[0; 6]);
+        assert!(matches!(
+            parse(&riff(&[vp8l])),
+            Err(InspectError::Invalid("VP8L signature"))
+        ));
+        assert!(matches!(parse(&riff(&[])), Err(InspectError::Invalid(_))));
+    }
+}
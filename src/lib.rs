@@ -0,0 +1,272 @@
+#![warn(clippy::cargo)]
+#![allow(clippy::cast_possible_wrap)]
+#![allow(clippy::cast_precision_loss)]
+#![allow(clippy::cast_sign_loss)]
+#![allow(clippy::cast_possible_truncation)]
+#![allow(clippy::cast_lossless)]
+
+//! Convert videos and images to AVIF and WebP.
+//!
+//! ```no_run
+//! use emote_tool::{options::AvifOptions, Conversion};
+//!
+//! emote_tool::init().unwrap();
+//! let (data, report) = Conversion::avif("input.gif", AvifOptions::default())
+//!     .progress(|stage, update| println!("{:?}: {:?}", stage, update))
+//!     .encode()
+//!     .unwrap();
+//! println!("{} bytes, {} frames", data.len(), report.frames.unwrap_or_default());
+//! ```
+
+pub mod avif;
+pub mod cancel;
+pub mod ffmpeg;
+pub mod inspect;
+pub mod metrics;
+pub mod options;
+pub mod pipeline;
+pub mod progress;
+pub mod report;
+pub mod sprite;
+pub mod task;
+pub mod webp;
+
+use crate::{
+    avif::task::AvifEncoderTask,
+    cancel::CancellationToken,
+    ffmpeg::{limits::Limits, FfmpegError},
+    options::{AvifOptions, OverwritePolicy, SequenceOptions, Size, WebpOptions},
+    pipeline::{run_ffmpeg, spawn_encoder, spawn_file_encoder, InputOptions, InputStream},
+    progress::{CallbackReporter, Progress, ProgressReporter, ProgressUpdate, SharedReporter},
+    report::{Stage, TaskReport},
+    webp::task::WebpEncoderTask,
+};
+use std::{
+    io,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+pub use crate::task::EncoderTask;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TaskError {
+    #[error("Couldn't open input: {0}")]
+    FfmpegReadInputStream(FfmpegError),
+    #[error("Couldn't configure encoder task: {0}")]
+    ConfigurationError(Box<dyn std::error::Error>),
+    #[error("Couldn't open/create output file: {0}")]
+    IoOutputFileError(io::Error),
+    #[error("The output file {} already exists", .0.display())]
+    OutputExists(PathBuf),
+    #[error("Couldn't calculate the total frames in the input.")]
+    NoFrames,
+    #[error("The conversion was cancelled")]
+    Cancelled,
+}
+
+/// Initialize ffmpeg, this has to be called once before converting anything.
+pub fn init() -> Result<(), ffmpeg_next::Error> {
+    ffmpeg_next::init()
+}
+
+/// Builder for converting one input with the encoder task `T`
+pub struct Conversion<T: EncoderTask> {
+    input: PathBuf,
+    options: T::Options,
+    metrics: bool,
+    progress: Option<SharedReporter>,
+    overwrite: OverwritePolicy,
+    input_options: InputOptions,
+    cancel: CancellationToken,
+}
+
+impl Conversion<AvifEncoderTask> {
+    pub fn avif<P: Into<PathBuf>>(input: P, options: AvifOptions) -> Self {
+        Self::new(input, options)
+    }
+}
+
+impl Conversion<WebpEncoderTask> {
+    pub fn webp<P: Into<PathBuf>>(input: P, options: WebpOptions) -> Self {
+        Self::new(input, options)
+    }
+}
+
+impl<T: EncoderTask> Conversion<T> {
+    pub fn new<P: Into<PathBuf>>(input: P, options: T::Options) -> Self {
+        Self {
+            input: input.into(),
+            options,
+            metrics: false,
+            progress: None,
+            overwrite: OverwritePolicy::default(),
+            input_options: InputOptions::default(),
+            cancel: CancellationToken::default(),
+        }
+    }
+
+    /// Decode the output and compare it against the source frames (PSNR/SSIM)
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Call `callback` whenever the decoder or the encoder made progress.
+    ///
+    /// The callback is called from the decoder and the encoder threads.
+    pub fn progress<F>(self, callback: F) -> Self
+    where
+        F: Fn(Stage, ProgressUpdate) + Send + Sync + 'static,
+    {
+        self.reporter(CallbackReporter(callback))
+    }
+
+    /// Report the progress to `reporter`, see [`crate::progress`] for the available reporters.
+    pub fn reporter<R: ProgressReporter + 'static>(mut self, reporter: R) -> Self {
+        self.progress = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Convert the video stream with this index instead of the best one.
+    ///
+    /// By default, attached pictures (cover art) are skipped if there's another video stream.
+    pub fn stream(mut self, index: usize) -> Self {
+        self.input_options.stream = Some(index);
+        self
+    }
+
+    /// Rotate the frames according to the display matrix of the stream (enabled by default).
+    pub fn autorotate(mut self, autorotate: bool) -> Self {
+        self.input_options.autorotate = autorotate;
+        self
+    }
+
+    /// Fill the color of fully transparent pixels from the visible ones (disabled by default),
+    /// which avoids dark halos around transparent edges.
+    ///
+    /// Frames with alpha are converted to full resolution 4:4:4 for this, and the color
+    /// spreads one pixel per pass, so large transparent areas make it slow.
+    pub fn alpha_bleed(mut self, alpha_bleed: bool) -> Self {
+        self.input_options.alpha_bleed = alpha_bleed;
+        self
+    }
+
+    /// Reject inputs that exceed the `limits`, before and while decoding them.
+    ///
+    /// Nothing is limited by default, set limits for untrusted inputs.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.input_options.limits = limits;
+        self
+    }
+
+    /// Scale the frames down to fit into `max_size` (e.g. [`options::Platform::max_size`]),
+    /// keeping their aspect ratio. Smaller inputs aren't scaled up.
+    pub fn max_size(mut self, max_size: Size) -> Self {
+        self.input_options.max_size = Some(max_size);
+        self
+    }
+
+    /// Read the input as an image sequence (`frame_%04d.png` or `frames/*.png`) with this timing.
+    ///
+    /// Patterns are also detected without this, but use a frame rate of 25.
+    pub fn sequence(mut self, options: SequenceOptions) -> Self {
+        self.input_options.sequence = Some(options);
+        self
+    }
+
+    /// Replace all options of the input at once (stream, rotation, limits, size and sequence)
+    pub fn input_options(mut self, options: InputOptions) -> Self {
+        self.input_options = options;
+        self
+    }
+
+    /// What [`save`](Self::save) does if the output file already exists (replaces it by default)
+    pub fn overwrite(mut self, overwrite: OverwritePolicy) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Stop the conversion once `cancel` is cancelled, the partial output file is deleted.
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Write the output to `output_name` with the extension of the format (e.g. `out` -> `out.avif`).
+    ///
+    /// The output only appears once it's complete, a failed conversion doesn't leave a file behind.
+    pub fn save(self, output_name: &str) -> Result<TaskReport<T::EncoderStats>, TaskError> {
+        if self.cancel.is_cancelled() {
+            return Err(TaskError::Cancelled);
+        }
+        let out_file = T::make_output_path(output_name);
+        if self.overwrite == OverwritePolicy::Skip && out_file.exists() {
+            return Ok(TaskReport::skipped(out_file));
+        }
+        let input = self.open_input()?;
+        let ffmpeg_progress = Progress::new(Stage::Ffmpeg, input.frames, self.progress.clone());
+        let (sink, encoder) = spawn_file_encoder::<T>(
+            output_name,
+            self.options,
+            &input,
+            self.metrics,
+            Progress::new(Stage::Encoder, input.frames, self.progress.clone()),
+            self.overwrite,
+            &self.cancel,
+        )?;
+        let dimensions = input.dimensions;
+        let ff_result = run_ffmpeg(input, vec![sink], ffmpeg_progress, &self.cancel);
+        Ok(encoder.join(&ff_result, dimensions))
+    }
+
+    /// Write the output to any writer.
+    pub fn write_to<W>(self, writer: W) -> Result<TaskReport<T::EncoderStats>, TaskError>
+    where
+        W: Write + Send + 'static,
+    {
+        if self.cancel.is_cancelled() {
+            return Err(TaskError::Cancelled);
+        }
+        let input = self.open_input()?;
+        let ffmpeg_progress = Progress::new(Stage::Ffmpeg, input.frames, self.progress.clone());
+        let (sink, encoder) = spawn_encoder::<T, W>(
+            writer,
+            self.options,
+            &input,
+            self.metrics,
+            Progress::new(Stage::Encoder, input.frames, self.progress.clone()),
+            &self.cancel,
+        )?;
+        let dimensions = input.dimensions;
+        let ff_result = run_ffmpeg(input, vec![sink], ffmpeg_progress, &self.cancel);
+        Ok(encoder.join(&ff_result, dimensions))
+    }
+
+    /// Encode into memory.
+    pub fn encode(self) -> Result<(Vec<u8>, TaskReport<T::EncoderStats>), TaskError> {
+        let buffer = SharedBuffer::default();
+        let report = self.write_to(buffer.clone())?;
+        let data = std::mem::take(&mut *buffer.0.lock().unwrap());
+        Ok((data, report))
+    }
+
+    fn open_input(&self) -> Result<InputStream, TaskError> {
+        InputStream::open_with(&self.input, &self.input_options)
+    }
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -5,123 +5,406 @@
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_lossless)]
 
-mod avif;
+mod auto;
+mod batch;
 mod cli;
-mod ffmpeg;
-mod task;
-mod webp;
+mod config;
+mod multi;
+mod sandbox;
+mod serve;
+mod watch;
 
 use crate::{
+    batch::BatchError,
+    cli::{
+        AvifCommand, BatchAvifCommand, BatchCommand, BatchFormat, BatchWebpCommand, Cli,
+        CliCommand, InspectCommand, IoOptions, MetricsOptions, OutputFormat, ProbeCommand,
+        ProgressMode, SandboxOptions, SpriteCommand, SpriteFormat, SpriteSheetCommand,
+        WatchAvifCommand, WatchCommand, WatchFormat, WatchWebpCommand, WebpCommand,
+    },
+    config::ConfigError,
+    sandbox::SandboxError,
+};
+use emote_tool::{
     avif::task::AvifEncoderTask,
-    cli::{AvifCommand, Cli, CliCommand, IoOptions, WebpCommand},
-    ffmpeg::{formats::AcceptedFormats, frames::extract_frames, FfmpegError},
-    task::EncoderTask,
+    cancel::CancellationToken,
+    ffmpeg,
+    ffmpeg::input::STDIO_PATH,
+    inspect,
+    inspect::InspectError,
+    progress,
+    progress::{JsonLines, SharedReporter, Silent, TerminalProgress},
+    report::{ErrorReport, Stage, TaskReport},
+    sprite::{task::SpriteSheetTask, Png, SheetFormat, Webp},
     webp::task::WebpEncoderTask,
+    Conversion, EncoderTask, TaskError,
 };
-use clap::Parser;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::{io, thread};
+use indicatif::MultiProgress;
+use std::{fmt::Display, io, io::Write, path::Path, sync::Arc};
 
 #[derive(Debug, thiserror::Error)]
-enum TaskError {
+enum CliError {
     #[error("Couldn't initialize ffmpeg: {0}")]
     FfmpegInitError(ffmpeg_next::Error),
-    #[error("Couldn't open input: {0}")]
-    FfmpegReadInputStream(FfmpegError),
-    #[error("Couldn't configure encoder task: {0}")]
-    ConfigurationError(Box<dyn std::error::Error>),
-    #[error("Couldn't open/create output file: {0}")]
-    IoOutputFileError(io::Error),
-    #[error("Couldn't calculate the total frames in the input.")]
-    NoFrames,
+    #[error(transparent)]
+    TaskError(#[from] TaskError),
+    #[error("Couldn't serialize output: {0}")]
+    SerializeError(#[from] serde_json::Error),
+    #[error("Couldn't read input file: {0}")]
+    IoInputFileError(io::Error),
+    #[error("Couldn't inspect file: {0}")]
+    InspectError(#[from] InspectError),
+    #[error("Couldn't collect batch inputs: {0}")]
+    BatchError(#[from] BatchError),
+    #[error("Couldn't print the report: {0}")]
+    PrintReportError(io::Error),
+    #[error("Couldn't install the Ctrl+C handler: {0}")]
+    SignalHandlerError(#[from] ctrlc::Error),
+    #[error(transparent)]
+    SandboxError(#[from] SandboxError),
+    #[error("Couldn't start the server: {0}")]
+    ServeError(io::Error),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("Couldn't watch the directory: {0}")]
+    WatchError(io::Error),
+    #[error("The {0} command writes more than one file and can't write to stdout")]
+    StdoutOutput(&'static str),
 }
 
-fn main() -> Result<(), TaskError> {
-    ffmpeg_next::init().map_err(TaskError::FfmpegInitError)?;
+/// Exit status after Ctrl+C (128 + SIGINT, like shells report it)
+const CANCELLED_EXIT_CODE: i32 = 130;
+
+fn main() -> Result<(), CliError> {
+    emote_tool::init().map_err(CliError::FfmpegInitError)?;
     ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Warning);
 
-    match Cli::parse().command {
-        CliCommand::Avif(AvifCommand { io, opts }) => run_task::<AvifEncoderTask>(&io, opts),
-        CliCommand::Webp(WebpCommand { io, opts }) => run_task::<WebpEncoderTask>(&io, opts),
+    let cancel = CancellationToken::default();
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || {
+        // a second Ctrl+C doesn't wait for the threads to stop
+        if handler_cancel.is_cancelled() {
+            std::process::exit(CANCELLED_EXIT_CODE);
+        }
+        eprintln!("Cancelling...");
+        handler_cancel.cancel();
+    })?;
+
+    let parsed = config::parse_cli()?;
+    let result_fd = parsed.cli.result_fd;
+    match parsed.cli.command {
+        CliCommand::Avif(AvifCommand {
+            io,
+            metrics,
+            sandbox,
+            opts,
+        }) => report_task::<AvifEncoderTask>(&io, &metrics, &sandbox, result_fd, opts, &cancel),
+        CliCommand::Webp(WebpCommand {
+            io,
+            metrics,
+            sandbox,
+            opts,
+        }) => report_task::<WebpEncoderTask>(&io, &metrics, &sandbox, result_fd, opts, &cancel),
+        CliCommand::Probe(ProbeCommand {
+            input,
+            stream,
+            output_format,
+        }) => run_probe(&input, stream, output_format),
+        CliCommand::Inspect(InspectCommand {
+            input,
+            output_format,
+        }) => run_inspect(&input, output_format),
+        CliCommand::Batch(BatchCommand { format }) => match format {
+            BatchFormat::Avif(BatchAvifCommand { batch, opts }) => {
+                batch::report_batch::<AvifEncoderTask>(&batch, opts, &cancel)
+            }
+            BatchFormat::Webp(BatchWebpCommand { batch, opts }) => {
+                batch::report_batch::<WebpEncoderTask>(&batch, opts, &cancel)
+            }
+        },
+        CliCommand::Multi(command) => multi::report_multi(command, &cancel),
+        CliCommand::Auto(command) => auto::report_auto(command, &cancel),
+        CliCommand::Serve(command) => serve::serve(command, &cancel),
+        CliCommand::Watch(WatchCommand { format }) => match format {
+            WatchFormat::Avif(WatchAvifCommand { watch, opts }) => {
+                watch::watch::<AvifEncoderTask>(&watch, opts, &cancel)
+            }
+            WatchFormat::Webp(WatchWebpCommand { watch, opts }) => {
+                watch::watch::<WebpEncoderTask>(&watch, opts, &cancel)
+            }
+        },
+        CliCommand::Sprite(SpriteCommand { format }) => match format {
+            SpriteFormat::Png(command) => report_sprite_sheet::<Png>(command, &cancel),
+            SpriteFormat::Webp(command) => report_sprite_sheet::<Webp>(command, &cancel),
+        },
+        CliCommand::Config(_) => {
+            config::dump(&parsed);
+            Ok(())
+        }
     }
 }
 
-fn run_task<T>(io_options: &IoOptions, task_options: T::CliArgs) -> Result<(), TaskError>
+fn run_probe(
+    input: &str,
+    stream: Option<usize>,
+    output_format: OutputFormat,
+) -> Result<(), CliError> {
+    let info = ffmpeg::probe::probe(&input, stream).map_err(TaskError::FfmpegReadInputStream)?;
+    match output_format {
+        OutputFormat::Text => println!("{}", info),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+    }
+    Ok(())
+}
+
+fn run_inspect(input: &Path, output_format: OutputFormat) -> Result<(), CliError> {
+    let data = std::fs::read(input).map_err(CliError::IoInputFileError)?;
+    let report = inspect::inspect(&data)?;
+    match output_format {
+        OutputFormat::Text => println!("{}", report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+fn report_task<T>(
+    io_options: &IoOptions,
+    metrics_options: &MetricsOptions,
+    sandbox_options: &SandboxOptions,
+    result_fd: Option<i32>,
+    task_options: T::Options,
+    cancel: &CancellationToken,
+) -> Result<(), CliError>
 where
     T: EncoderTask,
 {
-    let (input_ctx, istream_idx) =
-        ffmpeg::read_initial_stream(&io_options.input).map_err(TaskError::FfmpegReadInputStream)?;
-    // istream_idx is always valid
-    let istream = input_ctx.stream(istream_idx).unwrap();
-    let frames = extract_frames(&istream, &input_ctx);
-    if frames < 0 {
-        return Err(TaskError::NoFrames);
-    }
-    let frames = frames as u64;
-
-    let config = T::configure(task_options, &istream, &input_ctx)
-        .map_err(|e| TaskError::ConfigurationError(Box::new(e)))?;
-    let accepted_formats = AcceptedFormats::for_task::<T>();
-
-    let out_file = T::make_output_path(&io_options.output);
-    let writer = std::fs::OpenOptions::new()
-        .write(true)
-        .append(false)
-        .create(true)
-        .open(&out_file)
-        .map_err(TaskError::IoOutputFileError)?;
-
-    let is_single_frame = istream.frames() == 1;
-    let (frame_tx, frame_rx) = crossbeam::channel::bounded(if is_single_frame { 1 } else { 20 });
-
-    let progress_manager = MultiProgress::new();
-    let ffmpeg_progress = progress_manager.add(ProgressBar::new(frames).with_style(
-        ProgressStyle::default_bar().template("[Ffmpeg {spinner}] Frame {pos}/{len} ({percent}%) {bar:20} {elapsed}/{eta} {msg}").unwrap()));
-    let task_progress = progress_manager.add(ProgressBar::new(frames).with_style(
-        ProgressStyle::default_bar().template("[Encoder {spinner}] Frame {pos}/{len} ({percent}%) {bar:20} {elapsed}/{eta} {msg}").unwrap()));
-
-    let ffmpeg_thread = thread::spawn(move || {
-        ffmpeg::emit_frames(
-            input_ctx,
-            istream_idx,
-            accepted_formats,
-            frame_tx,
-            ffmpeg_progress,
-        )
-    });
-
-    let task_thread = thread::spawn(move || {
-        if is_single_frame {
-            T::run_still(writer, config, frame_rx, task_progress)
-        } else {
-            T::run_animation(writer, config, frame_rx, task_progress)
+    // a worker started by `report_sandboxed` sends its report to the parent
+    if let Some(pipe) = sandbox::result_pipe(result_fd)? {
+        return sandbox::run_as_worker::<T>(
+            pipe,
+            io_options,
+            metrics_options,
+            task_options,
+            cancel,
+        );
+    }
+    if sandbox_options.enabled() {
+        return sandbox::report_sandboxed::<T>(io_options, sandbox_options, cancel);
+    }
+    let result = run_task::<T>(
+        io_options,
+        metrics_options,
+        task_options,
+        task_reporter(io_options.progress, &MultiProgress::new(), None),
+        cancel,
+    );
+    finish_task(io_options, metrics_options, result, cancel)
+}
+
+/// Convert the input to a sprite sheet of the format `F` and write the atlas next to it.
+fn report_sprite_sheet<F: SheetFormat>(
+    command: SpriteSheetCommand,
+    cancel: &CancellationToken,
+) -> Result<(), CliError> {
+    let SpriteSheetCommand { io, atlas, opts } = command;
+    let metrics = MetricsOptions::default();
+    let mut result = run_task::<SpriteSheetTask<F>>(
+        &io,
+        &metrics,
+        opts,
+        task_reporter(io.progress, &MultiProgress::new(), None),
+        cancel,
+    );
+    let mut atlas_path = None;
+    if let Ok(report) = &mut result {
+        // without --atlas, the atlas of a sheet written to stdout is only part of the JSON report
+        let path = atlas.or_else(|| {
+            report
+                .output
+                .as_ref()
+                .map(|output| output.with_extension("json"))
+        });
+        if let (Some(stats), Some(path)) = (&report.stats, path) {
+            match stats.atlas.save(&path, io.overwrite_policy()) {
+                Ok(written) => atlas_path = written.then_some(path),
+                Err(e) => {
+                    if io.output_format == OutputFormat::Text {
+                        eprintln!("Couldn't write the atlas to {}: {}", path.display(), e);
+                    }
+                    report.errors.push(ErrorReport::new(Stage::Task, &e));
+                    report.success = false;
+                }
+            }
+        }
+    }
+    finish_task(&io, &metrics, result, cancel)?;
+    if let (Some(path), OutputFormat::Text) = (atlas_path, io.output_format) {
+        writeln!(report_output(&io), "Atlas written to {}", path.display())
+            .map_err(CliError::PrintReportError)?;
+    }
+    Ok(())
+}
+
+/// Print the report of a single task (or its error) and exit if it failed.
+fn finish_task<S>(
+    io_options: &IoOptions,
+    metrics_options: &MetricsOptions,
+    result: Result<TaskReport<S>, TaskError>,
+    cancel: &CancellationToken,
+) -> Result<(), CliError>
+where
+    S: Display + serde::Serialize,
+{
+    let mut out = report_output(io_options);
+    let report = match io_options.output_format {
+        OutputFormat::Text => {
+            let report = result?;
+            print_report(&mut out, &report, metrics_options).map_err(CliError::PrintReportError)?;
+            report
+        }
+        OutputFormat::Json => {
+            let report =
+                result.unwrap_or_else(|e| TaskReport::failed(ErrorReport::new(Stage::Task, &e)));
+            writeln!(out, "{}", serde_json::to_string_pretty(&report)?)
+                .map_err(CliError::PrintReportError)?;
+            report
         }
-    });
-    let ff_result = ffmpeg_thread.join();
-    let task_result = task_thread.join();
-
-    if matches!((&ff_result, &task_result), (Ok(Ok(_)), Ok(Ok(_)))) {
-        let stats = task_result.unwrap().unwrap();
-        println!("Finished: {}", stats);
-        println!(
-            "Written to {}",
+    };
+    exit_on_failure(report.success, cancel);
+    Ok(())
+}
+
+/// Exit with a non-zero status if a conversion failed, or with [`CANCELLED_EXIT_CODE`] if it was cancelled.
+fn exit_on_failure(success: bool, cancel: &CancellationToken) {
+    match (success, cancel.is_cancelled()) {
+        (true, _) => (),
+        (false, true) => std::process::exit(CANCELLED_EXIT_CODE),
+        (false, false) => std::process::exit(-1),
+    }
+}
+
+/// Print the report of a single task to `out`, errors always go to stderr.
+fn print_report<S: Display>(
+    out: &mut dyn Write,
+    report: &TaskReport<S>,
+    metrics_options: &MetricsOptions,
+) -> io::Result<()> {
+    if let (true, Some(out_file)) = (report.skipped, &report.output) {
+        return writeln!(
+            out,
+            "Skipped, {} already exists",
             out_file.as_os_str().to_str().unwrap_or("<invalid UTF8>")
         );
+    }
+    if let Some(stats) = &report.stats {
+        let written_to = match &report.output {
+            Some(out_file) => out_file.as_os_str().to_str().unwrap_or("<invalid UTF8>"),
+            None => "stdout",
+        };
+        writeln!(out, "Finished: {}", stats)?;
+        writeln!(out, "Written to {}", written_to)?;
     } else {
-        match ff_result {
-            Ok(Ok(_)) => eprintln!("[Ffmpeg] Finished without errors."),
-            Ok(Err(e)) => eprintln!("[Ffmpeg] Errored: {}", e),
-            Err(_) => eprintln!("[Ffmpeg] Thread panicked."),
-        }
-        match task_result {
-            Ok(Ok(_)) => eprintln!("[Encoder] Finished without errors."),
-            Ok(Err(e)) => eprintln!("[Encoder] Errored: {}", e),
-            Err(_) => eprintln!("[Encoder] Thread panicked."),
+        for (stage, name) in [(Stage::Ffmpeg, "Ffmpeg"), (Stage::Encoder, "Encoder")] {
+            match report.error(stage) {
+                None => eprintln!("[{}] Finished without errors.", name),
+                Some(e) if e.is_panic() => eprintln!("[{}] Thread panicked.", name),
+                Some(e) => eprintln!("[{}] Errored: {}", name, e.message),
+            }
         }
         eprintln!("Some thread panicked or returned an error!");
-        std::process::exit(-1);
+        return Ok(());
+    }
+    if let Some(metrics) = &report.metrics {
+        writeln!(out, "Metrics: {}", metrics)?;
+        if metrics.frames().len() > 1 {
+            for frame in metrics.frames() {
+                writeln!(out, "  {}", frame)?;
+            }
+        }
+        if let Some(csv) = &metrics_options.metrics_csv {
+            writeln!(
+                out,
+                "Metrics written to {}",
+                csv.as_os_str().to_str().unwrap_or("<invalid UTF8>")
+            )?;
+        }
+    }
+    if let Some(e) = report.error(Stage::Metrics) {
+        eprintln!("Couldn't compute metrics: {}", e.message);
     }
-
     Ok(())
 }
+
+/// Where reports are printed, stderr if the output itself is written to stdout
+fn report_output(io_options: &IoOptions) -> Box<dyn Write> {
+    if io_options.output == STDIO_PATH {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+fn run_task<T>(
+    io_options: &IoOptions,
+    metrics_options: &MetricsOptions,
+    task_options: T::Options,
+    reporter: SharedReporter,
+    cancel: &CancellationToken,
+) -> Result<TaskReport<T::EncoderStats>, TaskError>
+where
+    T: EncoderTask,
+{
+    let conversion = Conversion::<T>::new(&io_options.input, task_options)
+        .metrics(metrics_options.enabled())
+        .reporter(reporter)
+        .overwrite(io_options.overwrite_policy())
+        .input_options(io_options.input_options())
+        .cancellation(cancel.clone());
+    let mut report = if io_options.output == STDIO_PATH {
+        let mut report = conversion.write_to(io::stdout())?;
+        if let Err(e) = io::stdout().flush() {
+            let error = TaskError::IoOutputFileError(e);
+            report.errors.push(ErrorReport::new(Stage::Task, &error));
+            report.success = false;
+        }
+        report
+    } else {
+        conversion.save(&io_options.output)?
+    };
+
+    if let (Some(metrics), Some(csv)) = (&report.metrics, &metrics_options.metrics_csv) {
+        if let Err(e) = metrics.write_csv(csv) {
+            report.errors.push(ErrorReport::new(Stage::Metrics, &e));
+            report.success = false;
+        }
+    }
+    Ok(report)
+}
+
+/// Reports the progress of all stages of one conversion.
+///
+/// `label` tells concurrent conversions apart in JSON lines.
+fn task_reporter(
+    mode: ProgressMode,
+    progress_manager: &MultiProgress,
+    label: Option<&str>,
+) -> SharedReporter {
+    match mode {
+        ProgressMode::Bar => Arc::new(TerminalProgress::new(progress_manager)),
+        ProgressMode::Json => Arc::new(label.map_or_else(JsonLines::default, JsonLines::labeled)),
+        ProgressMode::None => Arc::new(Silent),
+    }
+}
+
+/// Reports the progress of a single stage, on its own bar if progress bars are shown.
+fn stage_reporter(
+    mode: ProgressMode,
+    progress_manager: &MultiProgress,
+    label: &str,
+) -> SharedReporter {
+    match mode {
+        ProgressMode::Bar => Arc::new(progress_manager.add(progress::bar(label, 0))),
+        ProgressMode::Json => Arc::new(JsonLines::labeled(label)),
+        ProgressMode::None => Arc::new(Silent),
+    }
+}
@@ -0,0 +1,288 @@
+mod planes;
+
+use crate::{
+    ffmpeg::types::FrameData,
+    metrics::planes::{psnr, sse, ssim, Plane},
+};
+use crossbeam::channel::{Receiver, Sender};
+use ffmpeg_next::{frame, software::scaling};
+use std::{
+    fmt::{Display, Formatter},
+    fs, io,
+    io::Write,
+    path::Path,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("Couldn't decode the encoded output: {0}")]
+    Decode(Box<dyn std::error::Error + Send>),
+    #[error("The encoded output didn't contain any frames")]
+    NoDecodedFrames,
+    #[error("Couldn't convert a decoded frame: {0}")]
+    Ffmpeg(#[from] ffmpeg_next::Error),
+    #[error("Couldn't write CSV: {0}")]
+    WriteCsv(io::Error),
+    #[error("The input is too long to compare, its frames would take more than {0} bytes")]
+    ReferenceTooLarge(u64),
+}
+
+/// Upper bound for the copies of the input frames, the output is decoded completely as well
+const MAX_REFERENCE_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(serde::Serialize)]
+pub struct FrameMetrics {
+    pub index: usize,
+    pub timestamp_ms: i64,
+    /// Infinite for identical frames, see [`serialize_psnr`]
+    #[serde(serialize_with = "serialize_psnr")]
+    pub psnr: f64,
+    /// SSIM of the luma plane
+    pub ssim: f64,
+    #[serde(skip)]
+    sse: u64,
+    #[serde(skip)]
+    samples: u64,
+}
+
+pub struct MetricsReport {
+    frames: Vec<FrameMetrics>,
+}
+
+/// Forwards all frames from `frame_rx` to `frame_tx` and keeps a copy of every frame.
+///
+/// The output can only be compared once it's complete, so the copies are kept until then.
+/// Once they exceed [`MAX_REFERENCE_BYTES`], no more are kept and only the metrics fail,
+/// the frames are still forwarded to the encoder.
+pub fn collect_reference(
+    frame_rx: Receiver<FrameData>,
+    frame_tx: Sender<FrameData>,
+) -> Result<Vec<FrameData>, MetricsError> {
+    let mut reference = Some(Vec::new());
+    let mut bytes = 0;
+    for (frame, timing) in frame_rx {
+        bytes += (0..frame.planes())
+            .map(|plane| frame.stride(plane) as u64 * frame.plane_height(plane) as u64)
+            .sum::<u64>();
+        if bytes > MAX_REFERENCE_BYTES {
+            reference = None;
+        }
+        if let Some(reference) = &mut reference {
+            // a copy that doesn't hold on to the encoder's pooled frames
+            reference.push(((*frame).clone().into(), timing));
+        }
+        if frame_tx.send((frame, timing)).is_err() {
+            break;
+        }
+    }
+    reference.ok_or(MetricsError::ReferenceTooLarge(MAX_REFERENCE_BYTES))
+}
+
+/// Write a PSNR as a number, or as `"inf"` if the frames are identical (e.g. lossless).
+///
+/// JSON doesn't have infinite numbers, serde_json would write `null` instead.
+pub fn serialize_psnr<S: serde::Serializer>(psnr: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    if psnr.is_infinite() {
+        serializer.serialize_str("inf")
+    } else {
+        serializer.serialize_f64(*psnr)
+    }
+}
+
+/// A PSNR that's serialized with [`serialize_psnr`]
+struct Psnr(f64);
+
+impl serde::Serialize for Psnr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_psnr(&self.0, serializer)
+    }
+}
+
+impl MetricsReport {
+    /// Compare every `reference` frame with the `decoded` frame shown at the same time.
+    pub fn compute(reference: &[FrameData], decoded: &[FrameData]) -> Result<Self, MetricsError> {
+        let first_decoded_ms = decoded
+            .first()
+            .ok_or(MetricsError::NoDecodedFrames)?
+            .1
+            .ts_in_ms();
+        let first_reference_ms = reference.first().map_or(0, |(_, timing)| timing.ts_in_ms());
+
+        let mut converter = Converter::default();
+        let frames = reference
+            .iter()
+            .enumerate()
+            .map(|(index, (reference, timing))| {
+                let timestamp_ms = timing.ts_in_ms() - first_reference_ms;
+                let decoded_idx = decoded
+                    .partition_point(|(_, t)| t.ts_in_ms() - first_decoded_ms <= timestamp_ms)
+                    .max(1)
+                    - 1;
+                let decoded = converter.convert(&decoded[decoded_idx].0, reference)?;
+                Ok(compare(index, timestamp_ms, reference, decoded))
+            })
+            .collect::<Result<Vec<_>, MetricsError>>()?;
+
+        Ok(Self { frames })
+    }
+
+    pub fn frames(&self) -> &[FrameMetrics] {
+        &self.frames
+    }
+
+    /// PSNR over all frames (computed from the total error, not the mean of the per-frame PSNRs)
+    pub fn psnr(&self) -> f64 {
+        psnr(
+            self.frames.iter().map(|f| f.sse).sum(),
+            self.frames.iter().map(|f| f.samples).sum(),
+        )
+    }
+
+    pub fn mean_ssim(&self) -> f64 {
+        self.frames.iter().map(|f| f.ssim).sum::<f64>() / self.frames.len().max(1) as f64
+    }
+
+    pub fn min_psnr(&self) -> f64 {
+        self.frames
+            .iter()
+            .map(|f| f.psnr)
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    pub fn min_ssim(&self) -> f64 {
+        self.frames.iter().map(|f| f.ssim).fold(1.0, f64::min)
+    }
+
+    pub fn write_csv(&self, path: &Path) -> Result<(), MetricsError> {
+        let mut writer =
+            io::BufWriter::new(fs::File::create(path).map_err(MetricsError::WriteCsv)?);
+        let mut write = || -> io::Result<()> {
+            writeln!(writer, "frame,timestamp_ms,psnr,ssim")?;
+            for frame in &self.frames {
+                writeln!(
+                    writer,
+                    "{},{},{},{}",
+                    frame.index, frame.timestamp_ms, frame.psnr, frame.ssim
+                )?;
+            }
+            writer.flush()
+        };
+        write().map_err(MetricsError::WriteCsv)
+    }
+}
+
+impl serde::Serialize for MetricsReport {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("MetricsReport", 5)?;
+        state.serialize_field("psnr", &Psnr(self.psnr()))?;
+        state.serialize_field("min_psnr", &Psnr(self.min_psnr()))?;
+        state.serialize_field("ssim", &self.mean_ssim())?;
+        state.serialize_field("min_ssim", &self.min_ssim())?;
+        state.serialize_field("frames", &self.frames)?;
+        state.end()
+    }
+}
+
+impl Display for MetricsReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PSNR {:.2} dB (min {:.2} dB), SSIM {:.4} (min {:.4}) over {} frames",
+            self.psnr(),
+            self.min_psnr(),
+            self.mean_ssim(),
+            self.min_ssim(),
+            self.frames.len()
+        )
+    }
+}
+
+impl Display for FrameMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Frame {} ({} ms): PSNR {:.2} dB, SSIM {:.4}",
+            self.index, self.timestamp_ms, self.psnr, self.ssim
+        )
+    }
+}
+
+fn compare(
+    index: usize,
+    timestamp_ms: i64,
+    reference: &frame::Video,
+    decoded: &frame::Video,
+) -> FrameMetrics {
+    let (sse, samples) = (0..reference.planes())
+        .map(|i| {
+            let reference = Plane::of(reference, i);
+            (sse(&reference, &Plane::of(decoded, i)), reference.samples())
+        })
+        .fold((0, 0), |(total_sse, total_samples), (s, n)| {
+            (total_sse + s, total_samples + n)
+        });
+
+    FrameMetrics {
+        index,
+        timestamp_ms,
+        psnr: psnr(sse, samples),
+        ssim: ssim(&Plane::of(reference, 0), &Plane::of(decoded, 0)),
+        sse,
+        samples,
+    }
+}
+
+/// Converts decoded frames to the format and size of the reference frames.
+#[derive(Default)]
+struct Converter {
+    scaler: Option<scaling::Context>,
+    converted: Option<frame::Video>,
+}
+
+impl Converter {
+    fn convert<'a>(
+        &'a mut self,
+        decoded: &'a frame::Video,
+        reference: &frame::Video,
+    ) -> Result<&'a frame::Video, ffmpeg_next::Error> {
+        if decoded.format() == reference.format()
+            && decoded.width() == reference.width()
+            && decoded.height() == reference.height()
+        {
+            return Ok(decoded);
+        }
+
+        let input = scaling::context::Definition {
+            format: decoded.format(),
+            width: decoded.width(),
+            height: decoded.height(),
+        };
+        let output = scaling::context::Definition {
+            format: reference.format(),
+            width: reference.width(),
+            height: reference.height(),
+        };
+        let reusable = matches!(
+            &self.scaler,
+            Some(scaler) if *scaler.input() == input && *scaler.output() == output
+        );
+        if !reusable {
+            self.scaler = Some(scaling::Context::get(
+                input.format,
+                input.width,
+                input.height,
+                output.format,
+                output.width,
+                output.height,
+                scaling::Flags::BILINEAR,
+            )?);
+        }
+        // the scaler was created above
+        let scaler = self.scaler.as_mut().unwrap();
+
+        let converted = self.converted.insert(frame::Video::empty());
+        scaler.run(decoded, converted)?;
+        Ok(converted)
+    }
+}
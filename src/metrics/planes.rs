@@ -0,0 +1,156 @@
+use ffmpeg_next::frame;
+
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+pub struct Plane<'a> {
+    data: &'a [u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Plane<'a> {
+    pub fn of(frame: &'a frame::Video, index: usize) -> Self {
+        Self {
+            data: frame.data(index),
+            stride: frame.stride(index),
+            width: frame.plane_width(index) as usize,
+            height: frame.plane_height(index) as usize,
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        (self.width * self.height) as u64
+    }
+
+    fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+}
+
+/// Sum of squared errors between two planes of the same size
+pub fn sse(a: &Plane, b: &Plane) -> u64 {
+    (0..a.height)
+        .flat_map(|y| a.row(y).iter().zip(b.row(y)))
+        .map(|(&a, &b)| {
+            let diff = a as i64 - b as i64;
+            (diff * diff) as u64
+        })
+        .sum()
+}
+
+/// Mean SSIM over overlapping 8x8 windows (stepping by 4 pixels)
+pub fn ssim(a: &Plane, b: &Plane) -> f64 {
+    let window_w = SSIM_WINDOW.min(a.width);
+    let window_h = SSIM_WINDOW.min(a.height);
+    if window_w == 0 || window_h == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=a.height - window_h).step_by(SSIM_STEP) {
+        for x in (0..=a.width - window_w).step_by(SSIM_STEP) {
+            total += window_ssim(a, b, x, y, window_w, window_h);
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn window_ssim(a: &Plane, b: &Plane, x: usize, y: usize, w: usize, h: usize) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    for row in y..y + h {
+        for (&pa, &pb) in a.row(row)[x..x + w].iter().zip(&b.row(row)[x..x + w]) {
+            let (pa, pb) = (pa as u64, pb as u64);
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+
+    let n = (w * h) as f64;
+    let mean_a = sum_a as f64 / n;
+    let mean_b = sum_b as f64 / n;
+    let var_a = sum_aa as f64 / n - mean_a * mean_a;
+    let var_b = sum_bb as f64 / n - mean_b * mean_b;
+    let cov = sum_ab as f64 / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * cov + SSIM_C2))
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+}
+
+pub fn psnr(sse: u64, samples: u64) -> f64 {
+    if sse == 0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 * samples as f64 / sse as f64).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plane(data: &[u8], stride: usize, width: usize, height: usize) -> Plane {
+        Plane {
+            data,
+            stride,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn identical_planes() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let a = plane(&data, 16, 16, 16);
+        assert_eq!(sse(&a, &a), 0);
+        assert_eq!(psnr(0, a.samples()), f64::INFINITY);
+        assert!((ssim(&a, &a) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn constant_difference() {
+        let (a, b) = ([10; 16], [12; 16]);
+        let (a, b) = (plane(&a, 4, 4, 4), plane(&b, 4, 4, 4));
+        assert_eq!(sse(&a, &b), 16 * 4);
+        // 10 * log10(255² * 16 / 64)
+        assert!((psnr(sse(&a, &b), a.samples()) - 42.1102).abs() < 1e-3);
+    }
+
+    #[test]
+    fn maximum_error() {
+        assert_eq!(psnr(255 * 255, 1), 0.0);
+    }
+
+    #[test]
+    fn padding_is_ignored() {
+        // two rows of two pixels, the third byte of every row is padding
+        let a = [1, 2, 100, 3, 4, 100];
+        let b = [1, 2, 0, 3, 5, 0];
+        assert_eq!(sse(&plane(&a, 3, 2, 2), &plane(&b, 3, 2, 2)), 1);
+    }
+
+    #[test]
+    fn ssim_of_different_planes() {
+        let gradient = (0..64).map(|i| (i * 4) as u8).collect::<Vec<_>>();
+        let inverted = gradient.iter().map(|v| 255 - v).collect::<Vec<_>>();
+        let (a, b) = (plane(&gradient, 8, 8, 8), plane(&inverted, 8, 8, 8));
+        let value = ssim(&a, &b);
+        assert!(value < 0.0, "{}", value);
+        assert!((value - ssim(&b, &a)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ssim_of_small_and_empty_planes() {
+        let (a, b) = ([50; 6], [50; 6]);
+        assert!((ssim(&plane(&a, 3, 3, 2), &plane(&b, 3, 3, 2)) - 1.0).abs() < 1e-9);
+        assert_eq!(ssim(&plane(&[], 0, 0, 0), &plane(&[], 0, 0, 0)), 1.0);
+    }
+}
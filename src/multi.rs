@@ -0,0 +1,171 @@
+//! Decoding an input once and encoding it to multiple formats in parallel.
+use crate::{
+    cli::{MetricsOptions, MultiCommand, OutputFormat},
+    exit_on_failure, print_report, stage_reporter, CliError,
+};
+use emote_tool::{
+    avif::task::{AvifEncoderStats, AvifEncoderTask},
+    cancel::CancellationToken,
+    ffmpeg::input::STDIO_PATH,
+    options::OverwritePolicy,
+    pipeline::{run_ffmpeg, spawn_file_encoder},
+    progress::Progress,
+    report::{ErrorReport, Stage, TaskReport},
+    webp::task::{WebpEncoderStats, WebpEncoderTask},
+    EncoderTask, TaskError,
+};
+use indicatif::MultiProgress;
+use std::{io, path::PathBuf};
+
+#[derive(Default, serde::Serialize)]
+pub struct MultiReport {
+    pub success: bool,
+    pub avif: Option<TaskReport<AvifEncoderStats>>,
+    pub webp: Option<TaskReport<WebpEncoderStats>>,
+    /// Errors that occurred before any encoder was started
+    pub errors: Vec<ErrorReport>,
+}
+
+pub fn report_multi(command: MultiCommand, cancel: &CancellationToken) -> Result<(), CliError> {
+    // one output per format, they can't all go to stdout
+    if command.io.output == STDIO_PATH {
+        return Err(CliError::StdoutOutput("multi"));
+    }
+    let output_format = command.io.output_format;
+    let result = run_multi(command, cancel);
+    let report = match output_format {
+        OutputFormat::Text => {
+            let report = result?;
+            let metrics_options = MetricsOptions::default();
+            if let Some(avif) = &report.avif {
+                println!("AVIF:");
+                print_report(&mut io::stdout(), avif, &metrics_options)
+                    .map_err(CliError::PrintReportError)?;
+            }
+            if let Some(webp) = &report.webp {
+                println!("WebP:");
+                print_report(&mut io::stdout(), webp, &metrics_options)
+                    .map_err(CliError::PrintReportError)?;
+            }
+            report
+        }
+        OutputFormat::Json => {
+            let report = result.unwrap_or_else(|e| MultiReport {
+                errors: vec![ErrorReport::new(Stage::Task, &e)],
+                ..MultiReport::default()
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            report
+        }
+    };
+    exit_on_failure(report.success, cancel);
+    Ok(())
+}
+
+pub fn run_multi(
+    command: MultiCommand,
+    cancel: &CancellationToken,
+) -> Result<MultiReport, TaskError> {
+    let encode_all = !command.avif && !command.webp;
+    let overwrite = command.io.overwrite_policy();
+    // checked before any encoder is started, instead of failing after the first one
+    let existing = |selected: bool, path: PathBuf| {
+        (selected && overwrite != OverwritePolicy::Overwrite && path.exists()).then_some(path)
+    };
+    let avif_existing = existing(
+        command.avif || encode_all,
+        AvifEncoderTask::make_output_path(&command.io.output),
+    );
+    let webp_existing = existing(
+        command.webp || encode_all,
+        WebpEncoderTask::make_output_path(&command.io.output),
+    );
+    if overwrite == OverwritePolicy::Fail {
+        if let Some(path) = avif_existing.as_ref().or(webp_existing.as_ref()) {
+            return Err(TaskError::OutputExists(path.clone()));
+        }
+    }
+    // with --no-clobber, only the formats without an output are encoded
+    let avif_selected = (command.avif || encode_all) && avif_existing.is_none();
+    let webp_selected = (command.webp || encode_all) && webp_existing.is_none();
+    let avif_skipped = avif_existing.map(TaskReport::skipped);
+    let webp_skipped = webp_existing.map(TaskReport::skipped);
+    if !avif_selected && !webp_selected {
+        return Ok(MultiReport {
+            success: true,
+            avif: avif_skipped,
+            webp: webp_skipped,
+            errors: Vec::new(),
+        });
+    }
+
+    let input = command.io.open_input()?;
+    let frames = input.frames;
+    let dimensions = input.dimensions;
+
+    let progress_manager = MultiProgress::new();
+    let stage_progress = |stage: Stage, label: &str| {
+        let reporter = stage_reporter(command.io.progress, &progress_manager, label);
+        Progress::new(stage, frames, Some(reporter))
+    };
+    let ffmpeg_progress = stage_progress(Stage::Ffmpeg, "Ffmpeg");
+    let mut sinks = Vec::new();
+    let avif = if avif_selected {
+        let (sink, encoder) = spawn_file_encoder::<AvifEncoderTask>(
+            &command.io.output,
+            command.avif_opts,
+            &input,
+            false,
+            stage_progress(Stage::Encoder, "AVIF"),
+            overwrite,
+            cancel,
+        )?;
+        sinks.push(sink);
+        Some(encoder)
+    } else {
+        None
+    };
+    let webp = if webp_selected {
+        let started = spawn_file_encoder::<WebpEncoderTask>(
+            &command.io.output,
+            command.webp_opts,
+            &input,
+            false,
+            stage_progress(Stage::Encoder, "WebP"),
+            overwrite,
+            cancel,
+        );
+        match started {
+            Ok((sink, encoder)) => {
+                sinks.push(sink);
+                Some(encoder)
+            }
+            Err(e) => {
+                // without its sink, the AVIF encoder runs out of frames, joining it deletes its
+                // temporary file
+                drop(sinks);
+                let error = Err(ErrorReport::new(Stage::Task, &e));
+                if let Some(avif) = avif {
+                    avif.join(&error, dimensions);
+                }
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    let ff_result = run_ffmpeg(input, sinks, ffmpeg_progress, cancel);
+    let avif = avif
+        .map(|encoder| encoder.join(&ff_result, dimensions))
+        .or(avif_skipped);
+    let webp = webp
+        .map(|encoder| encoder.join(&ff_result, dimensions))
+        .or(webp_skipped);
+    Ok(MultiReport {
+        success: avif.iter().all(|r| r.success) && webp.iter().all(|r| r.success),
+        avif,
+        webp,
+        errors: Vec::new(),
+    })
+}
@@ -0,0 +1,260 @@
+//! Encoder options for every format.
+//!
+//! With the `cli` feature, these are also used as command line arguments.
+use crate::{avif, webp};
+use hex::FromHex;
+use std::{borrow::Cow, os::raw::c_int, str::FromStr};
+
+#[derive(Clone)]
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct AvifOptions {
+    #[cfg_attr(feature = "cli", clap(short, long, arg_enum, default_value_t = avif::Codec::Auto))]
+    pub codec: avif::Codec,
+    #[cfg_attr(feature = "cli", clap(long, default_value = "0"))]
+    pub quantizer: u8,
+    #[cfg_attr(feature = "cli", clap(long, default_value = "0"))]
+    pub quantizer_alpha: u8,
+    #[cfg_attr(feature = "cli", clap(long, default_value = "10"))]
+    pub speed: u8,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub max_threads: Option<usize>,
+    /// Multiply the color by alpha and mark the images as premultiplied
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub premultiply_alpha: bool,
+    /// Split animations at the input's keyframes into segments of at least this many frames
+    /// and encode them in parallel, as many at once as --max-threads allows
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub segment_frames: Option<usize>,
+}
+
+impl Default for AvifOptions {
+    fn default() -> Self {
+        Self {
+            codec: avif::Codec::Auto,
+            quantizer: 0,
+            quantizer_alpha: 0,
+            speed: 10,
+            max_threads: None,
+            premultiply_alpha: false,
+            segment_frames: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct WebpOptions {
+    // general encoder options
+    #[cfg_attr(feature = "cli", clap(long, arg_enum))]
+    pub preset: Option<webp::Preset>,
+
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub lossless: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub quality: Option<f32>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub method: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long, arg_enum))]
+    pub image_hint: Option<webp::ImageHint>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub target_size: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub target_psnr: Option<f32>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub segments: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub sns_strength: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub filter_strength: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub filter_sharpness: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub strong_filter: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub autofilter: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub alpha_compression: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long, arg_enum))]
+    pub alpha_filtering: Option<webp::AlphaFiltering>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub alpha_quality: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub pass: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub show_compressed: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long, arg_enum))]
+    pub preprocessing: Option<webp::PreprocessingFilter>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub partitions: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub partition_limit: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub emulate_jpeg_size: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub thread_level: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub low_memory: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub near_lossless: Option<c_int>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub exact: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub use_delta_palette: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub use_sharp_yuv: Option<bool>,
+
+    //anim encoder options
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub minimize_size: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub keyframe_distance: Option<webp::KeyframeDistance>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub allow_mixed: Option<bool>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub background_color: Option<BackgroundColor>,
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub loop_count: Option<i32>,
+}
+
+/// Timing of image sequence inputs (`frame_%04d.png` or `frames/*.png`)
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct SequenceOptions {
+    /// Frame rate of an image sequence input (default: 25)
+    #[cfg_attr(feature = "cli", clap(long, conflicts_with = "delays"))]
+    pub fps: Option<f64>,
+    /// Delays of the frames of an image sequence in milliseconds, the last one is repeated
+    /// (e.g. `--delays 100,50,50`)
+    #[cfg_attr(feature = "cli", clap(long, use_value_delimiter = true))]
+    pub delays: Vec<u32>,
+}
+
+impl SequenceOptions {
+    pub fn is_set(&self) -> bool {
+        self.fps.is_some() || !self.delays.is_empty()
+    }
+}
+
+/// Layout of sprite sheets, see [`crate::sprite`]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "cli", derive(clap::Args))]
+pub struct SpriteSheetOptions {
+    /// Frames per row (default: as many as rows)
+    #[cfg_attr(feature = "cli", clap(long, conflicts_with = "strip"))]
+    pub columns: Option<u32>,
+    /// Put all frames into a single row
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub strip: bool,
+    /// Transparent pixels between the frames
+    #[cfg_attr(feature = "cli", clap(long, default_value = "0"))]
+    pub padding: u32,
+    /// Quality of lossy WebP sheets (0-100), they're lossless if this isn't set
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub quality: Option<f32>,
+}
+
+/// Width and height in pixels, written as `112x112` (or `112` for a square)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Size {
+    type Err = Cow<'static, str>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s.split_once('x').unwrap_or((s, s));
+        match (width.parse::<u32>(), height.parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok(Self { width, height }),
+            _ => Err("Expected a size like 112x112 or 112".into()),
+        }
+    }
+}
+
+/// Sites the emotes are uploaded to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+pub enum Platform {
+    #[cfg_attr(feature = "cli", clap(name = "7tv"))]
+    SevenTv,
+    Bttv,
+    Ffz,
+}
+
+impl Platform {
+    /// The largest size the site shows emotes at (4x, or 3x for BTTV), wide emotes are
+    /// limited by the width
+    pub fn max_size(self) -> Size {
+        match self {
+            Self::SevenTv | Self::Ffz => Size {
+                width: 512,
+                height: 128,
+            },
+            Self::Bttv => Size {
+                width: 112,
+                height: 112,
+            },
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Cow<'static, str>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "7tv" => Ok(Self::SevenTv),
+            "bttv" => Ok(Self::Bttv),
+            "ffz" => Ok(Self::Ffz),
+            _ => Err("Expected 7tv, bttv or ffz".into()),
+        }
+    }
+}
+
+/// What happens if the output file already exists
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum OverwritePolicy {
+    /// Fail with [`crate::TaskError::OutputExists`]
+    Fail,
+    /// Replace the existing file once the new output is complete
+    #[default]
+    Overwrite,
+    /// Don't convert anything and report the task as skipped
+    Skip,
+}
+
+#[derive(Clone)]
+pub struct BackgroundColor(pub webp::Argb);
+
+impl FromStr for BackgroundColor {
+    type Err = Cow<'static, str>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix('#') {
+            match hex.len() {
+                6 => {
+                    let rgb = <[u8; 3]>::from_hex(hex).map_err(|e| e.to_string())?;
+                    Ok(Self([255, rgb[0], rgb[1], rgb[2]]))
+                }
+                8 => {
+                    let argb = <[u8; 4]>::from_hex(hex).map_err(|e| e.to_string())?;
+                    Ok(Self(argb))
+                }
+                _ => Err("Expected #abcdef, or #abcdef01".into()),
+            }
+        } else {
+            Err("Expected #abcdef, or #abcdef01".into())
+        }
+    }
+}
+#[macro_export]
+macro_rules! apply_options {
+    ($self:ident, $opts:ident; $($opt:ident,)*) => {
+            $(if let Some(v) = &$opts.$opt {
+                concat_idents::concat_idents!(fn_name = set_, $opt {
+                    $self.fn_name(*v);
+                });
+            })*
+    };
+}
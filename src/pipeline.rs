@@ -0,0 +1,567 @@
+//! The building blocks of a conversion: ffmpeg decodes the input on one thread
+//! and sends the frames to one or more encoder threads.
+use crate::{
+    cancel::CancellationToken,
+    ffmpeg,
+    ffmpeg::{
+        formats::AcceptedFormats,
+        frames::{
+            display_dimensions, extract_dimensions, extract_frames, extract_sample_aspect_ratio,
+        },
+        input::InputContext,
+        limits::Limits,
+        pool::FramePool,
+        transform::Orientation,
+        types::FrameData,
+        FrameProcessing, FrameSink,
+    },
+    metrics,
+    metrics::{MetricsError, MetricsReport},
+    options::{OverwritePolicy, SequenceOptions, Size},
+    progress::Progress,
+    report::{ErrorReport, Stage, TaskReport},
+    task::EncoderTask,
+    TaskError,
+};
+use std::{
+    fs,
+    fs::{File, OpenOptions},
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    thread::JoinHandle,
+};
+
+/// Upper bound for the frames queued for each encoder (unless a frame is larger than half of it)
+const MAX_QUEUED_BYTES: usize = 256 * 1024 * 1024;
+
+/// The opened input and the video stream that gets converted
+pub struct InputStream {
+    pub ctx: InputContext,
+    pub stream_idx: usize,
+    /// Estimated number of frames
+    pub frames: u64,
+    /// Dimensions of the frames sent to the encoders (with square pixels, after rotating them)
+    pub dimensions: Option<(u32, u32)>,
+    /// How the frames are resampled, rotated and preprocessed before they're encoded
+    pub processing: FrameProcessing,
+    /// Checked again for every decoded frame
+    pub limits: Limits,
+}
+
+/// An encoder running on its own thread, waiting for frames from its `FrameSink`
+pub struct EncoderThread<T: EncoderTask> {
+    output: Option<PendingOutput>,
+    written: Arc<Mutex<Written>>,
+    thread: JoinHandle<Result<T::EncoderStats, T::RunError>>,
+    reference_thread: Option<JoinHandle<Result<Vec<FrameData>, MetricsError>>>,
+}
+
+/// An output file that's written to a temporary file until the encoder succeeded
+struct PendingOutput {
+    path: PathBuf,
+    temp: PathBuf,
+    overwrite: OverwritePolicy,
+}
+
+#[derive(Default)]
+struct Written {
+    bytes: u64,
+    /// Copy of the output, only kept if it's needed to compute metrics
+    copy: Option<Vec<u8>>,
+}
+
+/// Counts the bytes written by an encoder and optionally keeps a copy of them
+struct TrackedWriter<W> {
+    inner: W,
+    written: Arc<Mutex<Written>>,
+}
+
+/// How an input is opened and prepared for the encoders, see [`InputStream::open_with`]
+#[derive(Clone)]
+pub struct InputOptions {
+    /// The video stream with this index instead of the best one
+    pub stream: Option<usize>,
+    /// See [`InputStream::autorotate`] (enabled by default)
+    pub autorotate: bool,
+    /// See [`InputStream::alpha_bleed`] (disabled by default)
+    pub alpha_bleed: bool,
+    pub limits: Limits,
+    /// See [`InputStream::fit`]
+    pub max_size: Option<Size>,
+    /// Read the input as an image sequence with this timing
+    pub sequence: Option<SequenceOptions>,
+    /// The input is a local file from an untrusted source, see [`InputContext::open_restricted`]
+    pub restricted: bool,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self {
+            stream: None,
+            autorotate: true,
+            alpha_bleed: false,
+            limits: Limits::default(),
+            max_size: None,
+            sequence: None,
+            restricted: false,
+        }
+    }
+}
+
+impl InputStream {
+    /// Open `input` (or the image sequence it's a pattern of) with all of the `options` applied.
+    pub fn open_with(input: &Path, options: &InputOptions) -> Result<Self, TaskError> {
+        let stream = match (&options.sequence, input.to_str()) {
+            _ if options.restricted => {
+                let ctx = InputContext::open_restricted(&input)
+                    .map_err(TaskError::FfmpegReadInputStream)?;
+                Self::with_stream(ctx, options.stream, options.limits)?
+            }
+            (Some(sequence), Some(pattern)) => {
+                Self::open_sequence(pattern, sequence, options.limits)?
+            }
+            _ => Self::open(&input, options.stream, options.limits)?,
+        };
+        let stream = stream
+            .autorotate(options.autorotate)
+            .alpha_bleed(options.alpha_bleed);
+        Ok(match options.max_size {
+            Some(max_size) => stream.fit(max_size),
+            None => stream,
+        })
+    }
+
+    /// Open `input` and select the video stream `stream`, or the best one if it's `None`.
+    ///
+    /// The input is rejected if it exceeds the `limits`.
+    pub fn open<P: AsRef<Path>>(
+        input: &P,
+        stream: Option<usize>,
+        limits: Limits,
+    ) -> Result<Self, TaskError> {
+        let (ctx, stream_idx) = ffmpeg::read_initial_stream(input, stream, &limits)
+            .map_err(TaskError::FfmpegReadInputStream)?;
+        Self::new(ctx, stream_idx, limits)
+    }
+
+    /// Open an image sequence, see [`InputContext::open_sequence`].
+    pub fn open_sequence(
+        pattern: &str,
+        options: &SequenceOptions,
+        limits: Limits,
+    ) -> Result<Self, TaskError> {
+        let ctx = InputContext::open_sequence(pattern, options)
+            .map_err(TaskError::FfmpegReadInputStream)?;
+        Self::with_stream(ctx, None, limits)
+    }
+
+    /// Select the video stream `stream` (or the best one) of an opened input and check it
+    fn with_stream(
+        ctx: InputContext,
+        stream: Option<usize>,
+        limits: Limits,
+    ) -> Result<Self, TaskError> {
+        let stream_idx =
+            ffmpeg::select_stream(&ctx, stream).map_err(TaskError::FfmpegReadInputStream)?;
+        limits
+            .check_stream(&ctx, stream_idx)
+            .map_err(TaskError::FfmpegReadInputStream)?;
+        Self::new(ctx, stream_idx, limits)
+    }
+
+    fn new(ctx: InputContext, stream_idx: usize, limits: Limits) -> Result<Self, TaskError> {
+        // stream_idx is always valid
+        let stream = ctx.stream(stream_idx).unwrap();
+        let frames = extract_frames(&stream, &ctx);
+        if frames < 0 {
+            return Err(TaskError::NoFrames);
+        }
+        let orientation = Orientation::from_stream(&stream);
+        let sample_aspect_ratio = extract_sample_aspect_ratio(&stream, &ctx);
+        let display_size =
+            extract_dimensions(&stream).map(|dims| display_dimensions(dims, sample_aspect_ratio));
+        let dimensions = display_size.map(|dims| orientation.apply_dimensions(dims));
+        Ok(Self {
+            frames: frames as u64,
+            dimensions,
+            processing: FrameProcessing {
+                display_size,
+                orientation,
+                ..FrameProcessing::default()
+            },
+            limits,
+            ctx,
+            stream_idx,
+        })
+    }
+
+    /// Rotate the frames according to the display matrix (the default)
+    /// or keep them as they're stored.
+    pub fn autorotate(mut self, autorotate: bool) -> Self {
+        if !autorotate && !self.processing.orientation.is_identity() {
+            self.dimensions = self.processing.display_size;
+            self.processing.orientation = Orientation::default();
+        }
+        self
+    }
+
+    /// Scale the frames down (never up) to fit into `max_size`, keeping their aspect ratio.
+    ///
+    /// This has to be called after [`autorotate`](Self::autorotate), the size applies
+    /// to the frames the encoders receive.
+    pub fn fit(mut self, max_size: Size) -> Self {
+        if let (Some((width, height)), Some((display_width, display_height))) =
+            (self.dimensions, self.processing.display_size)
+        {
+            let scale =
+                (max_size.width as f64 / width as f64).min(max_size.height as f64 / height as f64);
+            if scale < 1.0 {
+                let fitted = |size: u32| ((size as f64 * scale).round() as u32).max(1);
+                self.dimensions = Some((fitted(width), fitted(height)));
+                self.processing.display_size =
+                    Some((fitted(display_width), fitted(display_height)));
+            }
+        }
+        self
+    }
+
+    /// Fill the color of fully transparent pixels from the visible ones (disabled by default),
+    /// see [`ffmpeg::alpha::bleed`].
+    pub fn alpha_bleed(mut self, alpha_bleed: bool) -> Self {
+        self.processing.alpha_bleed = alpha_bleed;
+        self
+    }
+
+    pub fn stream(&self) -> ffmpeg_next::Stream {
+        // stream_idx is always valid
+        self.ctx.stream(self.stream_idx).unwrap()
+    }
+}
+
+/// Decode the input on a separate thread and send the frames to all `sinks`.
+pub fn run_ffmpeg(
+    input: InputStream,
+    sinks: Vec<FrameSink>,
+    progress: Progress,
+    cancel: &CancellationToken,
+) -> Result<u64, ErrorReport> {
+    let cancel = cancel.clone();
+    let ffmpeg_thread = thread::spawn(move || {
+        ffmpeg::emit_frames(
+            input.ctx,
+            input.stream_idx,
+            input.processing,
+            input.limits,
+            sinks,
+            progress,
+            &cancel,
+        )
+    });
+    match ffmpeg_thread.join() {
+        Ok(Ok(frames)) => Ok(frames),
+        Ok(Err(e)) => Err(ErrorReport::new(Stage::Ffmpeg, &e)),
+        Err(_) => Err(ErrorReport::panic(Stage::Ffmpeg)),
+    }
+}
+
+/// Start the encoder for an output file for `T` (the extension is chosen by the task).
+///
+/// The encoder writes to a temporary file in the same directory, which replaces the output
+/// once the conversion succeeded. Unless `overwrite` is [`OverwritePolicy::Overwrite`],
+/// an existing output is an error, even if it only appeared during the conversion.
+pub fn spawn_file_encoder<T>(
+    output_name: &str,
+    options: T::Options,
+    input: &InputStream,
+    collect_reference: bool,
+    progress: Progress,
+    overwrite: OverwritePolicy,
+    cancel: &CancellationToken,
+) -> Result<(FrameSink, EncoderThread<T>), TaskError>
+where
+    T: EncoderTask,
+{
+    let out_file = T::make_output_path(output_name);
+    if overwrite != OverwritePolicy::Overwrite && out_file.exists() {
+        return Err(TaskError::OutputExists(out_file));
+    }
+    let config = T::configure(options, &input.stream(), &input.ctx, input.dimensions)
+        .map_err(|e| TaskError::ConfigurationError(Box::new(e)))?;
+
+    let (writer, temp) = create_temp_file(&out_file).map_err(TaskError::IoOutputFileError)?;
+    let (sink, mut encoder) =
+        start_encoder::<T, _>(writer, config, input, collect_reference, progress, cancel);
+    encoder.output = Some(PendingOutput {
+        path: out_file,
+        temp,
+        overwrite,
+    });
+    Ok((sink, encoder))
+}
+
+/// Create a new hidden file next to `path` (e.g. `dir/.out.avif.1234-0.tmp`)
+fn create_temp_file(path: &Path) -> io::Result<(File, PathBuf)> {
+    let prefix = temp_file_prefix(path, process::id());
+    let mut attempt = 0;
+    loop {
+        let temp = path.with_file_name(format!("{}{}.tmp", prefix, attempt));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The start of the names of the temporary files the process `pid` creates for `path`
+fn temp_file_prefix(path: &Path, pid: u32) -> String {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!(".{}.{}-", file_name, pid)
+}
+
+/// Delete the temporary files the process `pid` left next to `path`, e.g. because it was killed.
+pub fn remove_temp_files(path: &Path, pid: u32) -> io::Result<()> {
+    let prefix = temp_file_prefix(path, pid);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(".tmp") {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a small file (e.g. an atlas) through a temporary file like the encoders' outputs.
+pub(crate) fn write_file<F>(path: &Path, overwrite: OverwritePolicy, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let (mut file, temp) = create_temp_file(path)?;
+    let result = write(&mut file).and_then(|()| {
+        drop(file);
+        publish(&temp, path, overwrite)
+    });
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Move the complete `temp` file to `path`, which must not exist unless `overwrite` is
+/// [`OverwritePolicy::Overwrite`].
+///
+/// The data is synced first, so a crash can't leave a truncated output behind. Linking fails
+/// atomically if `path` exists, unlike checking for it before renaming. Filesystems without
+/// hard links (e.g. FAT or some network mounts) fall back to that check.
+fn publish(temp: &Path, path: &Path, overwrite: OverwritePolicy) -> io::Result<()> {
+    OpenOptions::new().write(true).open(temp)?.sync_all()?;
+    if overwrite == OverwritePolicy::Overwrite {
+        return fs::rename(temp, path);
+    }
+    match fs::hard_link(temp, path) {
+        Ok(()) => {
+            // the output is complete even if the temporary name stays behind
+            fs::remove_file(temp).ok();
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) if !path.exists() => fs::rename(temp, path),
+        Err(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+    }
+}
+
+/// Configure `T` and start the encoder thread writing to `writer`.
+///
+/// If `collect_reference` is set, the frames are also kept to compute metrics.
+pub fn spawn_encoder<T, W>(
+    writer: W,
+    options: T::Options,
+    input: &InputStream,
+    collect_reference: bool,
+    progress: Progress,
+    cancel: &CancellationToken,
+) -> Result<(FrameSink, EncoderThread<T>), TaskError>
+where
+    T: EncoderTask,
+    W: Write + Send + 'static,
+{
+    let config = T::configure(options, &input.stream(), &input.ctx, input.dimensions)
+        .map_err(|e| TaskError::ConfigurationError(Box::new(e)))?;
+    Ok(start_encoder::<T, W>(
+        writer,
+        config,
+        input,
+        collect_reference,
+        progress,
+        cancel,
+    ))
+}
+
+fn start_encoder<T, W>(
+    writer: W,
+    config: T::Config,
+    input: &InputStream,
+    collect_reference: bool,
+    progress: Progress,
+    cancel: &CancellationToken,
+) -> (FrameSink, EncoderThread<T>)
+where
+    T: EncoderTask,
+    W: Write + Send + 'static,
+{
+    let written = Arc::new(Mutex::new(Written {
+        bytes: 0,
+        copy: collect_reference.then(Vec::new),
+    }));
+    let writer = TrackedWriter {
+        inner: writer,
+        written: written.clone(),
+    };
+
+    let is_single_frame = input.stream().frames() == 1;
+    let channel_size = if is_single_frame {
+        1
+    } else {
+        // large frames are queued less deeply, four bytes per pixel are a generous estimate
+        let frame_bytes = input
+            .dimensions
+            .map_or(0, |(width, height)| width as usize * height as usize * 4);
+        (MAX_QUEUED_BYTES / frame_bytes.max(1)).clamp(2, 20)
+    };
+    let (frame_tx, frame_rx) = crossbeam::channel::bounded(channel_size);
+    // the encoder receives the frames through the reference collector if metrics are enabled
+    let (frame_rx, reference_thread) = if collect_reference {
+        let (encoder_tx, encoder_rx) = crossbeam::channel::bounded(channel_size);
+        let reference_thread =
+            thread::spawn(move || metrics::collect_reference(frame_rx, encoder_tx));
+        (encoder_rx, Some(reference_thread))
+    } else {
+        (frame_rx, None)
+    };
+
+    let thread_cancel = cancel.clone();
+    let thread = thread::spawn(move || {
+        if is_single_frame {
+            T::run_still(writer, config, frame_rx, progress, &thread_cancel)
+        } else {
+            T::run_animation(writer, config, frame_rx, progress, &thread_cancel)
+        }
+    });
+
+    (
+        FrameSink {
+            accepted_formats: AcceptedFormats::for_task::<T>(),
+            frame_tx,
+            // the frames in the channel, plus the ones the encoder and the ffmpeg thread hold
+            pool: FramePool::new(channel_size + 2),
+        },
+        EncoderThread {
+            output: None,
+            written,
+            thread,
+            reference_thread,
+        },
+    )
+}
+
+impl<T: EncoderTask> EncoderThread<T> {
+    /// Wait for the encoder and collect its result (and metrics) into a report.
+    ///
+    /// An output file is only moved into place if decoding and encoding succeeded,
+    /// otherwise the temporary file is deleted.
+    pub fn join(
+        self,
+        ff_result: &Result<u64, ErrorReport>,
+        dimensions: Option<(u32, u32)>,
+    ) -> TaskReport<T::EncoderStats> {
+        let task_result = self.thread.join();
+        let reference = self.reference_thread.and_then(|thread| thread.join().ok());
+
+        let mut report = TaskReport {
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            ..TaskReport::default()
+        };
+        match ff_result {
+            Ok(frames) => report.frames = Some(*frames),
+            Err(e) => report.errors.push(e.clone()),
+        }
+        match task_result {
+            Ok(Ok(stats)) => report.stats = Some(stats),
+            Ok(Err(e)) => report.errors.push(ErrorReport::new(Stage::Encoder, &e)),
+            Err(_) => report.errors.push(ErrorReport::panic(Stage::Encoder)),
+        }
+        if let Some(output) = self.output {
+            if report.errors.is_empty() {
+                match publish(&output.temp, &output.path, output.overwrite) {
+                    Ok(()) => report.output = Some(output.path),
+                    Err(e) => {
+                        let error = match e.kind() {
+                            io::ErrorKind::AlreadyExists => TaskError::OutputExists(output.path),
+                            _ => TaskError::IoOutputFileError(e),
+                        };
+                        report.errors.push(ErrorReport::new(Stage::Task, &error));
+                    }
+                }
+            }
+            if report.output.is_none() {
+                // never leave a partial output behind
+                fs::remove_file(&output.temp).ok();
+            }
+        }
+        if !report.errors.is_empty() {
+            report.stats = None;
+            return report;
+        }
+
+        // the encoder thread is done, so nothing else holds the lock
+        let written = std::mem::take(&mut *self.written.lock().unwrap());
+        report.bytes = Some(written.bytes);
+        if let (Some(reference), Some(data)) = (reference, written.copy) {
+            match reference.and_then(|reference| compute_metrics::<T>(&data, &reference)) {
+                Ok(metrics) => report.metrics = Some(metrics),
+                Err(e) => report.errors.push(ErrorReport::new(Stage::Metrics, &e)),
+            }
+        }
+        report.success = report.errors.is_empty();
+
+        report
+    }
+}
+
+fn compute_metrics<T>(data: &[u8], reference: &[FrameData]) -> Result<MetricsReport, MetricsError>
+where
+    T: EncoderTask,
+{
+    let decoded = T::decode_output(data).map_err(|e| MetricsError::Decode(Box::new(e)))?;
+    MetricsReport::compute(reference, &decoded)
+}
+
+impl<W: Write> Write for TrackedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        let mut written = self.written.lock().unwrap();
+        written.bytes += len as u64;
+        if let Some(copy) = &mut written.copy {
+            copy.extend_from_slice(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
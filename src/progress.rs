@@ -0,0 +1,204 @@
+//! Progress reporting, independent of how (or whether) it's displayed.
+use crate::report::Stage;
+use std::{cell::Cell, io, io::Write, sync::Arc};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressUpdate {
+    /// The number of frames to process (this is an estimate for some inputs)
+    Length {
+        total: u64,
+    },
+    Frame {
+        position: u64,
+        total: u64,
+    },
+    /// All frames were processed, the output is being assembled
+    Finishing,
+    Finished,
+}
+
+/// Receives the progress of all stages of a conversion.
+///
+/// Reporters are called from the decoder and the encoder threads.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, stage: Stage, update: ProgressUpdate);
+}
+
+pub type SharedReporter = Arc<dyn ProgressReporter>;
+
+impl<R: ProgressReporter + ?Sized> ProgressReporter for Arc<R> {
+    fn report(&self, stage: Stage, update: ProgressUpdate) {
+        (**self).report(stage, update);
+    }
+}
+
+/// Calls a closure for every update
+pub struct CallbackReporter<F>(pub F);
+
+impl<F> ProgressReporter for CallbackReporter<F>
+where
+    F: Fn(Stage, ProgressUpdate) + Send + Sync,
+{
+    fn report(&self, stage: Stage, update: ProgressUpdate) {
+        (self.0)(stage, update);
+    }
+}
+
+/// Ignores all updates
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Silent;
+
+impl ProgressReporter for Silent {
+    fn report(&self, _: Stage, _: ProgressUpdate) {}
+}
+
+/// Writes every update as a single line of JSON to stderr,
+/// e.g. `{"label":"avif","stage":"encoder","event":"frame","position":3,"total":20}`
+#[derive(Debug, Clone, Default)]
+pub struct JsonLines {
+    label: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonLine<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    stage: Stage,
+    #[serde(flatten)]
+    update: ProgressUpdate,
+}
+
+impl JsonLines {
+    /// Include `label` in every line to tell multiple conversions apart
+    pub fn labeled<S: Into<String>>(label: S) -> Self {
+        Self {
+            label: Some(label.into()),
+        }
+    }
+}
+
+impl ProgressReporter for JsonLines {
+    fn report(&self, stage: Stage, update: ProgressUpdate) {
+        let line = JsonLine {
+            label: self.label.as_deref(),
+            stage,
+            update,
+        };
+        if let Ok(line) = serde_json::to_string(&line) {
+            // progress is best effort, a closed stderr shouldn't stop the conversion
+            writeln!(io::stderr().lock(), "{}", line).ok();
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+pub use self::terminal::{bar, TerminalProgress};
+
+#[cfg(feature = "cli")]
+mod terminal {
+    use super::{ProgressReporter, ProgressUpdate};
+    use crate::report::Stage;
+    use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+    /// A terminal progress bar for frames, labeled with `label`
+    pub fn bar(label: &str, frames: u64) -> ProgressBar {
+        ProgressBar::new(frames).with_style(
+            ProgressStyle::default_bar()
+                .template(&format!(
+                    "[{} {{spinner}}] Frame {{pos}}/{{len}} ({{percent}}%) {{bar:20}} {{elapsed}}/{{eta}} {{msg}}",
+                    label
+                ))
+                .unwrap(),
+        )
+    }
+
+    /// Shows every update on this bar, regardless of the stage
+    impl ProgressReporter for ProgressBar {
+        fn report(&self, _: Stage, update: ProgressUpdate) {
+            match update {
+                ProgressUpdate::Length { total } => self.set_length(total),
+                ProgressUpdate::Frame { position, .. } => self.set_position(position),
+                ProgressUpdate::Finishing => self.finish_with_message("Finishing..."),
+                ProgressUpdate::Finished => self.finish_and_clear(),
+            }
+        }
+    }
+
+    /// One terminal progress bar for the decoder and one for the encoder
+    pub struct TerminalProgress {
+        ffmpeg: ProgressBar,
+        encoder: ProgressBar,
+    }
+
+    impl TerminalProgress {
+        pub fn new(progress_manager: &MultiProgress) -> Self {
+            Self {
+                ffmpeg: progress_manager.add(bar("Ffmpeg", 0)),
+                encoder: progress_manager.add(bar("Encoder", 0)),
+            }
+        }
+    }
+
+    impl ProgressReporter for TerminalProgress {
+        fn report(&self, stage: Stage, update: ProgressUpdate) {
+            match stage {
+                Stage::Ffmpeg => self.ffmpeg.report(stage, update),
+                _ => self.encoder.report(stage, update),
+            }
+        }
+    }
+}
+
+/// Reports the progress of one stage
+pub struct Progress {
+    stage: Stage,
+    reporter: Option<SharedReporter>,
+    position: Cell<u64>,
+    total: Cell<u64>,
+}
+
+impl Progress {
+    pub fn new(stage: Stage, total: u64, reporter: Option<SharedReporter>) -> Self {
+        let progress = Self {
+            stage,
+            reporter,
+            position: Cell::new(0),
+            total: Cell::new(total),
+        };
+        progress.report(ProgressUpdate::Length { total });
+        progress
+    }
+
+    /// Progress that isn't reported anywhere
+    pub fn hidden(stage: Stage) -> Self {
+        Self::new(stage, 0, None)
+    }
+
+    pub fn set_length(&self, total: u64) {
+        self.total.set(total);
+        self.report(ProgressUpdate::Length { total });
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.position.set(self.position.get() + delta);
+        self.report(ProgressUpdate::Frame {
+            position: self.position.get(),
+            total: self.total.get(),
+        });
+    }
+
+    pub fn finishing(&self) {
+        self.report(ProgressUpdate::Finishing);
+    }
+
+    pub fn finish(&self) {
+        self.report(ProgressUpdate::Finished);
+    }
+
+    fn report(&self, update: ProgressUpdate) {
+        if let Some(reporter) = &self.reporter {
+            reporter.report(self.stage, update);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Outcome of an encoder task, printed as text or as a single JSON document.
+use crate::metrics::MetricsReport;
+use std::{error::Error, iter, path::PathBuf};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Opening the input, configuring the encoder or creating the output
+    Task,
+    Ffmpeg,
+    Encoder,
+    Metrics,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ErrorReport {
+    pub stage: Stage,
+    /// Name of the error variant (e.g. `NoPixelFormat`), `Panic` if the thread panicked
+    pub kind: String,
+    pub message: String,
+    /// Messages of the underlying errors, outermost first
+    pub causes: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TaskReport<S> {
+    pub success: bool,
+    /// The output already existed and wasn't overwritten (see [`crate::options::OverwritePolicy::Skip`])
+    pub skipped: bool,
+    pub output: Option<PathBuf>,
+    /// Size of the output file
+    pub bytes: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames decoded by ffmpeg and sent to the encoder
+    pub frames: Option<u64>,
+    /// Encoder settings and statistics
+    pub stats: Option<S>,
+    pub metrics: Option<MetricsReport>,
+    pub errors: Vec<ErrorReport>,
+}
+
+const PANIC_KIND: &str = "Panic";
+
+impl ErrorReport {
+    pub fn new<E: Error + ?Sized>(stage: Stage, error: &E) -> Self {
+        // all error enums derive `Debug`, so the variant name is the leading identifier
+        let debug = format!("{:?}", error);
+        let kind = debug
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        Self {
+            stage,
+            kind,
+            message: error.to_string(),
+            causes: iter::successors(error.source(), |&e| e.source())
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+
+    pub fn panic(stage: Stage) -> Self {
+        Self {
+            stage,
+            kind: PANIC_KIND.to_string(),
+            message: "Thread panicked.".to_string(),
+            causes: Vec::new(),
+        }
+    }
+
+    pub fn is_panic(&self) -> bool {
+        self.kind == PANIC_KIND
+    }
+}
+
+impl<S> Default for TaskReport<S> {
+    fn default() -> Self {
+        Self {
+            success: false,
+            skipped: false,
+            output: None,
+            bytes: None,
+            width: None,
+            height: None,
+            frames: None,
+            stats: None,
+            metrics: None,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl<S> TaskReport<S> {
+    /// Report of a task that failed before any thread was started
+    pub fn failed(error: ErrorReport) -> Self {
+        Self {
+            errors: vec![error],
+            ..Self::default()
+        }
+    }
+
+    /// Report of a task that wasn't run because `output` already exists
+    pub fn skipped(output: PathBuf) -> Self {
+        Self {
+            success: true,
+            skipped: true,
+            output: Some(output),
+            ..Self::default()
+        }
+    }
+
+    pub fn error(&self, stage: Stage) -> Option<&ErrorReport> {
+        self.errors.iter().find(|e| e.stage == stage)
+    }
+}
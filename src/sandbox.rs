@@ -0,0 +1,278 @@
+//! Running a conversion in a worker process with a timeout and resource limits.
+//!
+//! The worker is this executable started with the same arguments and the write end of a
+//! pipe in the hidden `--result-fd` argument, which only the parent sets. It converts the input like without `--sandbox`
+//! and sends the report as JSON over the pipe. A worker that hangs is killed,
+//! one that crashes only fails its own conversion.
+use crate::{
+    cli::{IoOptions, MetricsOptions, OutputFormat, SandboxOptions},
+    exit_on_failure, print_report, report_output, run_task, task_reporter, CliError,
+};
+use emote_tool::{
+    cancel::CancellationToken,
+    ffmpeg::input::STDIO_PATH,
+    pipeline::remove_temp_files,
+    report::{ErrorReport, Stage, TaskReport},
+    EncoderTask, TaskError,
+};
+use indicatif::MultiProgress;
+use std::{
+    env,
+    fmt::Display,
+    fs::File,
+    io,
+    io::{Read, Write},
+    path::Path,
+    process::{Child, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the parent checks whether the worker exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("Couldn't start the worker process: {0}")]
+    Spawn(io::Error),
+    #[error("Couldn't wait for the worker process: {0}")]
+    Wait(io::Error),
+    #[error("The worker didn't finish within {0} seconds and was killed")]
+    Timeout(f64),
+    #[error("--result-fd {0} isn't the write end of a pipe")]
+    InvalidResultFd(i32),
+    #[error("The worker exited without a report ({0})")]
+    NoReport(ExitStatus),
+    #[error("Couldn't read the worker's report: {0}")]
+    InvalidReport(serde_json::Error),
+    #[cfg(not(unix))]
+    #[error("Sandboxed workers are only supported on unix")]
+    Unsupported,
+}
+
+/// Convert in a worker process and print its report like [`crate::report_task`] would.
+///
+/// In text mode the worker prints the report itself, only failures of the worker
+/// (e.g. the timeout) are reported here.
+pub fn report_sandboxed<T: EncoderTask>(
+    io_options: &IoOptions,
+    options: &SandboxOptions,
+    cancel: &CancellationToken,
+) -> Result<(), CliError> {
+    let mut out = report_output(io_options);
+    // the worker's partial output has to be removed if it doesn't finish
+    let output = (io_options.output != STDIO_PATH).then(|| T::make_output_path(&io_options.output));
+    let success = match (
+        run_worker(options, output.as_deref()),
+        io_options.output_format,
+    ) {
+        (Ok(report), OutputFormat::Text) => report["success"].as_bool().unwrap_or_default(),
+        (Ok(report), OutputFormat::Json) => {
+            writeln!(out, "{}", serde_json::to_string_pretty(&report)?)
+                .map_err(CliError::PrintReportError)?;
+            report["success"].as_bool().unwrap_or_default()
+        }
+        (Err(e), OutputFormat::Text) => return Err(e.into()),
+        (Err(e), OutputFormat::Json) => {
+            let report = TaskReport::<()>::failed(ErrorReport::new(Stage::Task, &e));
+            writeln!(out, "{}", serde_json::to_string_pretty(&report)?)
+                .map_err(CliError::PrintReportError)?;
+            false
+        }
+    };
+    exit_on_failure(success, cancel);
+    Ok(())
+}
+
+/// Convert the input as a worker and send the report to the parent through `pipe`.
+pub fn run_as_worker<T>(
+    pipe: File,
+    io_options: &IoOptions,
+    metrics_options: &MetricsOptions,
+    task_options: T::Options,
+    cancel: &CancellationToken,
+) -> Result<(), CliError>
+where
+    T: EncoderTask,
+{
+    let result = run_task::<T>(
+        io_options,
+        metrics_options,
+        task_options,
+        task_reporter(io_options.progress, &MultiProgress::new(), None),
+        cancel,
+    );
+    let success = send_report(pipe, io_options, metrics_options, result)?;
+    exit_on_failure(success, cancel);
+    Ok(())
+}
+
+fn send_report<S>(
+    pipe: File,
+    io_options: &IoOptions,
+    metrics_options: &MetricsOptions,
+    result: Result<TaskReport<S>, TaskError>,
+) -> Result<bool, CliError>
+where
+    S: Display + serde::Serialize,
+{
+    let report = match result {
+        Ok(report) => {
+            if io_options.output_format == OutputFormat::Text {
+                print_report(&mut report_output(io_options), &report, metrics_options)
+                    .map_err(CliError::PrintReportError)?;
+            }
+            report
+        }
+        Err(e) => {
+            if io_options.output_format == OutputFormat::Text {
+                eprintln!("Error: {}", e);
+            }
+            TaskReport::failed(ErrorReport::new(Stage::Task, &e))
+        }
+    };
+    serde_json::to_writer(pipe, &report)?;
+    Ok(report.success)
+}
+
+/// The write end of the report pipe, if this process is a worker (`result_fd` is set).
+///
+/// The descriptor is checked before it's used, it could be any file otherwise.
+pub fn result_pipe(result_fd: Option<i32>) -> Result<Option<File>, SandboxError> {
+    match result_fd {
+        Some(fd) if is_pipe(fd) => Ok(open_fd(fd)),
+        Some(fd) => Err(SandboxError::InvalidResultFd(fd)),
+        None => Ok(None),
+    }
+}
+
+/// Start the worker, wait for it (at most for the timeout) and read its report.
+///
+/// A worker that's killed or crashes can't delete the temporary file of its `output` itself.
+fn run_worker(
+    options: &SandboxOptions,
+    output: Option<&Path>,
+) -> Result<serde_json::Value, SandboxError> {
+    let (mut child, mut reader) = spawn_worker(options)?;
+    // read concurrently, the worker would block on a full pipe otherwise
+    let report_thread = thread::spawn(move || {
+        let mut report = Vec::new();
+        reader.read_to_end(&mut report).map(|_| report)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(SandboxError::Wait)? {
+            break status;
+        }
+        if let Some(timeout) = options.timeout {
+            if started.elapsed().as_secs_f64() >= timeout {
+                // the pipe is closed once the worker is gone, so the reader stops as well
+                child.kill().ok();
+                child.wait().map_err(SandboxError::Wait)?;
+                remove_worker_files(&child, output);
+                return Err(SandboxError::Timeout(timeout));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    match report_thread.join() {
+        Ok(Ok(report)) if !report.is_empty() => {
+            serde_json::from_slice(&report).map_err(SandboxError::InvalidReport)
+        }
+        _ => {
+            remove_worker_files(&child, output);
+            Err(SandboxError::NoReport(status))
+        }
+    }
+}
+
+/// Delete the temporary files of an exited worker
+fn remove_worker_files(child: &Child, output: Option<&Path>) {
+    if let Some(output) = output {
+        remove_temp_files(output, child.id()).ok();
+    }
+}
+
+#[cfg(unix)]
+fn spawn_worker(options: &SandboxOptions) -> Result<(Child, File), SandboxError> {
+    use std::{os::unix::process::CommandExt, process::Command};
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(SandboxError::Spawn(io::Error::last_os_error()));
+    }
+    let [read_fd, write_fd] = fds;
+    // only the write end is inherited
+    unsafe { libc::fcntl(read_fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    let (reader, writer) = (open_fd(read_fd).unwrap(), open_fd(write_fd).unwrap());
+
+    let limits = [
+        (libc::RLIMIT_AS, options.max_memory),
+        (libc::RLIMIT_CPU, options.max_cpu_time),
+        // a crashing worker shouldn't leave a core dump of the input behind
+        (libc::RLIMIT_CORE, Some(0)),
+    ];
+    let exe = env::current_exe().map_err(SandboxError::Spawn)?;
+    let mut command = Command::new(exe);
+    // before the subcommand, so it can't end up after a `--`
+    command
+        .arg("--result-fd")
+        .arg(write_fd.to_string())
+        .args(env::args_os().skip(1));
+    unsafe {
+        // only async-signal-safe calls between fork and exec
+        command.pre_exec(move || {
+            for (resource, limit) in limits {
+                if let Some(limit) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().map_err(SandboxError::Spawn)?;
+    // the reader only sees the end of the report once the worker's end is the last one
+    drop(writer);
+    Ok((child, reader))
+}
+
+#[cfg(not(unix))]
+fn spawn_worker(_options: &SandboxOptions) -> Result<(Child, File), SandboxError> {
+    Err(SandboxError::Unsupported)
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Option<File> {
+    use std::os::unix::io::FromRawFd;
+
+    (fd >= 0).then(|| unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Option<File> {
+    None
+}
+
+/// Whether `fd` is open and a pipe
+#[cfg(unix)]
+fn is_pipe(fd: i32) -> bool {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    unsafe {
+        libc::fcntl(fd, libc::F_GETFD) != -1
+            && libc::fstat(fd, stat.as_mut_ptr()) == 0
+            && stat.assume_init().st_mode & libc::S_IFMT == libc::S_IFIFO
+    }
+}
+
+#[cfg(not(unix))]
+fn is_pipe(_fd: i32) -> bool {
+    false
+}
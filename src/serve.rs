@@ -0,0 +1,502 @@
+//! Converting uploaded files over HTTP with a bounded job queue.
+//!
+//! `POST /convert?format=webp&quality=80` with the file as the body responds with a
+//! `multipart/mixed` body: the report (stats and metrics) as JSON, then the encoded file.
+//! If the conversion fails, the report is the body of a `422` response.
+//!
+//! `size=112x112` scales the frames down to fit into that size, `platform=7tv` (or `bttv`, `ffz`)
+//! to the largest size the site shows emotes at.
+//!
+//! Uploads are opened with [`InputOptions::restricted`], so they can't make ffmpeg read other
+//! files or URLs, and conversions that take longer than `--timeout` are cancelled.
+use crate::{auto::apply_target_quality, cli::ServeCommand, CliError};
+use crossbeam::channel::{self, RecvTimeoutError, Sender, TrySendError};
+use emote_tool::{
+    avif::task::AvifEncoderTask,
+    cancel::CancellationToken,
+    ffmpeg::limits::Limits,
+    options::{AvifOptions, Platform, Size, WebpOptions},
+    pipeline::InputOptions,
+    report::{ErrorReport, Stage, TaskReport},
+    webp::task::WebpEncoderTask,
+    Conversion, EncoderTask,
+};
+use std::{
+    env, fs,
+    fs::{DirBuilder, OpenOptions},
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Uploads that take longer between two reads are dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longer lines of the request head are cut off
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// How often the listener checks for Ctrl+C while there are no connections
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Avif,
+    Webp,
+}
+
+/// The options in the query of a request
+struct JobOptions {
+    format: Format,
+    quality: Option<u8>,
+    max_size: Option<Size>,
+}
+
+/// A conversion waiting for a free job
+struct Job {
+    input: PathBuf,
+    options: JobOptions,
+    reply: Sender<Response>,
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+/// The options every conversion starts from, shared by all jobs
+struct Defaults {
+    avif_opts: AvifOptions,
+    webp_opts: WebpOptions,
+    limits: Limits,
+    timeout: Duration,
+}
+
+/// Accept connections until Ctrl+C is pressed, at most `jobs` conversions run at once.
+pub fn serve(command: ServeCommand, cancel: &CancellationToken) -> Result<(), CliError> {
+    let listener = TcpListener::bind(&command.listen).map_err(CliError::ServeError)?;
+    // non-blocking, so Ctrl+C stops the server even if nobody connects
+    listener
+        .set_nonblocking(true)
+        .map_err(CliError::ServeError)?;
+    eprintln!(
+        "Listening on http://{}",
+        listener.local_addr().map_err(CliError::ServeError)?
+    );
+
+    let upload_dir = create_upload_dir().map_err(CliError::ServeError)?;
+    let defaults = Arc::new(Defaults {
+        avif_opts: command.avif_opts,
+        webp_opts: command.webp_opts,
+        limits: command.limits,
+        timeout: Duration::from_secs_f64(command.timeout),
+    });
+    let (job_tx, job_rx) = channel::bounded::<Job>(command.queue);
+    let workers = (0..command.jobs.max(1))
+        .map(|_| {
+            let job_rx = job_rx.clone();
+            let defaults = defaults.clone();
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                for job in job_rx {
+                    let response = convert(&job, &defaults, &cancel);
+                    job.reply.send(response).ok();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // connections that are uploading or waiting, one more than the jobs and the queue is rejected
+    let connections = Arc::new(AtomicUsize::new(0));
+    let max_connections = command.jobs.max(1) + command.queue;
+    let mut uploads = 0;
+    while !cancel.is_cancelled() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Couldn't accept a connection: {}", e);
+                continue;
+            }
+        };
+        uploads += 1;
+        let upload = upload_dir.join(format!("upload-{}", uploads));
+        let connections = connections.clone();
+        let job_tx = job_tx.clone();
+        let max_upload = command.max_upload;
+        thread::spawn(move || {
+            let busy = connections.fetch_add(1, Ordering::SeqCst) >= max_connections;
+            if let Err(e) = handle_connection(stream, &upload, max_upload, busy, &job_tx) {
+                eprintln!("Connection failed: {}", e);
+            }
+            fs::remove_file(&upload).ok();
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    // the running conversions are cancelled as well, the workers stop once the queue is empty
+    drop(job_tx);
+    for worker in workers {
+        worker.join().ok();
+    }
+    fs::remove_dir_all(&upload_dir).ok();
+    Ok(())
+}
+
+/// A new directory in the temp directory for the uploads, only this user can access it
+fn create_upload_dir() -> io::Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    let mut attempt = 0;
+    loop {
+        let dir = env::temp_dir().join(format!("emote-tool-serve-{}-{}", process::id(), attempt));
+        // fails for existing directories and symlinks, someone else could control those
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    upload: &Path,
+    max_upload: u64,
+    busy: bool,
+    job_tx: &Sender<Job>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let response = if busy {
+        Response::error(
+            503,
+            "Service Unavailable",
+            "Too many conversions are queued",
+        )
+    } else {
+        match receive_job(&mut reader, &mut writer, upload, max_upload) {
+            Ok(options) => submit(job_tx, upload, options),
+            Err(response) => response,
+        }
+    };
+    response.write_to(&mut writer)
+}
+
+/// Read the request, the uploaded file is written to `upload`.
+fn receive_job(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut TcpStream,
+    upload: &Path,
+    max_upload: u64,
+) -> Result<JobOptions, Response> {
+    let bad_request = |message: &str| Response::error(400, "Bad Request", message);
+    let request_line = read_line(reader).ok_or_else(|| bad_request("Couldn't read the request"))?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(bad_request("Malformed request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/convert" {
+        return Err(Response::error(404, "Not Found", "Only /convert exists"));
+    }
+    if method != "POST" {
+        return Err(Response::error(
+            405,
+            "Method Not Allowed",
+            "Upload the file with POST",
+        ));
+    }
+    let options = parse_query(query).map_err(|message| bad_request(&message))?;
+
+    let mut content_length = None;
+    let mut expects_continue = false;
+    loop {
+        let header = read_line(reader).ok_or_else(|| bad_request("Couldn't read the headers"))?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse::<u64>().ok(),
+                "expect" => expects_continue = value.trim().eq_ignore_ascii_case("100-continue"),
+                _ => (),
+            }
+        }
+    }
+    let length = content_length.ok_or_else(|| {
+        Response::error(
+            411,
+            "Length Required",
+            "Send the file with a Content-Length",
+        )
+    })?;
+    if length > max_upload {
+        return Err(Response::error(
+            413,
+            "Payload Too Large",
+            &format!("Uploads are limited to {} bytes", max_upload),
+        ));
+    }
+    // clients like curl wait for this before sending large bodies
+    if expects_continue {
+        writer
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|_| bad_request("Couldn't answer the request"))?;
+    }
+
+    let internal_error =
+        |_| Response::error(500, "Internal Server Error", "Couldn't store the upload");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(upload)
+        .map_err(internal_error)?;
+    let received = io::copy(&mut reader.take(length), &mut file).map_err(internal_error)?;
+    if received < length {
+        return Err(bad_request("The upload is shorter than its Content-Length"));
+    }
+    Ok(options)
+}
+
+/// A line of the request head, longer lines are cut off
+fn read_line(reader: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut line = String::new();
+    reader
+        .take(MAX_LINE_LENGTH)
+        .read_line(&mut line)
+        .ok()
+        .map(|_| line)
+}
+
+/// `format` (`avif` or `webp`, required), `quality` (0-100), `size` (`112x112`)
+/// and `platform` (`7tv`, `bttv` or `ffz`)
+fn parse_query(query: &str) -> Result<JobOptions, String> {
+    let mut format = None;
+    let mut quality = None;
+    let mut size = None;
+    let mut platform = None;
+    for (name, value) in query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+    {
+        match name {
+            "format" => {
+                format = Some(match value {
+                    "avif" => Format::Avif,
+                    "webp" => Format::Webp,
+                    _ => return Err(format!("Unknown format {:?}, expected avif or webp", value)),
+                })
+            }
+            "quality" => {
+                quality = Some(
+                    value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&quality| quality <= 100)
+                        .ok_or_else(|| format!("Invalid quality {:?}, expected 0-100", value))?,
+                )
+            }
+            "size" => {
+                size = Some(
+                    value
+                        .parse::<Size>()
+                        .map_err(|e| format!("Invalid size {:?}: {}", value, e))?,
+                )
+            }
+            "platform" => {
+                platform = Some(
+                    value
+                        .parse::<Platform>()
+                        .map_err(|e| format!("Invalid platform {:?}: {}", value, e))?,
+                )
+            }
+            _ => return Err(format!("Unknown option {:?}", name)),
+        }
+    }
+    let format = format.ok_or("The format is missing (format=avif or format=webp)")?;
+    Ok(JobOptions {
+        format,
+        quality,
+        // like --resize and --platform, an explicit size wins
+        max_size: size.or_else(|| platform.map(Platform::max_size)),
+    })
+}
+
+/// Queue the conversion and wait for its response
+fn submit(job_tx: &Sender<Job>, upload: &Path, options: JobOptions) -> Response {
+    let (reply, response_rx) = channel::bounded(1);
+    let job = Job {
+        input: upload.to_path_buf(),
+        options,
+        reply,
+    };
+    match job_tx.try_send(job) {
+        Ok(()) => response_rx.recv().unwrap_or_else(|_| {
+            Response::error(500, "Internal Server Error", "The conversion was aborted")
+        }),
+        Err(TrySendError::Full(_)) => Response::error(
+            503,
+            "Service Unavailable",
+            "Too many conversions are queued",
+        ),
+        Err(TrySendError::Disconnected(_)) => {
+            Response::error(503, "Service Unavailable", "The server is shutting down")
+        }
+    }
+}
+
+fn convert(job: &Job, defaults: &Defaults, cancel: &CancellationToken) -> Response {
+    let mut avif_opts = defaults.avif_opts.clone();
+    let mut webp_opts = defaults.webp_opts.clone();
+    if let Some(quality) = job.options.quality {
+        apply_target_quality(quality, &mut avif_opts, &mut webp_opts);
+    }
+    let input_options = InputOptions {
+        limits: defaults.limits,
+        max_size: job.options.max_size,
+        restricted: true,
+        ..InputOptions::default()
+    };
+
+    // cancelled when the server stops or once the conversion takes too long
+    let job_cancel = CancellationToken::default();
+    let (done_tx, done_rx) = channel::bounded::<()>(0);
+    let watchdog = {
+        let job_cancel = job_cancel.clone();
+        let cancel = cancel.clone();
+        let deadline = Instant::now() + defaults.timeout;
+        thread::spawn(move || loop {
+            match done_rx.recv_timeout(ACCEPT_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) if cancel.is_cancelled() => {
+                    job_cancel.cancel();
+                    return false;
+                }
+                Err(RecvTimeoutError::Timeout) if Instant::now() >= deadline => {
+                    job_cancel.cancel();
+                    return true;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => return false,
+            }
+        })
+    };
+    let response = match job.options.format {
+        Format::Avif => convert_with::<AvifEncoderTask>(job, avif_opts, input_options, &job_cancel),
+        Format::Webp => convert_with::<WebpEncoderTask>(job, webp_opts, input_options, &job_cancel),
+    };
+    drop(done_tx);
+    if watchdog.join().unwrap_or_default() {
+        return Response::error(
+            422,
+            "Unprocessable Entity",
+            &format!(
+                "The conversion took longer than {} seconds and was cancelled",
+                defaults.timeout.as_secs_f64()
+            ),
+        );
+    }
+    response
+}
+
+fn convert_with<T: EncoderTask>(
+    job: &Job,
+    options: T::Options,
+    input_options: InputOptions,
+    cancel: &CancellationToken,
+) -> Response {
+    let result = Conversion::<T>::new(&job.input, options)
+        .input_options(input_options)
+        .cancellation(cancel.clone())
+        .encode();
+    let content_type = match job.options.format {
+        Format::Avif => "image/avif",
+        Format::Webp => "image/webp",
+    };
+    match result {
+        Ok((data, report)) if report.success => {
+            let report = serde_json::to_vec(&report).unwrap_or_default();
+            let (content_type, body) = multipart(&report, content_type, &data);
+            Response {
+                status: 200,
+                reason: "OK",
+                content_type,
+                body,
+            }
+        }
+        Ok((_, report)) => Response::json(422, "Unprocessable Entity", &report),
+        Err(e) => Response::json(
+            422,
+            "Unprocessable Entity",
+            &TaskReport::<()>::failed(ErrorReport::new(Stage::Task, &e)),
+        ),
+    }
+}
+
+/// A `multipart/mixed` body with the JSON `report` and the encoded `data`, and its content type
+fn multipart(report: &[u8], content_type: &str, data: &[u8]) -> (String, Vec<u8>) {
+    let contains = |part: &[u8], boundary: &str| {
+        part.windows(boundary.len())
+            .any(|window| window == boundary.as_bytes())
+    };
+    // the boundary must not appear in either part
+    let boundary = (0..)
+        .map(|attempt| format!("emote-tool-part-{}", attempt))
+        .find(|boundary| !contains(report, boundary) && !contains(data, boundary))
+        .unwrap();
+    let mut body = Vec::with_capacity(report.len() + data.len() + 256);
+    for (part_type, part) in [("application/json", report), (content_type, data)] {
+        body.extend_from_slice(
+            format!("--{}\r\nContent-Type: {}\r\n\r\n", boundary, part_type).as_bytes(),
+        );
+        body.extend_from_slice(part);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/mixed; boundary={}", boundary), body)
+}
+
+impl Response {
+    fn json<S: serde::Serialize>(status: u16, reason: &'static str, body: &S) -> Self {
+        Self {
+            status,
+            reason,
+            content_type: "application/json".to_string(),
+            body: serde_json::to_vec(body).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> Self {
+        Self::json(status, reason, &serde_json::json!({ "error": message }))
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
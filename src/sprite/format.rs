@@ -0,0 +1,89 @@
+use crate::{
+    options::{SpriteSheetOptions, WebpOptions},
+    sprite::SpriteSheetError,
+    webp::task::encode_argb_frame,
+};
+use ffmpeg_next::{codec, format::Pixel, frame, Packet};
+use std::io::Write;
+
+/// The image format of a sprite sheet
+pub trait SheetFormat: 'static {
+    /// Extension of the output file
+    const EXTENSION: &'static str;
+    /// Largest width and height of a sheet
+    const MAX_DIMENSION: u32;
+
+    /// Encode the RGBA `sheet` to `output`, returns the number of bytes written.
+    fn encode<W: Write + 'static>(
+        sheet: frame::Video,
+        output: W,
+        options: &SpriteSheetOptions,
+    ) -> Result<usize, SpriteSheetError>;
+}
+
+/// Lossless sheets, encoded by ffmpeg
+pub struct Png;
+
+/// Lossless or lossy sheets, encoded by libwebp
+pub struct Webp;
+
+impl SheetFormat for Png {
+    const EXTENSION: &'static str = "png";
+    // the texture size limit of most browsers, ffmpeg also limits the total area
+    const MAX_DIMENSION: u32 = 16384;
+
+    fn encode<W: Write + 'static>(
+        mut sheet: frame::Video,
+        mut output: W,
+        _options: &SpriteSheetOptions,
+    ) -> Result<usize, SpriteSheetError> {
+        let codec =
+            codec::encoder::find(codec::Id::PNG).ok_or(ffmpeg_next::Error::EncoderNotFound)?;
+        let mut encoder = codec::encoder::new().video()?;
+        encoder.set_width(sheet.width());
+        encoder.set_height(sheet.height());
+        encoder.set_format(Pixel::RGBA);
+        encoder.set_time_base((1, 1));
+        let mut encoder = encoder.open_as(codec)?;
+
+        sheet.set_pts(Some(0));
+        encoder.send_frame(&sheet)?;
+        encoder.send_eof()?;
+        let mut packet = Packet::empty();
+        let mut bytes_written = 0;
+        loop {
+            match encoder.receive_packet(&mut packet) {
+                Ok(()) => {
+                    let data = packet.data().unwrap_or_default();
+                    output.write_all(data)?;
+                    bytes_written += data.len();
+                }
+                Err(ffmpeg_next::Error::Eof) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl SheetFormat for Webp {
+    const EXTENSION: &'static str = "webp";
+    // WEBP_MAX_DIMENSION
+    const MAX_DIMENSION: u32 = 16383;
+
+    fn encode<W: Write + 'static>(
+        sheet: frame::Video,
+        output: W,
+        options: &SpriteSheetOptions,
+    ) -> Result<usize, SpriteSheetError> {
+        // libwebp takes ARGB in native endianness
+        let mut argb = frame::Video::new(Pixel::RGB32, sheet.width(), sheet.height());
+        sheet.converter(Pixel::RGB32)?.run(&sheet, &mut argb)?;
+        let webp_options = WebpOptions {
+            lossless: Some(options.quality.is_none()),
+            quality: options.quality,
+            ..WebpOptions::default()
+        };
+        Ok(encode_argb_frame(&mut argb, output, &webp_options)?)
+    }
+}
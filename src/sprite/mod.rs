@@ -0,0 +1,204 @@
+//! Packing all frames into a single image (sprite sheet) with an atlas of the frames.
+//!
+//! The frames are placed row by row, the atlas is part of the task's stats:
+//!
+//! ```json
+//! {
+//!   "width": 230, "height": 112, "columns": 2, "rows": 1, "duration_ms": 100,
+//!   "frames": [
+//!     { "x": 0, "y": 0, "width": 112, "height": 112, "timestamp_ms": 0, "duration_ms": 50 },
+//!     { "x": 118, "y": 0, "width": 112, "height": 112, "timestamp_ms": 50, "duration_ms": 50 }
+//!   ]
+//! }
+//! ```
+mod format;
+pub mod task;
+
+pub use format::{Png, SheetFormat, Webp};
+
+use crate::{
+    options::{OverwritePolicy, SpriteSheetOptions},
+    pipeline,
+    webp::task::WebpEncoderError,
+    TaskError,
+};
+use std::{
+    io,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpriteSheetError {
+    #[error("Encoder thread didn't receive any frame")]
+    NoFrames,
+    #[error("Frame {0} is {1}x{2}, but the first one is {3}x{4}")]
+    FrameSizeChanged(usize, u32, u32, u32, u32),
+    #[error("The sheet would be {0}x{1} pixels, but at most {2}x{2} are supported")]
+    SheetTooLarge(u64, u64, u32),
+    #[error("Ffmpeg Error: {0}")]
+    FfmpegError(#[from] ffmpeg_next::Error),
+    #[error("WebP Encoder errored: {0}")]
+    WebpEncoderError(#[from] WebpEncoderError),
+    #[error("IO Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Sprite sheets can't be compared against the input")]
+    NoMetrics,
+    #[error("Encoding was cancelled")]
+    Cancelled,
+}
+
+/// Where the frames are in the sheet and when they're shown
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Atlas {
+    /// Size of the whole sheet
+    pub width: u32,
+    pub height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    /// Sum of the durations of all frames
+    pub duration_ms: i64,
+    pub frames: Vec<AtlasFrame>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AtlasFrame {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// When the frame is shown, relative to the first frame
+    pub timestamp_ms: i64,
+    pub duration_ms: i64,
+}
+
+impl Atlas {
+    /// Write the atlas as JSON to `path`, it only appears once it's complete.
+    ///
+    /// Returns `false` if `path` exists and `overwrite` is [`OverwritePolicy::Skip`].
+    pub fn save(&self, path: &Path, overwrite: OverwritePolicy) -> Result<bool, TaskError> {
+        let result = pipeline::write_file(path, overwrite, |file| {
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer_pretty(&mut writer, self)?;
+            writer.flush()
+        });
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match overwrite {
+                OverwritePolicy::Skip => Ok(false),
+                _ => Err(TaskError::OutputExists(path.to_owned())),
+            },
+            Err(e) => Err(TaskError::IoOutputFileError(e)),
+        }
+    }
+}
+
+/// Columns and rows of a sheet with equally sized cells
+#[derive(Debug, Copy, Clone)]
+struct Grid {
+    columns: u32,
+    rows: u32,
+    cell_width: u32,
+    cell_height: u32,
+    padding: u32,
+}
+
+impl Grid {
+    fn new(frames: u32, cell_width: u32, cell_height: u32, options: &SpriteSheetOptions) -> Self {
+        let columns = if options.strip {
+            frames
+        } else {
+            // roughly square by default
+            options
+                .columns
+                .unwrap_or_else(|| (frames as f64).sqrt().ceil() as u32)
+        }
+        .clamp(1, frames.max(1));
+        Self {
+            columns,
+            rows: frames.div_ceil(columns),
+            cell_width,
+            cell_height,
+            padding: options.padding,
+        }
+    }
+
+    /// Width and height of the sheet, these can exceed what any format supports
+    fn size(&self) -> (u64, u64) {
+        let extent = |cells: u32, cell_size: u32| {
+            cells as u64 * cell_size as u64 + cells.saturating_sub(1) as u64 * self.padding as u64
+        };
+        (
+            extent(self.columns, self.cell_width),
+            extent(self.rows, self.cell_height),
+        )
+    }
+
+    /// Top left corner of the frame with this index
+    fn position(&self, idx: u32) -> (u32, u32) {
+        (
+            (idx % self.columns) * (self.cell_width + self.padding),
+            (idx / self.columns) * (self.cell_height + self.padding),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_by_default() {
+        let grid = Grid::new(10, 32, 16, &SpriteSheetOptions::default());
+        assert_eq!((grid.columns, grid.rows), (4, 3));
+        assert_eq!(grid.size(), (128, 48));
+        assert_eq!(grid.position(0), (0, 0));
+        assert_eq!(grid.position(5), (32, 16));
+        assert_eq!(grid.position(9), (32, 32));
+    }
+
+    #[test]
+    fn strip_and_columns() {
+        let strip = SpriteSheetOptions {
+            strip: true,
+            ..SpriteSheetOptions::default()
+        };
+        let grid = Grid::new(5, 10, 10, &strip);
+        assert_eq!((grid.columns, grid.rows), (5, 1));
+        assert_eq!(grid.position(4), (40, 0));
+
+        let columns = SpriteSheetOptions {
+            columns: Some(2),
+            ..SpriteSheetOptions::default()
+        };
+        let grid = Grid::new(5, 10, 10, &columns);
+        assert_eq!((grid.columns, grid.rows), (2, 3));
+        assert_eq!(grid.size(), (20, 30));
+        // no more columns than frames
+        let columns = SpriteSheetOptions {
+            columns: Some(8),
+            ..SpriteSheetOptions::default()
+        };
+        assert_eq!(Grid::new(3, 10, 10, &columns).size(), (30, 10));
+    }
+
+    #[test]
+    fn padding_between_cells() {
+        let options = SpriteSheetOptions {
+            columns: Some(2),
+            padding: 3,
+            ..SpriteSheetOptions::default()
+        };
+        let grid = Grid::new(4, 10, 20, &options);
+        assert_eq!(grid.size(), (23, 43));
+        assert_eq!(grid.position(1), (13, 0));
+        assert_eq!(grid.position(3), (13, 23));
+    }
+
+    #[test]
+    fn single_frame() {
+        let grid = Grid::new(1, 10, 20, &SpriteSheetOptions::default());
+        assert_eq!(grid.size(), (10, 20));
+        assert_eq!(grid.position(0), (0, 0));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{
+    cancel::CancellationToken,
+    ffmpeg::types::FrameData,
+    options::SpriteSheetOptions,
+    progress::Progress,
+    sprite::{Atlas, AtlasFrame, Grid, Png, SheetFormat, SpriteSheetError, Webp},
+    EncoderTask,
+};
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    format::{context, Pixel},
+    frame, Stream,
+};
+use std::{
+    fmt::{Display, Formatter},
+    io::Write,
+    marker::PhantomData,
+    path::PathBuf,
+};
+
+/// Packs all frames into one sheet of the format `F`
+pub struct SpriteSheetTask<F>(PhantomData<F>);
+
+pub type PngSheetTask = SpriteSheetTask<Png>;
+pub type WebpSheetTask = SpriteSheetTask<Webp>;
+
+pub struct SpriteSheetConfig {
+    options: SpriteSheetOptions,
+}
+
+#[derive(serde::Serialize)]
+pub struct SpriteSheetStats {
+    bytes_written: usize,
+    pub atlas: Atlas,
+}
+
+impl<F: SheetFormat> EncoderTask for SpriteSheetTask<F> {
+    type Options = SpriteSheetOptions;
+    type Config = SpriteSheetConfig;
+    type ConfigError = SpriteSheetError;
+    type RunError = SpriteSheetError;
+    type EncoderStats = SpriteSheetStats;
+    type DecodeError = SpriteSheetError;
+
+    fn accepted_formats() -> &'static [Pixel] {
+        &[Pixel::RGBA]
+    }
+
+    fn accepted_alpha_formats() -> &'static [Pixel] {
+        &[Pixel::RGBA]
+    }
+
+    fn make_output_path(output_name: &str) -> PathBuf {
+        PathBuf::from(output_name).with_extension(F::EXTENSION)
+    }
+
+    fn configure(
+        args: Self::Options,
+        _stream: &Stream,
+        _ctx: &context::Input,
+        _dimensions: Option<(u32, u32)>,
+    ) -> Result<Self::Config, Self::ConfigError> {
+        Ok(Self::Config { options: args })
+    }
+
+    fn run_animation<W: Write + 'static>(
+        output: W,
+        config: Self::Config,
+        frame_rx: Receiver<FrameData>,
+        progress: Progress,
+        cancel: &CancellationToken,
+    ) -> Result<Self::EncoderStats, Self::RunError> {
+        pack::<F, W>(output, config, frame_rx, progress, cancel)
+    }
+
+    fn run_still<W: Write + 'static>(
+        output: W,
+        config: Self::Config,
+        frame_rx: Receiver<FrameData>,
+        progress: Progress,
+        cancel: &CancellationToken,
+    ) -> Result<Self::EncoderStats, Self::RunError> {
+        pack::<F, W>(output, config, frame_rx, progress, cancel)
+    }
+
+    fn decode_output(_data: &[u8]) -> Result<Vec<FrameData>, Self::DecodeError> {
+        Err(SpriteSheetError::NoMetrics)
+    }
+}
+
+impl Display for SpriteSheetStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Written {} bytes ({} frames in {} columns and {} rows, {}x{} pixels)",
+            self.bytes_written,
+            self.atlas.frames.len(),
+            self.atlas.columns,
+            self.atlas.rows,
+            self.atlas.width,
+            self.atlas.height
+        )
+    }
+}
+
+fn pack<F: SheetFormat, W: Write + 'static>(
+    output: W,
+    config: SpriteSheetConfig,
+    frame_rx: Receiver<FrameData>,
+    progress: Progress,
+    cancel: &CancellationToken,
+) -> Result<SpriteSheetStats, SpriteSheetError> {
+    // the grid depends on the number of frames, so all of them are needed first
+    let mut frames: Vec<FrameData> = Vec::new();
+    let mut grid = None;
+    for (frame, timing) in frame_rx {
+        if cancel.is_cancelled() {
+            return Err(SpriteSheetError::Cancelled);
+        }
+        let (width, height) = frames
+            .first()
+            .map_or((frame.width(), frame.height()), |(first, _)| {
+                (first.width(), first.height())
+            });
+        if (frame.width(), frame.height()) != (width, height) {
+            return Err(SpriteSheetError::FrameSizeChanged(
+                frames.len(),
+                frame.width(),
+                frame.height(),
+                width,
+                height,
+            ));
+        }
+        frames.push((frame, timing));
+        // the sheet only grows with more frames, so there's no point in buffering the rest
+        grid = Some(fit_grid::<F>(
+            frames.len() as u32,
+            width,
+            height,
+            &config.options,
+        )?);
+        progress.inc(1);
+    }
+    // ffmpeg stops sending frames when cancelled, so this isn't the end of the input
+    if cancel.is_cancelled() {
+        return Err(SpriteSheetError::Cancelled);
+    }
+
+    let grid = grid.ok_or(SpriteSheetError::NoFrames)?;
+    let (sheet_width, sheet_height) = grid.size();
+    let start_ms = frames[0].1.ts_in_ms();
+    let mut sheet = frame::Video::new(Pixel::RGBA, sheet_width as u32, sheet_height as u32);
+    // the padding and unused cells stay transparent
+    sheet.data_mut(0).fill(0);
+    let durations = durations_ms(&frames);
+    let mut atlas_frames = Vec::with_capacity(frames.len());
+    for (idx, ((frame, timing), duration_ms)) in frames.iter().zip(durations).enumerate() {
+        let (x, y) = grid.position(idx as u32);
+        copy_frame(frame, &mut sheet, x, y);
+        atlas_frames.push(AtlasFrame {
+            x,
+            y,
+            width: grid.cell_width,
+            height: grid.cell_height,
+            timestamp_ms: timing.ts_in_ms() - start_ms,
+            duration_ms,
+        });
+    }
+    // the frames are returned to the pool before the (slow) encoding
+    drop(frames);
+
+    let bytes_written = F::encode(sheet, output, &config.options)?;
+    progress.finish();
+    Ok(SpriteSheetStats {
+        bytes_written,
+        atlas: Atlas {
+            width: sheet_width as u32,
+            height: sheet_height as u32,
+            columns: grid.columns,
+            rows: grid.rows,
+            duration_ms: atlas_frames.iter().map(|frame| frame.duration_ms).sum(),
+            frames: atlas_frames,
+        },
+    })
+}
+
+/// The grid for `frames` frames of `width`x`height`, if the sheet fits into the format `F`
+fn fit_grid<F: SheetFormat>(
+    frames: u32,
+    width: u32,
+    height: u32,
+    options: &SpriteSheetOptions,
+) -> Result<Grid, SpriteSheetError> {
+    let grid = Grid::new(frames, width, height, options);
+    let (sheet_width, sheet_height) = grid.size();
+    if sheet_width > F::MAX_DIMENSION as u64 || sheet_height > F::MAX_DIMENSION as u64 {
+        return Err(SpriteSheetError::SheetTooLarge(
+            sheet_width,
+            sheet_height,
+            F::MAX_DIMENSION,
+        ));
+    }
+    Ok(grid)
+}
+
+/// The duration of every frame, or the time until the next frame if the input doesn't know it.
+///
+/// A last frame without a duration is shown as long as the one before it.
+fn durations_ms(frames: &[FrameData]) -> Vec<i64> {
+    let mut durations = Vec::<i64>::with_capacity(frames.len());
+    for (idx, (_, timing)) in frames.iter().enumerate() {
+        let duration_ms = match (timing.duration_in_ms(), frames.get(idx + 1)) {
+            (duration_ms, _) if duration_ms > 0 => duration_ms,
+            (_, Some((_, next))) => (next.ts_in_ms() - timing.ts_in_ms()).max(0),
+            (_, None) => durations.last().copied().unwrap_or_default(),
+        };
+        durations.push(duration_ms);
+    }
+    durations
+}
+
+/// Copy the RGBA `frame` into `sheet` with its top left corner at `x`, `y`
+fn copy_frame(frame: &frame::Video, sheet: &mut frame::Video, x: u32, y: u32) {
+    let row_bytes = frame.width() as usize * 4;
+    let (src_stride, dst_stride) = (frame.stride(0), sheet.stride(0));
+    let src = frame.data(0);
+    let dst = sheet.data_mut(0);
+    for row in 0..frame.height() as usize {
+        let src_start = row * src_stride;
+        let dst_start = (y as usize + row) * dst_stride + x as usize * 4;
+        dst[dst_start..dst_start + row_bytes]
+            .copy_from_slice(&src[src_start..src_start + row_bytes]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::types::TimingData;
+
+    fn with_timing(timing: &[(i64, i64)]) -> Vec<FrameData> {
+        timing
+            .iter()
+            .map(|&(ts, duration)| {
+                let frame = frame::Video::new(Pixel::RGBA, 1, 1);
+                (frame.into(), TimingData::from_ms(ts, duration))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn durations_from_the_input() {
+        let frames = with_timing(&[(0, 20), (20, 30), (50, 40)]);
+        assert_eq!(durations_ms(&frames), [20, 30, 40]);
+    }
+
+    #[test]
+    fn missing_durations() {
+        let frames = with_timing(&[(0, 0), (20, 0), (50, 0)]);
+        assert_eq!(durations_ms(&frames), [20, 30, 30]);
+        let frames = with_timing(&[(0, 0)]);
+        assert_eq!(durations_ms(&frames), [0]);
+        assert!(durations_ms(&[]).is_empty());
+    }
+
+    #[test]
+    fn sheet_size_limit() {
+        let options = SpriteSheetOptions {
+            strip: true,
+            ..SpriteSheetOptions::default()
+        };
+        assert!(fit_grid::<Png>(128, 128, 128, &options).is_ok());
+        assert!(matches!(
+            fit_grid::<Webp>(128, 128, 128, &options),
+            Err(SpriteSheetError::SheetTooLarge(16384, 128, 16383))
+        ));
+    }
+}
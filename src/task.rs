@@ -1,35 +1,47 @@
-use crate::ffmpeg::types::FrameData;
+use crate::{cancel::CancellationToken, ffmpeg::types::FrameData, progress::Progress};
 use crossbeam::channel::Receiver;
 use ffmpeg_next::{format, format::context};
-use indicatif::ProgressBar;
 use std::{io, path::PathBuf};
 
 pub trait EncoderTask {
-    type CliArgs;
+    /// Options chosen by the user, see [`crate::options`]
+    type Options;
     type Config: Send + 'static;
     type ConfigError: std::error::Error + 'static;
     type RunError: std::error::Error + Send + 'static;
-    type EncoderStats: std::fmt::Display + Send + 'static;
+    type EncoderStats: std::fmt::Display + serde::Serialize + Send + 'static;
+    type DecodeError: std::error::Error + Send + 'static;
 
     fn accepted_formats() -> &'static [format::Pixel];
     fn accepted_alpha_formats() -> &'static [format::Pixel];
 
     fn make_output_path(output_name: &str) -> PathBuf;
+    /// `dimensions` are the dimensions of the frames the encoder receives,
+    /// which can differ from the stream's (e.g. rotated videos).
     fn configure(
-        args: Self::CliArgs,
+        args: Self::Options,
         stream: &ffmpeg_next::Stream,
         ctx: &context::Input,
+        dimensions: Option<(u32, u32)>,
     ) -> Result<Self::Config, Self::ConfigError>;
+    /// Encode all frames from `frame_rx`.
+    ///
+    /// If `cancel` is cancelled, this has to return an error without writing to `output`.
     fn run_animation<W: io::Write + 'static>(
         output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError>;
     fn run_still<W: io::Write + 'static>(
         output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError>;
+
+    /// Decode data written by this task back into frames (used to compute quality metrics).
+    fn decode_output(data: &[u8]) -> Result<Vec<FrameData>, Self::DecodeError>;
 }
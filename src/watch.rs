@@ -0,0 +1,191 @@
+//! Watching a directory and converting every file that's added or changed.
+use crate::{
+    batch::{collect_inputs, convert, BatchError, BatchJob, BatchResult},
+    cli::{BatchOptions, OutputFormat, ProgressMode, WatchOptions},
+    CliError,
+};
+use emote_tool::{cancel::CancellationToken, EncoderTask};
+use indicatif::MultiProgress;
+use std::{
+    collections::HashMap,
+    fs,
+    fs::OpenOptions,
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How often Ctrl+C is checked while waiting for the next scan
+const CANCEL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size and modification time, a file is converted once they stop changing
+type FileState = (u64, SystemTime);
+
+/// A line of the log
+#[derive(serde::Serialize)]
+struct LogEntry<'a, S> {
+    /// Seconds since the unix epoch
+    time: u64,
+    #[serde(flatten)]
+    result: &'a BatchResult<S>,
+}
+
+/// Convert new and changed files in `options.dir` until Ctrl+C is pressed.
+///
+/// Files are only converted once their size and modification time didn't change between
+/// two scans, so files that are still being copied aren't picked up.
+pub fn watch<T>(
+    options: &WatchOptions,
+    task_options: T::Options,
+    cancel: &CancellationToken,
+) -> Result<(), CliError>
+where
+    T: EncoderTask,
+    T::Options: Clone,
+{
+    let log_path = options
+        .log
+        .clone()
+        .unwrap_or_else(|| options.output_dir.join("watch.log"));
+    fs::create_dir_all(&options.output_dir).map_err(CliError::WatchError)?;
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(CliError::WatchError)?;
+    // outputs and the log must not be converted again if they're in the watched directory
+    let ignored = [
+        options
+            .output_dir
+            .canonicalize()
+            .map_err(CliError::WatchError)?,
+        log_path.canonicalize().map_err(CliError::WatchError)?,
+    ];
+    println!(
+        "Watching {}, outputs are written to {}",
+        options.dir.display(),
+        options.output_dir.display()
+    );
+
+    let progress_manager = MultiProgress::new();
+    let mut converted = HashMap::<PathBuf, FileState>::new();
+    let mut pending = HashMap::<PathBuf, FileState>::new();
+    while !cancel.is_cancelled() {
+        for job in scan(&options.dir, &ignored) {
+            let state = match file_state(&job.input) {
+                Ok(state) => state,
+                // removed since the scan
+                Err(_) => continue,
+            };
+            if converted.get(&job.input) == Some(&state) {
+                continue;
+            }
+            if pending.insert(job.input.clone(), state) != Some(state) {
+                continue;
+            }
+            pending.remove(&job.input);
+
+            // changed files replace their previous output
+            let overwrite = options.overwrite || converted.contains_key(&job.input);
+            let report = convert::<T>(
+                &job,
+                &batch_options(options, overwrite),
+                task_options.clone(),
+                &progress_manager,
+                cancel,
+            );
+            converted.insert(job.input.clone(), state);
+            let result = BatchResult {
+                input: job.input,
+                report,
+            };
+            print_result(&result);
+            if let Err(e) = write_log(&mut log, &result) {
+                eprintln!("Couldn't write to {}: {}", log_path.display(), e);
+            }
+        }
+        sleep(options.interval, cancel);
+    }
+    Ok(())
+}
+
+/// All files in `dir` except hidden and `ignored` ones
+fn scan(dir: &Path, ignored: &[PathBuf]) -> Vec<BatchJob> {
+    let jobs = match collect_inputs(&[dir.to_string_lossy().into_owned()]) {
+        Ok(jobs) => jobs,
+        Err(BatchError::NoInputs) => Vec::new(),
+        Err(e) => {
+            eprintln!("Couldn't scan {}: {}", dir.display(), e);
+            Vec::new()
+        }
+    };
+    jobs.into_iter()
+        .filter(|job| {
+            let hidden = job
+                .relative_output
+                .components()
+                .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+            let ignored = match job.input.canonicalize() {
+                Ok(path) => ignored.iter().any(|ignored| path.starts_with(ignored)),
+                Err(_) => true,
+            };
+            !hidden && !ignored
+        })
+        .collect()
+}
+
+fn file_state(path: &Path) -> io::Result<FileState> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+/// The settings of a single batch job, without progress bars
+fn batch_options(options: &WatchOptions, overwrite: bool) -> BatchOptions {
+    BatchOptions {
+        inputs: vec![options.dir.to_string_lossy().into_owned()],
+        output_dir: options.output_dir.clone(),
+        jobs: 1,
+        limits: options.limits,
+        output_format: OutputFormat::Text,
+        progress: ProgressMode::None,
+        overwrite,
+        // existing outputs of files that were there before are kept
+        no_clobber: !overwrite,
+    }
+}
+
+fn print_result<S>(result: &BatchResult<S>) {
+    let input = result.input.display();
+    let report = &result.report;
+    match (&report.output, report.errors.first()) {
+        (Some(output), None) if report.skipped => {
+            println!("Skipped {}, {} already exists", input, output.display())
+        }
+        (Some(output), None) => println!("Converted {} to {}", input, output.display()),
+        (_, Some(error)) => println!("Failed {}: [{:?}] {}", input, error.stage, error.message),
+        (None, None) => println!("Failed {}: No output", input),
+    }
+}
+
+fn write_log<S: serde::Serialize>(log: &mut fs::File, result: &BatchResult<S>) -> io::Result<()> {
+    let entry = LogEntry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+        result,
+    };
+    writeln!(log, "{}", serde_json::to_string(&entry)?)
+}
+
+/// Wait `seconds` (which `--interval` ensures is finite and not negative) or until `cancel`
+/// is cancelled
+fn sleep(seconds: f64, cancel: &CancellationToken) {
+    let mut remaining = Duration::try_from_secs_f64(seconds).unwrap_or_default();
+    while !remaining.is_zero() && !cancel.is_cancelled() {
+        let step = remaining.min(CANCEL_INTERVAL);
+        thread::sleep(step);
+        remaining -= step;
+    }
+}
@@ -1,11 +1,15 @@
 use super::types::{AlphaFiltering, ImageHint, PreprocessingFilter};
-use crate::{apply_options, cli, getter_debug, getter_setter, webp::Preset};
+use crate::{
+    apply_options, getter_debug, getter_serialize, getter_setter, options::WebpOptions,
+    webp::Preset,
+};
 use libwebp_sys as sys;
 use std::{
     fmt::{Debug, Formatter},
     os::raw::c_int,
 };
 
+#[derive(Clone)]
 pub struct Config {
     conf: sys::WebPConfig,
 }
@@ -65,7 +69,7 @@ impl Config {
         unsafe { sys::WebPValidateConfig(&self.conf) != 0 }
     }
 
-    pub fn apply_cli_options(&mut self, opts: &cli::WebpOptions) {
+    pub fn apply_cli_options(&mut self, opts: &WebpOptions) {
         apply_options!(self, opts;
             lossless,
             quality,
@@ -136,3 +140,38 @@ impl Debug for Config {
         )
     }
 }
+
+impl serde::Serialize for Config {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        getter_serialize!(
+            self, serializer, "Config";
+            lossless,
+            quality,
+            method,
+            image_hint,
+            target_size,
+            target_psnr,
+            segments,
+            sns_strength,
+            filter_strength,
+            filter_sharpness,
+            strong_filter,
+            autofilter,
+            alpha_compression,
+            alpha_filtering,
+            alpha_quality,
+            pass,
+            show_compressed,
+            preprocessing,
+            partitions,
+            partition_limit,
+            emulate_jpeg_size,
+            thread_level,
+            low_memory,
+            near_lossless,
+            exact,
+            use_delta_palette,
+            use_sharp_yuv,
+        )
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{
+    ffmpeg::types::{FrameData, TimingData},
+    webp::{errors, mux::Mux},
+};
+use ffmpeg_next::{format::Pixel, frame};
+use libwebp_sys as sys;
+use std::ptr::NonNull;
+
+/// Decodes all frames of a (possibly animated) webp file into RGBA frames of the full canvas.
+///
+/// Frames are composited like a viewer would (honoring blending and disposal),
+/// the background is assumed to be transparent.
+pub fn decode_frames(data: &[u8]) -> Result<Vec<FrameData>, errors::DecodeError> {
+    let mux = Mux::from_bytes(data)?;
+    let (width, height) = mux.canvas_size()?;
+    let mut canvas = vec![0u8; width * height * 4];
+    let mut frames = Vec::with_capacity(mux.frame_count()?);
+    let mut timestamp_ms = 0;
+    // rectangle (x, y, width, height) to clear before drawing the next frame
+    let mut dispose_rect = None;
+
+    for index in 0..mux.frame_count()? {
+        if let Some((x, y, w, h)) = dispose_rect.take() {
+            for row in y..y + h {
+                canvas[(row * width + x) * 4..(row * width + x + w) * 4].fill(0);
+            }
+        }
+
+        let mux_frame = mux.frame(index)?;
+        let image = RgbaImage::decode(mux_frame.bitstream())
+            .ok_or(errors::DecodeError::DecodeFrame(index))?;
+        let (x_offset, y_offset) = mux_frame.offset();
+        let w = image.width.min(width.saturating_sub(x_offset));
+        let h = image.height.min(height.saturating_sub(y_offset));
+        let src = image.as_slice();
+        for row in 0..h {
+            let src_row = &src[row * image.width * 4..(row * image.width + w) * 4];
+            let dst_start = ((y_offset + row) * width + x_offset) * 4;
+            let dst_row = &mut canvas[dst_start..dst_start + w * 4];
+            if mux_frame.blends() {
+                for (dst, src) in dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)) {
+                    blend_pixel(dst, src);
+                }
+            } else {
+                dst_row.copy_from_slice(src_row);
+            }
+        }
+        if mux_frame.disposes_to_background() {
+            dispose_rect = Some((x_offset, y_offset, w, h));
+        }
+
+        frames.push((
+            canvas_to_frame(&canvas, width, height).into(),
+            TimingData::from_ms(timestamp_ms, mux_frame.duration_ms()),
+        ));
+        timestamp_ms += mux_frame.duration_ms();
+    }
+
+    Ok(frames)
+}
+
+/// Non-premultiplied "source over destination"
+fn blend_pixel(dst: &mut [u8], src: &[u8]) {
+    let src_a = src[3] as u32;
+    if src_a == 255 {
+        dst.copy_from_slice(src);
+        return;
+    }
+    if src_a == 0 {
+        return;
+    }
+    let dst_a = (dst[3] as u32 * (255 - src_a)) / 255;
+    let out_a = src_a + dst_a;
+    for (d, s) in dst[..3].iter_mut().zip(&src[..3]) {
+        *d = ((*s as u32 * src_a + *d as u32 * dst_a) / out_a) as u8;
+    }
+    dst[3] = out_a as u8;
+}
+
+fn canvas_to_frame(canvas: &[u8], width: usize, height: usize) -> frame::Video {
+    let mut frame = frame::Video::new(Pixel::RGBA, width as u32, height as u32);
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for row in 0..height {
+        data[row * stride..row * stride + width * 4]
+            .copy_from_slice(&canvas[row * width * 4..(row + 1) * width * 4]);
+    }
+    frame
+}
+
+struct RgbaImage {
+    data: NonNull<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl RgbaImage {
+    fn decode(bitstream: &[u8]) -> Option<Self> {
+        let (mut width, mut height) = (0, 0);
+        let data = unsafe {
+            sys::WebPDecodeRGBA(bitstream.as_ptr(), bitstream.len(), &mut width, &mut height)
+        };
+        Some(Self {
+            data: NonNull::new(data)?,
+            width: width as usize,
+            height: height as usize,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.width * self.height * 4) }
+    }
+}
+
+impl Drop for RgbaImage {
+    fn drop(&mut self) {
+        unsafe {
+            sys::WebPFree(self.data.as_ptr() as *mut _);
+        }
+    }
+}
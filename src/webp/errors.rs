@@ -28,6 +28,22 @@ pub enum StillEncoderError {
     CorruptedUserData,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum MuxError {
+    #[error("Couldn't parse the webp container")]
+    InvalidContainer,
+    #[error("Mux Error: {0:?}")]
+    MuxError(sys::WebPMuxError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("Mux Error: {0}")]
+    MuxError(#[from] MuxError),
+    #[error("Couldn't decode frame {0}")]
+    DecodeFrame(usize),
+}
+
 impl AnimEncoderError {
     pub(super) unsafe fn last_error(encoder: NonNull<sys::WebPAnimEncoder>) -> Self {
         let error = sys::WebPAnimEncoderGetError(encoder.as_ptr());
@@ -49,3 +65,12 @@ impl StillEncoderError {
         }
     }
 }
+
+impl MuxError {
+    pub(super) fn from_code(code: sys::WebPMuxError) -> Result<(), Self> {
+        match code {
+            sys::WebPMuxError::WEBP_MUX_OK => Ok(()),
+            code => Err(Self::MuxError(code)),
+        }
+    }
+}
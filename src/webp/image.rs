@@ -1,7 +1,7 @@
 use crate::webp::errors;
-use ffmpeg_next::{format, frame};
+use ffmpeg_next::{ffi::AVPixelFormat, format, frame};
 use libwebp_sys as sys;
-use std::{marker::PhantomData, mem::ManuallyDrop, ptr::NonNull};
+use std::{marker::PhantomData, mem::ManuallyDrop};
 
 // we don't need to implement drop for WebpImage since it contains a reference to the video data
 // and nothing from libwebp is allocated (WebPPictureInitInternal only zeroes the memory)
@@ -57,6 +57,23 @@ impl<'a> WebpImage<'a> {
         Ok(pic)
     }
 
+    /// Reference a frame in ffmpeg's `RGB32` format, which is native endian ARGB like libwebp's.
+    pub fn from_argb_frame(frame: &'a mut frame::Video) -> Result<Self, errors::CreateImageError> {
+        // RGB32 is an alias for BGRA or ARGB, depending on the endianness
+        if AVPixelFormat::from(frame.format()) != AVPixelFormat::from(format::Pixel::RGB32) {
+            return Err(errors::CreateImageError::InvalidPixelFormat);
+        }
+
+        let mut pic = Self::new()?;
+        pic.picture.use_argb = 1;
+        pic.picture.argb = frame.data_mut(0).as_mut_ptr() as *mut u32;
+        pic.picture.argb_stride = (frame.stride(0) / 4) as _;
+        pic.picture.width = frame.width() as _;
+        pic.picture.height = frame.height() as _;
+
+        Ok(pic)
+    }
+
     pub fn set_writer(&mut self, writer: sys::WebPWriterFunction) {
         self.picture.writer = writer;
     }
@@ -85,8 +102,9 @@ impl<'a> WebpImage<'a> {
         }
     }
 
-    pub fn clone_stats(&self) -> Option<sys::WebPAuxStats> {
-        unsafe { NonNull::new(self.picture.stats).map(|p| *p.as_ref()) }
+    /// `stats` has to outlive the encoding, reset it to null afterwards
+    pub(super) unsafe fn set_stats(&mut self, stats: *mut sys::WebPAuxStats) {
+        self.picture.stats = stats;
     }
 
     pub(super) unsafe fn as_mut_ptr(&mut self) -> *mut sys::WebPPicture {
@@ -36,16 +36,21 @@ pub fn encode_image<W: io::Write + 'static>(
     image: &mut WebpImage,
     writer: W,
     config: &Config,
-) -> Result<Option<sys::WebPAuxStats>, errors::StillEncoderError> {
+) -> Result<sys::WebPAuxStats, errors::StillEncoderError> {
+    // libwebp only fills in the stats (including the coded size) if the picture points to them
+    let mut stats = unsafe { std::mem::zeroed::<sys::WebPAuxStats>() };
     unsafe {
         image.set_user_data(Box::<UserData>::new(Ok(WriteData {
             writer: Box::new(writer),
         })));
         image.set_writer(Some(writer_write));
-        if sys::WebPEncode(config.as_ptr(), image.as_mut_ptr()) == 0 {
-            Err(errors::StillEncoderError::from_image(image))
+        image.set_stats(&mut stats);
+        let encoded = sys::WebPEncode(config.as_ptr(), image.as_mut_ptr()) != 0;
+        image.set_stats(std::ptr::null_mut());
+        if encoded {
+            Ok(stats)
         } else {
-            Ok(image.clone_stats())
+            Err(errors::StillEncoderError::from_image(image))
         }
     }
 }
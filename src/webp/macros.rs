@@ -76,3 +76,13 @@ macro_rules! getter_debug {
             .finish()
     };
 }
+
+#[macro_export]
+macro_rules! getter_serialize {
+    ($self:ident, $serializer:ident, $struct_name:literal; $($name:ident,)*) => {{
+        use serde::ser::SerializeStruct;
+        let mut state = $serializer.serialize_struct($struct_name, [$(stringify!($name),)*].len())?;
+        $(state.serialize_field(stringify!($name), &$self.$name())?;)*
+        state.end()
+    }};
+}
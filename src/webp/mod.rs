@@ -1,10 +1,12 @@
 mod anim_encoder;
 mod config;
 mod data;
+mod decode;
 mod errors;
 mod image;
 mod image_encode;
 mod macros;
+mod mux;
 mod options;
 pub mod task;
 mod types;
@@ -0,0 +1,102 @@
+use crate::webp::errors;
+use libwebp_sys as sys;
+use std::{os::raw::c_int, ptr::NonNull};
+
+pub struct Mux {
+    mux: NonNull<sys::WebPMux>,
+}
+
+/// A single frame of a [`Mux`], its bitstream is a standalone webp image.
+pub struct MuxFrame {
+    info: sys::WebPMuxFrameInfo,
+}
+
+impl Mux {
+    /// Parse a webp file, the data is copied into the mux.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, errors::MuxError> {
+        let data = sys::WebPData {
+            bytes: data.as_ptr(),
+            size: data.len(),
+        };
+        let mux = unsafe { sys::WebPMuxCreateInternal(&data, 1, sys::WEBP_MUX_ABI_VERSION) };
+        Ok(Self {
+            mux: NonNull::new(mux).ok_or(errors::MuxError::InvalidContainer)?,
+        })
+    }
+
+    pub fn canvas_size(&self) -> Result<(usize, usize), errors::MuxError> {
+        let (mut width, mut height) = (0, 0);
+        unsafe {
+            errors::MuxError::from_code(sys::WebPMuxGetCanvasSize(
+                self.mux.as_ptr(),
+                &mut width,
+                &mut height,
+            ))?;
+        }
+        Ok((width as usize, height as usize))
+    }
+
+    /// Number of frames, a still image has exactly one frame.
+    pub fn frame_count(&self) -> Result<usize, errors::MuxError> {
+        let mut count: c_int = 0;
+        unsafe {
+            errors::MuxError::from_code(sys::WebPMuxNumChunks(
+                self.mux.as_ptr(),
+                sys::WebPChunkId::WEBP_CHUNK_ANMF,
+                &mut count,
+            ))?;
+        }
+        Ok((count as usize).max(1))
+    }
+
+    /// Get the frame at `index` (starting at 0).
+    pub fn frame(&self, index: usize) -> Result<MuxFrame, errors::MuxError> {
+        unsafe {
+            let mut info = std::mem::zeroed::<sys::WebPMuxFrameInfo>();
+            errors::MuxError::from_code(sys::WebPMuxGetFrame(
+                self.mux.as_ptr(),
+                index as u32 + 1,
+                &mut info,
+            ))?;
+            Ok(MuxFrame { info })
+        }
+    }
+}
+
+impl MuxFrame {
+    pub fn bitstream(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.info.bitstream.bytes, self.info.bitstream.size) }
+    }
+
+    pub fn offset(&self) -> (usize, usize) {
+        (self.info.x_offset as usize, self.info.y_offset as usize)
+    }
+
+    pub fn duration_ms(&self) -> i64 {
+        self.info.duration as i64
+    }
+
+    pub fn disposes_to_background(&self) -> bool {
+        self.info.dispose_method == sys::WebPMuxAnimDispose::WEBP_MUX_DISPOSE_BACKGROUND
+    }
+
+    pub fn blends(&self) -> bool {
+        self.info.blend_method == sys::WebPMuxAnimBlend::WEBP_MUX_BLEND
+    }
+}
+
+impl Drop for MuxFrame {
+    fn drop(&mut self) {
+        unsafe {
+            sys::WebPDataClear(&mut self.info.bitstream);
+        }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        unsafe {
+            sys::WebPMuxDelete(self.mux.as_ptr());
+        }
+    }
+}
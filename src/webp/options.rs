@@ -1,4 +1,4 @@
-use crate::{apply_options, cli, getter_debug};
+use crate::{apply_options, getter_debug, getter_serialize, options::WebpOptions};
 use libwebp_sys as sys;
 use std::{
     borrow::Cow,
@@ -8,11 +8,12 @@ use std::{
     str::FromStr,
 };
 
+#[derive(Clone)]
 pub struct AnimEncoderOptions {
     opts: sys::WebPAnimEncoderOptions,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
 pub enum KeyframeDistance {
     MinMax(u32, u32),
     Disabled,
@@ -87,7 +88,7 @@ impl AnimEncoderOptions {
         self
     }
 
-    pub fn apply_cli_options(&mut self, opts: &cli::WebpOptions) {
+    pub fn apply_cli_options(&mut self, opts: &WebpOptions) {
         apply_options!(self, opts;
             minimize_size,
             keyframe_distance,
@@ -117,6 +118,19 @@ impl Debug for AnimEncoderOptions {
     }
 }
 
+impl serde::Serialize for AnimEncoderOptions {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        getter_serialize!(
+            self, serializer, "AnimEncoderOptions";
+            minimize_size,
+            keyframe_distance,
+            allow_mixed,
+            loop_count,
+            background_color,
+        )
+    }
+}
+
 impl From<(os::raw::c_int, os::raw::c_int)> for KeyframeDistance {
     fn from((min, max): (c_int, c_int)) -> Self {
         if max <= 0 {
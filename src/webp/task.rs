@@ -1,39 +1,46 @@
 use crate::{
-    cli,
+    cancel::CancellationToken,
     ffmpeg::{frames::extract_duration_ms, types::FrameData},
+    options::WebpOptions,
+    progress::Progress,
     webp::{
-        anim_encoder::AnimEncoder, config::Config, image::WebpImage, image_encode::encode_image,
-        options::AnimEncoderOptions, AnimEncoderError, CreateImageError, Preset, StillEncoderError,
+        anim_encoder::AnimEncoder, config::Config, decode::decode_frames, image::WebpImage,
+        image_encode::encode_image, options::AnimEncoderOptions, AnimEncoderError,
+        CreateImageError, DecodeError, Preset, StillEncoderError,
     },
     EncoderTask,
 };
 use crossbeam::channel::{Receiver, RecvError};
 use ffmpeg_next::{
     format::{context, Pixel},
-    Stream,
+    frame, Stream,
 };
-use indicatif::ProgressBar;
 use libwebp_sys as sys;
 use std::{
-    fmt::{Display, Formatter, Pointer},
+    cell::Cell,
+    fmt::{Display, Formatter},
     io,
     io::Write,
     path::PathBuf,
-    ptr::NonNull,
+    rc::Rc,
 };
 
 pub struct WebpEncoderTask;
 
 pub struct WebpEncoderConfig {
-    args: cli::WebpOptions,
+    args: WebpOptions,
     width: usize,
     height: usize,
     duration_ms: i32,
 }
 
-pub enum WebpEncoderStats {
-    Still(Option<sys::WebPAuxStats>),
-    Animation(usize),
+#[derive(serde::Serialize)]
+pub struct WebpEncoderStats {
+    bytes_written: usize,
+    config: Config,
+    anim_options: Option<AnimEncoderOptions>,
+    /// PSNR of the Y, U, V and alpha channels and all of them combined (still images only)
+    psnr: Option<[f32; 5]>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -56,6 +63,8 @@ pub enum WebpEncoderError {
     CreateAnimEncoder,
     #[error("Invalid config, validation failed")]
     InvalidConfig,
+    #[error("Encoding was cancelled")]
+    Cancelled,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -65,11 +74,12 @@ pub enum WebpEncoderConfigError {
 }
 
 impl EncoderTask for WebpEncoderTask {
-    type CliArgs = cli::WebpOptions;
+    type Options = WebpOptions;
     type Config = WebpEncoderConfig;
     type ConfigError = WebpEncoderConfigError;
     type RunError = WebpEncoderError;
     type EncoderStats = WebpEncoderStats;
+    type DecodeError = DecodeError;
 
     fn accepted_formats() -> &'static [Pixel] {
         &[Pixel::YUV420P]
@@ -84,15 +94,12 @@ impl EncoderTask for WebpEncoderTask {
     }
 
     fn configure(
-        args: Self::CliArgs,
+        args: Self::Options,
         stream: &Stream,
         ctx: &context::Input,
+        dimensions: Option<(u32, u32)>,
     ) -> Result<Self::Config, Self::ConfigError> {
-        let (width, height) = unsafe {
-            let par =
-                NonNull::new((*stream.as_ptr()).codecpar).ok_or(Self::ConfigError::NoCodecPar)?;
-            (par.as_ref().width, par.as_ref().height)
-        };
+        let (width, height) = dimensions.ok_or(Self::ConfigError::NoCodecPar)?;
         Ok(Self::Config {
             args,
             width: width as usize,
@@ -105,9 +112,11 @@ impl EncoderTask for WebpEncoderTask {
         mut output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError> {
         let encoder_config = encoder_config_from_cli(&config.args)?;
+        let used_config = encoder_config.clone();
         let mut anim_encoder_opts =
             AnimEncoderOptions::new().ok_or(Self::RunError::CreateAnimEncoderOptions)?;
         anim_encoder_opts.apply_cli_options(&config.args);
@@ -119,44 +128,80 @@ impl EncoderTask for WebpEncoderTask {
         )
         .ok_or(Self::RunError::CreateAnimEncoder)?;
 
+        // the end of the last frame, if the stream's duration is too short (e.g. for image sequences)
+        let mut end_ms = config.duration_ms;
         for (mut frame, timing) in frame_rx {
+            if cancel.is_cancelled() {
+                return Err(Self::RunError::Cancelled);
+            }
             let mut image = WebpImage::from_av_frame(&mut frame)?;
             encoder.add_image(&mut image, timing.ts_in_ms() as i32)?;
+            end_ms = end_ms.max((timing.ts_in_ms() + timing.duration_in_ms()) as i32);
             progress.inc(1);
         }
-        let data = encoder.finalize(config.duration_ms)?;
+        // ffmpeg stops sending frames when cancelled, so this isn't the end of the input
+        if cancel.is_cancelled() {
+            return Err(Self::RunError::Cancelled);
+        }
+        let data = encoder.finalize(end_ms)?;
         output.write_all(data.as_slice())?;
-        progress.finish_and_clear();
-
-        Ok(WebpEncoderStats::Animation(data.len()))
+        progress.finish();
+        drop(encoder);
+
+        Ok(WebpEncoderStats {
+            bytes_written: data.len(),
+            config: used_config,
+            anim_options: Some(anim_encoder_opts),
+            psnr: None,
+        })
     }
 
     fn run_still<W: Write + 'static>(
         output: W,
         config: Self::Config,
         frame_rx: Receiver<FrameData>,
-        progress: ProgressBar,
+        progress: Progress,
+        cancel: &CancellationToken,
     ) -> Result<Self::EncoderStats, Self::RunError> {
         let (mut frame, _) = frame_rx.recv()?;
+        if cancel.is_cancelled() {
+            return Err(Self::RunError::Cancelled);
+        }
         let mut image = WebpImage::from_av_frame(&mut frame)?;
         let encoder_config = encoder_config_from_cli(&config.args)?;
         let stats = encode_image(&mut image, output, &encoder_config)?;
-        progress.finish_and_clear();
-        Ok(WebpEncoderStats::Still(stats))
+        progress.finish();
+        Ok(WebpEncoderStats {
+            bytes_written: stats.coded_size as usize,
+            // libwebp only measures the distortion of lossy images
+            psnr: (!encoder_config.lossless()).then_some(stats.PSNR),
+            config: encoder_config,
+            anim_options: None,
+        })
+    }
+
+    fn decode_output(data: &[u8]) -> Result<Vec<FrameData>, Self::DecodeError> {
+        decode_frames(data)
     }
 }
 
 impl Display for WebpEncoderStats {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            WebpEncoderStats::Still(Some(stats)) => stats.fmt(f),
-            WebpEncoderStats::Still(None) => write!(f, "No stats"),
-            WebpEncoderStats::Animation(bytes) => write!(f, "Written {bytes} bytes"),
+        write!(f, "Written {} bytes ({:?}", self.bytes_written, self.config)?;
+        if let Some(anim_options) = &self.anim_options {
+            write!(f, ", {:?}", anim_options)?;
         }
+        if let Some([y, u, v, all, alpha]) = self.psnr {
+            write!(
+                f,
+                ", PSNR: {all:.2} dB (Y {y:.2}, U {u:.2}, V {v:.2}, alpha {alpha:.2})"
+            )?;
+        }
+        write!(f, ")")
     }
 }
 
-fn encoder_config_from_cli(cli: &cli::WebpOptions) -> Result<Config, WebpEncoderError> {
+fn encoder_config_from_cli(cli: &WebpOptions) -> Result<Config, WebpEncoderError> {
     let mut encoder_config = Config::new(cli.preset.unwrap_or(Preset::Default))
         .ok_or(WebpEncoderError::CreateConfigError)?;
     encoder_config.apply_cli_options(cli);
@@ -166,3 +211,44 @@ fn encoder_config_from_cli(cli: &cli::WebpOptions) -> Result<Config, WebpEncoder
         Err(WebpEncoderError::InvalidConfig)
     }
 }
+
+/// Encode a single frame in ffmpeg's `RGB32` format (e.g. a sprite sheet) with `options`.
+///
+/// Returns the number of bytes written.
+pub(crate) fn encode_argb_frame<W: Write + 'static>(
+    frame: &mut frame::Video,
+    output: W,
+    options: &WebpOptions,
+) -> Result<usize, WebpEncoderError> {
+    let mut image = WebpImage::from_argb_frame(frame)?;
+    let encoder_config = encoder_config_from_cli(options)?;
+    let written = Rc::new(Cell::new(0));
+    let output = CountingWriter {
+        inner: output,
+        written: written.clone(),
+    };
+    let result = encode_image(&mut image, output, &encoder_config);
+    // lossy encoding converts the picture to YUV in buffers that libwebp allocated,
+    // the ARGB data still belongs to the frame
+    unsafe { sys::WebPPictureFree(image.as_mut_ptr()) };
+    result?;
+    Ok(written.get())
+}
+
+/// Counts the bytes libwebp writes
+struct CountingWriter<W> {
+    inner: W,
+    written: Rc<Cell<usize>>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written.set(self.written.get() + written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -2,7 +2,8 @@ use libwebp_sys as sys;
 use std::os::raw::c_int;
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
 pub enum ImageHint {
     Default = 0,
     Picture = 1,
@@ -12,7 +13,8 @@ pub enum ImageHint {
 }
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
 pub enum AlphaFiltering {
     None = 0,
     Fast = 1,
@@ -20,7 +22,8 @@ pub enum AlphaFiltering {
 }
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
 pub enum PreprocessingFilter {
     None = 0,
     SegmentSmooth = 1,
@@ -28,7 +31,8 @@ pub enum PreprocessingFilter {
 }
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ArgEnum)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
 pub enum Preset {
     Default,
     Picture,